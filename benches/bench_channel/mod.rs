@@ -6,12 +6,12 @@ pub async fn run_bench_channel() {
 
     let tx_task = task::spawn(async move {
         for i in 0 .. 10_000 {
-            let _ = tx.send(i).await.unwrap();
+            tx.send(i).await.unwrap();
         }
     });
 
     let rx_task = task::spawn(async move {
-        while let Some(_) = rx.recv().await {
+        while rx.recv().await.is_some() {
             // nothing
         }
     });
@@ -18,10 +18,50 @@ impl AsyncExecutor for NaraExecutor {
     }
 }
 
+// Send from a blocking thread into an async receiver, through the
+// thread-safe channel. Every wakeup of the receiver goes through the
+// executor's cross-thread wake pipe.
+async fn run_bench_sync_channel_cross_thread() {
+    let (tx, mut rx) = nara::sync::mpsc::channel(16);
+
+    let tx_task = task::spawn_blocking(move || {
+        for i in 0 .. 10_000 {
+            futures::executor::block_on(tx.send(i)).unwrap();
+        }
+    });
+
+    while rx.recv().await.is_some() {
+        // nothing
+    }
+    let _ = tx_task.await;
+}
+
+// Same, with the unbounded channel, where the sender never waits.
+async fn run_bench_sync_unbounded_cross_thread() {
+    let (tx, mut rx) = nara::sync::mpsc::unbounded_channel();
+
+    let tx_task = task::spawn_blocking(move || {
+        for i in 0 .. 10_000 {
+            tx.send(i).unwrap();
+        }
+    });
+
+    while rx.recv().await.is_some() {
+        // nothing
+    }
+    let _ = tx_task.await;
+}
+
 fn bench_channel(c: &mut Criterion) {
     let mut group = c.benchmark_group("nara");
     group.bench_function("channel", |b| {
-        b.to_async(NaraExecutor).iter(|| bench_channel::run_bench_channel());
+        b.to_async(NaraExecutor).iter(bench_channel::run_bench_channel);
+    });
+    group.bench_function("sync_channel_cross_thread", |b| {
+        b.to_async(NaraExecutor).iter(run_bench_sync_channel_cross_thread);
+    });
+    group.bench_function("sync_unbounded_cross_thread", |b| {
+        b.to_async(NaraExecutor).iter(run_bench_sync_unbounded_cross_thread);
     });
 }
 
@@ -21,7 +21,7 @@ impl AsyncExecutor for TokioExecutor {
 fn bench_channel(c: &mut Criterion) {
     let mut group = c.benchmark_group("tokio");
     group.bench_function("channel", |b| {
-        b.to_async(TokioExecutor).iter(|| bench_channel::run_bench_channel());
+        b.to_async(TokioExecutor).iter(bench_channel::run_bench_channel);
     });
 }
 
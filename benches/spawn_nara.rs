@@ -0,0 +1,41 @@
+use std::future::Future;
+use criterion::async_executor::AsyncExecutor;
+use criterion::{criterion_group, criterion_main, Criterion};
+use nara::runtime::Runtime;
+use nara::task;
+
+struct NaraExecutor;
+impl AsyncExecutor for NaraExecutor {
+    fn block_on<T>(&self, future: impl Future<Output = T>) -> T {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(future)
+    }
+}
+
+// Spawn a batch of small tasks and wait for all of them.
+async fn run_bench_spawn() {
+    let handles: Vec<_> = (0 .. 1_000u64).map(|i| task::spawn(async move { i })).collect();
+    for handle in handles {
+        let _ = handle.await;
+    }
+}
+
+// Spawn tasks one after the other, like a server spawning a task per request.
+async fn run_bench_spawn_sequential() {
+    for i in 0 .. 1_000u64 {
+        let _ = task::spawn(async move { i }).await;
+    }
+}
+
+fn bench_spawn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nara");
+    group.bench_function("spawn", |b| {
+        b.to_async(NaraExecutor).iter(run_bench_spawn);
+    });
+    group.bench_function("spawn_sequential", |b| {
+        b.to_async(NaraExecutor).iter(run_bench_spawn_sequential);
+    });
+}
+
+criterion_group!(benches, bench_spawn);
+criterion_main!(benches);
@@ -1,7 +1,6 @@
 use std::thread::sleep as std_sleep;
 use std::time::Duration;
 
-use nara::io::AsyncReadExt;
 use nara::runtime::Runtime;
 use nara::task;
 use nara::time::sleep;
@@ -63,6 +62,7 @@ async fn test_sleep() {
     println!("test_sleep: timer done");
 }
 
+#[allow(clippy::never_loop)]
 async fn test_tcp() -> std::io::Result<()> {
         println!("test_tcp: open tcp connection");
         let mut tcp = TcpStream::connect(("smtp.bit.nl", 25)).await?;
@@ -87,7 +87,7 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
         test_spawn_blocking().await;
         test_sleep().await;
         let _ = test_tcp().await.map_err(|e| println!("test_tcp: error: {}", e));
-        return Ok::<_, std::io::Error>(3u32);
+        Ok::<_, std::io::Error>(3u32)
     });
 
     println!("final: block_on return value {:?}", n);
@@ -0,0 +1,267 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::waiters::WaitQueue;
+
+// Re-exports.
+pub use std::sync::mpsc::SendError;
+
+// Shared channel state.
+struct Shared<T> {
+    // The last `capacity` messages. buffer[0] has position `head`.
+    buffer: VecDeque<T>,
+    capacity: usize,
+    head: u64,
+    rx_wakers: WaitQueue,
+    receivers: usize,
+    senders: usize,
+}
+
+impl<T> Shared<T> {
+    // Position of the next message to be sent.
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+
+    fn wake_receivers(&mut self) {
+        self.rx_wakers.wake_all();
+    }
+
+    // Add a receiver that sees messages sent from now on.
+    fn new_receiver(this: &Rc<RefCell<Shared<T>>>) -> Receiver<T> {
+        let mut shared = this.borrow_mut();
+        shared.receivers += 1;
+        let id = shared.rx_wakers.next_id();
+        Receiver { id, pos: shared.tail(), shared: this.clone() }
+    }
+}
+
+/// Error returned by `Receiver::recv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// All senders are gone, and all messages have been received.
+    Closed,
+    /// The receiver fell behind; this many messages were dropped for it.
+    /// The next `recv` returns the oldest message that is still there.
+    Lagged(u64),
+}
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecvError::Closed => write!(f, "channel closed"),
+            RecvError::Lagged(n) => write!(f, "receiver lagged behind by {} messages", n),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+/// Error returned by `Receiver::try_recv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// There is no new message.
+    Empty,
+    /// All senders are gone, and all messages have been received.
+    Closed,
+    /// See `RecvError::Lagged`.
+    Lagged(u64),
+}
+
+impl std::fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel empty"),
+            TryRecvError::Closed => write!(f, "channel closed"),
+            TryRecvError::Lagged(n) => write!(f, "receiver lagged behind by {} messages", n),
+        }
+    }
+}
+
+impl std::error::Error for TryRecvError {}
+
+/// Create a new broadcast channel.
+///
+/// Every receiver gets every message that was sent after it was created.
+/// The channel keeps the last `capacity` messages; a receiver that falls
+/// further behind gets `RecvError::Lagged` and skips ahead.
+///
+/// Panics if `capacity` is 0.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast::channel: capacity must be > 0");
+    let mut rx_wakers = WaitQueue::new();
+    let id = rx_wakers.next_id();
+    let shared = Rc::new(RefCell::new(Shared {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        head: 0,
+        rx_wakers,
+        receivers: 1,
+        senders: 1,
+    }));
+    (Sender { shared: shared.clone() }, Receiver { id, pos: 0, shared })
+}
+
+/// Sender side of the broadcast channel. Can have multiple instances.
+pub struct Sender<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Send a message to all receivers.
+    ///
+    /// Returns the number of receivers. Fails, and returns the message,
+    /// if there are no receivers.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.receivers == 0 {
+            return Err(SendError(value));
+        }
+        if shared.buffer.len() == shared.capacity {
+            shared.buffer.pop_front();
+            shared.head += 1;
+        }
+        shared.buffer.push_back(value);
+        shared.wake_receivers();
+        Ok(shared.receivers)
+    }
+
+    /// Create a new receiver, that gets the messages sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        Shared::new_receiver(&self.shared)
+    }
+
+    /// Number of receivers.
+    pub fn receiver_count(&self) -> usize {
+        self.shared.borrow().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.borrow_mut().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            shared.wake_receivers();
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Shared::fmt_debug(&self.shared, "Sender", f)
+    }
+}
+
+/// Receiver side of the broadcast channel.
+pub struct Receiver<T> {
+    id: u64,
+    // Position of the next message for this receiver.
+    pos: u64,
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Receive the next message.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        std::future::poll_fn(|cx: &mut Context<'_>| {
+            // Yield every now and then, so we don't starve I/O.
+            if crate::executor::poll_budget(cx).is_pending() {
+                return Poll::Pending;
+            }
+            match self.try_recv() {
+                Ok(value) => Poll::Ready(Ok(value)),
+                Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+                Err(TryRecvError::Lagged(n)) => Poll::Ready(Err(RecvError::Lagged(n))),
+                Err(TryRecvError::Empty) => {
+                    // Arrange for us to be woken when a message is sent.
+                    let mut shared = self.shared.borrow_mut();
+                    shared.rx_wakers.register(self.id, cx.waker(), ());
+                    Poll::Pending
+                },
+            }
+        }).await
+    }
+
+    /// Receive the next message, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let shared = self.shared.borrow();
+        if self.pos < shared.head {
+            let missed = shared.head - self.pos;
+            self.pos = shared.head;
+            return Err(TryRecvError::Lagged(missed));
+        }
+        if self.pos < shared.tail() {
+            let value = shared.buffer[(self.pos - shared.head) as usize].clone();
+            self.pos += 1;
+            return Ok(value);
+        }
+        if shared.senders == 0 {
+            return Err(TryRecvError::Closed);
+        }
+        Err(TryRecvError::Empty)
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Create a new receiver, that gets the messages sent from now on.
+    pub fn resubscribe(&self) -> Receiver<T> {
+        Shared::new_receiver(&self.shared)
+    }
+
+    /// Number of messages that this receiver has not received yet.
+    pub fn len(&self) -> usize {
+        let shared = self.shared.borrow();
+        (shared.tail() - std::cmp::max(self.pos, shared.head)) as usize
+    }
+
+    /// Are there no messages for this receiver?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.receivers -= 1;
+        shared.rx_wakers.remove(self.id);
+    }
+}
+
+impl<T> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Shared::fmt_debug(&self.shared, "Receiver", f)
+    }
+}
+
+impl<T> Shared<T> {
+    // Debug helper. Does not panic if the channel is already borrowed.
+    fn fmt_debug(
+        this: &RefCell<Shared<T>>,
+        name: &str,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        let mut d = f.debug_struct(name);
+        match this.try_borrow() {
+            Ok(shared) => {
+                d.field("capacity", &shared.capacity);
+                d.field("len", &shared.buffer.len());
+                d.field("receivers", &shared.receivers);
+            },
+            Err(_) => {
+                d.field("channel", &format_args!("<borrowed>"));
+            },
+        }
+        d.finish()
+    }
+}
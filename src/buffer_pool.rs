@@ -0,0 +1,121 @@
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+// Size of the buffers in the thread-local pool.
+const LOCAL_BUF_SIZE: usize = 8192;
+// Maximum number of idle buffers kept in the thread-local pool.
+const LOCAL_MAX_BUFFERS: usize = 64;
+
+thread_local! {
+    static LOCAL: BufferPool = BufferPool::new(LOCAL_BUF_SIZE, LOCAL_MAX_BUFFERS);
+}
+
+struct Inner {
+    buf_size:       usize,
+    max_buffers:    usize,
+    free:           RefCell<Vec<Box<[u8]>>>,
+}
+
+/// A pool of fixed-size byte buffers.
+///
+/// `get` hands out a `PooledBuffer`, which goes back into the pool when
+/// it is dropped, so that the next `get` does not have to allocate. At
+/// most `max_buffers` idle buffers are kept; the rest is freed, so that a
+/// burst of activity does not leave a lot of memory behind.
+///
+/// The pool is not thread-safe, it's meant to be used by the tasks of
+/// one runtime. Clones share the same pool.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Rc<Inner>,
+}
+
+impl BufferPool {
+    /// Create a new pool of buffers of `buf_size` bytes, keeping at most
+    /// `max_buffers` idle buffers.
+    pub fn new(buf_size: usize, max_buffers: usize) -> BufferPool {
+        let inner = Inner { buf_size, max_buffers, free: RefCell::new(Vec::new()) };
+        BufferPool { inner: Rc::new(inner) }
+    }
+
+    /// The pool of this thread, with 8 KiB buffers.
+    ///
+    /// This is the pool that `copy_bidirectional` uses.
+    pub fn local() -> BufferPool {
+        LOCAL.with(|pool| pool.clone())
+    }
+
+    /// Get a buffer from the pool, or allocate a new one if it is empty.
+    ///
+    /// The contents of a reused buffer are whatever the previous user left in it.
+    pub fn get(&self) -> PooledBuffer {
+        let buf = self.inner.free.borrow_mut().pop();
+        let buf = buf.unwrap_or_else(|| vec![0; self.inner.buf_size].into_boxed_slice());
+        PooledBuffer { buf, pool: self.inner.clone() }
+    }
+
+    /// Size of the buffers in bytes.
+    pub fn buf_size(&self) -> usize {
+        self.inner.buf_size
+    }
+
+    /// Number of idle buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.inner.free.borrow().len()
+    }
+
+    /// Is the pool empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl std::fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("BufferPool");
+        d.field("buf_size", &self.inner.buf_size);
+        d.field("max_buffers", &self.inner.max_buffers);
+        if let Ok(free) = self.inner.free.try_borrow() {
+            d.field("len", &free.len());
+        }
+        d.finish()
+    }
+}
+
+/// A buffer from a `BufferPool`. Returns to the pool when dropped.
+///
+/// Dereferences to a `[u8]` of the pool's buffer size.
+pub struct PooledBuffer {
+    buf:    Box<[u8]>,
+    pool:   Rc<Inner>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl std::fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PooledBuffer").field("len", &self.buf.len()).finish()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.borrow_mut();
+        if free.len() < self.pool.max_buffers {
+            free.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
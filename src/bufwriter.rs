@@ -0,0 +1,165 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures_io::AsyncWrite;
+use crate::time::Sleep;
+
+const DEFAULT_CAPACITY: usize = 8192;
+
+/// Adds buffering to a writer.
+///
+/// Data is written to the inner writer when the buffer is full, or on an
+/// explicit flush or close. Optionally, with `set_flush_latency`, buffered
+/// data is also flushed when it has been waiting for longer than a maximum
+/// latency. That batches many small writes into fewer system calls (and
+/// TCP segments), while bounding the extra delay.
+pub struct BufWriter<W> {
+    inner:      W,
+    buf:        Vec<u8>,
+    written:    usize,
+    capacity:   usize,
+    latency:    Option<Duration>,
+    // Armed when the first byte is buffered after a flush.
+    sleep:      Option<Sleep>,
+}
+
+impl<W: AsyncWrite + Unpin> BufWriter<W> {
+    /// Create a new BufWriter with a default buffer size.
+    pub fn new(inner: W) -> BufWriter<W> {
+        Self::with_capacity(DEFAULT_CAPACITY, inner)
+    }
+
+    /// Create a new BufWriter with a buffer of `capacity` bytes.
+    pub fn with_capacity(capacity: usize, inner: W) -> BufWriter<W> {
+        BufWriter {
+            inner,
+            buf: Vec::with_capacity(capacity),
+            written: 0,
+            capacity,
+            latency: None,
+            sleep: None,
+        }
+    }
+
+    /// Set the maximum time that data may sit in the buffer.
+    ///
+    /// The default is `None`: only flush when the buffer is full, or when
+    /// asked to. The latency is enforced on the next write, or by awaiting
+    /// `flush_due`, which is meant to be used in a `select!` next to
+    /// whatever produces the data to write.
+    pub fn set_flush_latency(&mut self, latency: Option<Duration>) {
+        self.latency = latency;
+        if latency.is_none() {
+            self.sleep = None;
+        }
+    }
+
+    /// Get the maximum flush latency.
+    pub fn flush_latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Wait until the buffered data is older than the flush latency,
+    /// then flush it.
+    ///
+    /// If there is no buffered data, or no latency was set, this never
+    /// completes.
+    pub async fn flush_due(&mut self) -> io::Result<()> {
+        std::future::poll_fn(|cx| {
+            let Some(sleep) = self.sleep.as_mut() else {
+                return Poll::Pending;
+            };
+            ready!(Pin::new(sleep).poll(cx));
+            ready!(self.poll_flush_buf(cx))?;
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }).await
+    }
+
+    /// Get a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the inner writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// The data that has been buffered but not written yet.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.written..]
+    }
+
+    /// Return the inner writer. Buffered data is lost.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    // Write out the buffer.
+    fn poll_flush_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.buf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buf[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.buf.clear();
+        self.written = 0;
+        self.sleep = None;
+        Poll::Ready(Ok(()))
+    }
+
+    fn flush_is_due(&self) -> bool {
+        self.sleep.as_ref().is_some_and(|s| s.is_elapsed())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for BufWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.flush_is_due() || this.buf.len() + buf.len() > this.capacity {
+            ready!(this.poll_flush_buf(cx))?;
+        }
+        if buf.len() >= this.capacity {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if this.buf.is_empty() {
+            if let Some(latency) = this.latency {
+                this.sleep = Some(crate::time::sleep(latency));
+            }
+        }
+        this.buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_buf(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+impl<W: std::fmt::Debug> std::fmt::Debug for BufWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BufWriter")
+            .field("inner", &self.inner)
+            .field("buffered", &(self.buf.len() - self.written))
+            .field("capacity", &self.capacity)
+            .field("latency", &self.latency)
+            .finish()
+    }
+}
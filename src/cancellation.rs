@@ -0,0 +1,83 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::waiters::{WaitQueue, WaiterGuard};
+
+// Shared token state.
+struct Inner {
+    cancelled: bool,
+    waiters: WaitQueue,
+}
+
+impl Inner {
+    fn remove_waiter(&mut self, id: u64) {
+        self.waiters.remove(id);
+    }
+}
+
+/// A token to signal cancellation, for example to shut down an accept loop.
+///
+/// Clones share the same state: calling `cancel` on one of them wakes up
+/// everyone waiting in `cancelled` on any of them. Once cancelled, a token
+/// stays cancelled.
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl CancellationToken {
+    /// Create a new token.
+    pub fn new() -> CancellationToken {
+        let inner = Inner { cancelled: false, waiters: WaitQueue::new() };
+        CancellationToken { inner: Rc::new(RefCell::new(inner)) }
+    }
+
+    /// Cancel the token, and wake up all waiters.
+    pub fn cancel(&self) {
+        let mut waiters = {
+            let mut inner = self.inner.borrow_mut();
+            inner.cancelled = true;
+            std::mem::replace(&mut inner.waiters, WaitQueue::new())
+        };
+        waiters.wake_all();
+    }
+
+    /// Has the token been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.borrow().cancelled
+    }
+
+    /// Wait until the token is cancelled.
+    ///
+    /// This is cancel-safe: dropping the future removes its waker, so it can
+    /// be used in a `select!` in a loop.
+    pub async fn cancelled(&self) {
+        let id = self.inner.borrow_mut().waiters.next_id();
+        let _guard = WaiterGuard::new(id, &self.inner, Inner::remove_waiter);
+        std::future::poll_fn(|cx: &mut Context<'_>| {
+            let mut inner = self.inner.borrow_mut();
+            if inner.cancelled {
+                return Poll::Ready(());
+            }
+            inner.waiters.register(id, cx.waker(), ());
+            Poll::Pending
+        }).await
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+impl std::fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("CancellationToken");
+        if let Ok(inner) = self.inner.try_borrow() {
+            d.field("cancelled", &inner.cancelled);
+        }
+        d.finish()
+    }
+}
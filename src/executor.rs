@@ -6,35 +6,55 @@ use std::io::Read;
 use std::os::fd::AsRawFd;
 use std::rc::{Rc, Weak};
 use std::sync::Arc;
-use std::task::Wake;
+use std::task::{Context, Poll, Wake};
+use std::time::Duration;
 
 use crate::reactor::{Interest, Reactor, Registration};
+use crate::runtime::ScheduleOrder;
 use crate::syscall;
-use crate::task::{JoinHandle, Task};
+use crate::task::{LocalJoinHandle, Task, TaskEvent, TaskEventKind, TaskWaker, WakeQueue};
 use crate::threadpool::ThreadPool;
 use crate::time::Timer;
 
+// How many operations a task can complete before it is forced to yield.
+const BUDGET: u32 = 128;
+
+// Maximum number of TaskWakers kept around for reuse.
+const TASK_WAKER_POOL_SIZE: usize = 256;
+
 pub (crate) struct Executor {
     inner: Rc<InnerExecutor>,
 }
 
 pub(crate) struct InnerExecutor {
-    // Pipe for cross-thread wakeups.
-    wake_pipe: Registration,
-    // Read wakeup requests from this file
-    wake_pipe_rx: File,
-    // Write wkaeup requests to this file.
-    wake_pipe_tx: File,
+    // Eventfd or pipe for cross-thread wakeups, unless disabled.
+    wake_fd: Option<WakeFd>,
+    // Wakeup requests from other threads.
+    wake_queue: Arc<WakeQueue>,
     // waiting to run.
     runq: RefCell<VecDeque<Task>>,
+    // order in which the run queue is processed.
+    schedule_order: ScheduleOrder,
+    // state of the random generator for ScheduleOrder::Random.
+    rng: Cell<u64>,
     // tasks not currently running.
     tasks: RefCell<HashMap<u64, Task>>,
     // current task.
     current_id: Cell<u64>,
     // current task woken?
     current_woken: Cell<bool>,
+    // cooperative scheduling budget of the current task.
+    budget: Cell<u32>,
+    // current task yielded?
+    yielded: Cell<bool>,
+    // tasks that yielded, to be run after the reactor has run.
+    yielded_tasks: RefCell<Vec<u64>>,
     // next unique id
     next_id: Cell<u64>,
+    // TaskWakers of finished tasks, to save an allocation per spawn.
+    task_waker_pool: RefCell<Vec<Arc<TaskWaker>>>,
+    // Task event hook.
+    on_task_event: Option<TaskEventHook>,
     // Threadpool for spawn_nonblocking
     pub pool: ThreadPool,
     // Timers
@@ -43,24 +63,54 @@ pub(crate) struct InnerExecutor {
     pub reactor: Reactor,
 }
 
+pub(crate) type TaskEventHook = Box<dyn Fn(TaskEvent<'_>)>;
+
+// Read end of the cross-thread wakeup eventfd or pipe.
+struct WakeFd {
+    reg: Registration,
+    // Read wakeup requests from this file
+    rx: File,
+}
+
 thread_local! {
     // Valid after Executor::activate(), invalid after Executor::deactivate()
-    pub(crate) static EXECUTOR: RefCell<Weak<InnerExecutor>> = RefCell::new(Weak::new());
+    pub(crate) static EXECUTOR: RefCell<Weak<InnerExecutor>> = const { RefCell::new(Weak::new()) };
 }
 
 impl Executor {
-    pub fn new(reactor: Reactor, timer: Timer) -> Self {
-        let (rx, tx) = syscall::pipe().unwrap();
-        let wake_pipe = reactor.registration(rx.as_raw_fd());
+    pub fn new(
+        reactor: Reactor,
+        timer: Timer,
+        on_task_event: Option<TaskEventHook>,
+        cross_thread_wake: bool,
+        schedule_order: ScheduleOrder,
+    ) -> Self {
+        let seed = match schedule_order {
+            ScheduleOrder::Random(seed) => seed,
+            _ => 0,
+        };
+        let (wake_fd, tx) = if cross_thread_wake {
+            let (rx, tx) = syscall::wakeup_fds().unwrap();
+            let reg = reactor.registration(rx.as_raw_fd()).unwrap();
+            (Some(WakeFd { reg, rx }), Some(tx))
+        } else {
+            (None, None)
+        };
         let inner = Rc::new(InnerExecutor {
-            wake_pipe,
-            wake_pipe_rx: rx,
-            wake_pipe_tx: tx,
+            wake_fd,
+            wake_queue: Arc::new(WakeQueue::new(tx)),
             runq: RefCell::new(VecDeque::new()),
+            schedule_order,
+            rng: Cell::new(seed),
             tasks: RefCell::new(HashMap::new()),
             current_id: Cell::new(0),
             current_woken: Cell::new(false),
+            budget: Cell::new(BUDGET),
+            yielded: Cell::new(false),
+            yielded_tasks: RefCell::new(Vec::new()),
             next_id: Cell::new(1),
+            task_waker_pool: RefCell::new(Vec::new()),
+            on_task_event,
             pool: ThreadPool::new(),
             reactor,
             timer,
@@ -68,6 +118,16 @@ impl Executor {
         Executor { inner }
     }
 
+    // The threadpool for spawn_blocking.
+    pub fn pool(&self) -> &ThreadPool {
+        &self.inner.pool
+    }
+
+    // Weak reference to the inner executor, for runtime::Handle.
+    pub fn downgrade(&self) -> Weak<InnerExecutor> {
+        Rc::downgrade(&self.inner)
+    }
+
     // Activate the thread-local reference.
     pub fn activate(&self) {
         EXECUTOR.with_borrow_mut(|t| *t = Rc::downgrade(&self.inner));
@@ -83,10 +143,31 @@ impl Executor {
     }
 
     fn pop_task(&self) -> Option<Task> {
-        self.inner.runq.borrow_mut().pop_back()
+        let mut runq = self.inner.runq.borrow_mut();
+        match self.inner.schedule_order {
+            ScheduleOrder::Lifo => runq.pop_back(),
+            ScheduleOrder::Fifo => runq.pop_front(),
+            ScheduleOrder::Random(_) => {
+                if runq.is_empty() {
+                    return None;
+                }
+                let idx = (self.inner.next_random() % runq.len() as u64) as usize;
+                runq.swap_remove_back(idx)
+            },
+        }
     }
 
     pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.block_on_inner(future, false).unwrap()
+    }
+
+    // Like block_on, but returns None if the runtime is idle: nothing is
+    // running or waiting for I/O, timers or the threadpool.
+    pub fn block_on_until_idle<F: Future>(&self, future: F) -> Option<F::Output> {
+        self.block_on_inner(future, true)
+    }
+
+    fn block_on_inner<F: Future>(&self, future: F, stop_when_idle: bool) -> Option<F::Output> {
         let this = &self.inner;
 
         // The passed in future does not have to be Send or 'static. All current
@@ -99,49 +180,127 @@ impl Executor {
 
         // This is the entire scheduler.
         loop {
+            let poll_main = |cx: &mut Context<'_>| main_future.as_mut().poll(cx);
+            if let Some(output) = self.run_tasks(main_task_id, poll_main) {
+                return Some(output);
+            }
+            if stop_when_idle && self.is_idle() {
+                // Pick up any pending cross-thread wakeups.
+                self.park(Some(Duration::ZERO));
+                if this.runq.borrow().is_empty() {
+                    return None;
+                }
+                continue;
+            }
+            self.park(None);
+        }
+    }
 
-            // Loop over the wake up messages in the queue.
-            while let Some(mut task) = self.pop_task() {
-
-                this.current_id.set(task.id);
-                this.current_woken.set(false);
-
-                loop {
-                    if task.id == main_task_id {
-                        // Poll the main future.
-                        use std::task::{Context, Poll};
-                        let mut cx = Context::from_waker(&task.waker);
-                        if let Poll::Ready(output) = main_future.as_mut().poll(&mut cx) {
-                            return output;
-                        }
-                    } else {
-                        // Poll the task.
-                        if task.poll().is_ready() {
-                            break;
-                        }
-                    }
+    // Check if nothing can wake up a task anymore. Wakeups from other
+    // threads that are not part of the runtime can not be detected.
+    fn is_idle(&self) -> bool {
+        let this = &self.inner;
+        this.runq.borrow().is_empty() &&
+            this.yielded_tasks.borrow().is_empty() &&
+            this.timer.is_empty() &&
+            !this.pool.is_busy() &&
+            !this.reactor.has_waiters(this.wake_fd.as_ref().map(|w| &w.reg))
+    }
 
-                    // Stop the loop, _unless_ we woke ourself.
-                    if !this.current_woken.replace(false) {
-                        // Put the task back.
-                        this.tasks.borrow_mut().insert(task.id, task);
+    // Run one iteration of the scheduler, waiting at most `timeout` for I/O.
+    // Returns `true` if there are tasks ready to run.
+    pub fn turn(&self, timeout: Option<Duration>) -> bool {
+        self.run_tasks(0, |_| Poll::<()>::Pending);
+        self.park(timeout);
+        !self.inner.runq.borrow().is_empty()
+    }
+
+    // Run all tasks on the run queue. The main task is not a real task,
+    // it's polled by calling `poll_main`. If that returns Ready, we're done.
+    fn run_tasks<T>(
+        &self,
+        main_task_id: u64,
+        mut poll_main: impl FnMut(&mut Context<'_>) -> Poll<T>,
+    ) -> Option<T> {
+        let this = &self.inner;
+
+        // Loop over the wake up messages in the queue.
+        while let Some(mut task) = self.pop_task() {
+
+            this.current_id.set(task.id);
+            this.current_woken.set(false);
+            this.budget.set(BUDGET);
+            this.yielded.set(false);
+
+            loop {
+                this.task_event(&task, TaskEventKind::Polling);
+                if task.id == main_task_id {
+                    // Poll the main future.
+                    let mut cx = Context::from_waker(&task.waker);
+                    if let Poll::Ready(output) = poll_main(&mut cx) {
+                        this.task_event(&task, TaskEventKind::Completed);
+                        this.current_id.set(0);
+                        return Some(output);
+                    }
+                } else {
+                    // Poll the task.
+                    if task.poll().is_ready() {
+                        this.task_event(&task, TaskEventKind::Completed);
+                        this.recycle(task);
                         break;
                     }
                 }
+                let kind = if this.yielded.get() { TaskEventKind::Yielded } else { TaskEventKind::Pending };
+                this.task_event(&task, kind);
+
+                // Stop the loop, _unless_ we woke ourself.
+                if !this.current_woken.replace(false) {
+                    // Put the task back.
+                    this.tasks.borrow_mut().insert(task.id, task);
+                    break;
+                }
+
+                // If we yielded, run again after the reactor has run.
+                if this.yielded.replace(false) {
+                    this.yielded_tasks.borrow_mut().push(task.id);
+                    this.tasks.borrow_mut().insert(task.id, task);
+                    break;
+                }
+            }
+        }
+        this.current_id.set(0);
+        None
+    }
+
+    // Wait for I/O or the next timer, but no longer than `max_wait`.
+    fn park(&self, max_wait: Option<Duration>) {
+        let this = &self.inner;
+
+        // This is suboptimal, see comment in impl Waker for ExecutorWaker.
+        if let Some(wake_fd) = this.wake_fd.as_ref() {
+            if wake_fd.reg.was_woken(Interest::READ) {
+                wake_fd.reg.wake_when(Interest::READ, Arc::new(ExecutorWaker).into());
             }
-            this.current_id.set(0);
+        }
 
-            // This is suboptimal, see comment in impl Waker for ExecutorWaker.
-            if this.wake_pipe.was_woken() {
-                this.wake_pipe.wake_when(Interest::Read, Arc::new(ExecutorWaker).into());
+        // Wait for I/O. Don't wait if there are tasks that yielded.
+        let timeout = if this.yielded_tasks.borrow().is_empty() {
+            match (this.timer.next_deadline(), max_wait) {
+                (Some(t), Some(m)) => Some(std::cmp::min(t, m)),
+                (t, m) => t.or(m),
             }
+        } else {
+            Some(Duration::ZERO)
+        };
+        this.reactor.react(timeout);
 
-            // Wait for I/O.
-            let timeout = this.timer.next_deadline();
-            this.reactor.react(timeout);
+        // Run timers.
+        this.timer.tick();
 
-            // Run timers.
-            this.timer.tick();
+        // Requeue tasks that yielded.
+        let yielded = std::mem::take(&mut *this.yielded_tasks.borrow_mut());
+        for id in yielded {
+            this.queue(id);
         }
     }
 }
@@ -149,10 +308,16 @@ impl Executor {
 impl InnerExecutor {
 
     // Create a new task and put it on the run queue right away.
-    pub(crate) fn spawn<F: Future + 'static>(&self, fut: F) -> JoinHandle<F::Output> {
+    pub(crate) fn spawn<F: Future + 'static>(&self, fut: F) -> LocalJoinHandle<F::Output> {
+        self.spawn_named(None, fut)
+    }
+
+    // Like spawn, with an optional name for the task.
+    pub(crate) fn spawn_named<F: Future + 'static>(&self, name: Option<&str>, fut: F) -> LocalJoinHandle<F::Output> {
         let id = self.next_id.get();
         self.next_id.set(id + 1);
-        let (task, handle) = Task::new(id, self.wake_pipe_tx.as_raw_fd(), fut);
+        let (task, handle) = Task::new(id, name, self.task_waker(id), fut);
+        self.task_event(&task, TaskEventKind::Spawned);
         self.runq.borrow_mut().push_back(task);
         handle
     }
@@ -161,11 +326,57 @@ impl InnerExecutor {
     pub(crate) fn spawn_main(&self) -> u64 {
         let id = self.next_id.get();
         self.next_id.set(id + 1);
-        let task = Task::main_task(id, self.wake_pipe_tx.as_raw_fd());
+        let task = Task::main_task(id, self.task_waker(id));
+        self.task_event(&task, TaskEventKind::Spawned);
         self.runq.borrow_mut().push_back(task);
         id
     }
 
+    // splitmix64. Deterministic for a given seed, which is the point.
+    fn next_random(&self) -> u64 {
+        let state = self.rng.get().wrapping_add(0x9e3779b97f4a7c15);
+        self.rng.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn task_waker(&self, id: u64) -> Arc<TaskWaker> {
+        TaskWaker::get(id, &self.wake_queue, &mut self.task_waker_pool.borrow_mut())
+    }
+
+    // The threadpool for spawn_blocking. Its threads wake up tasks,
+    // so it can not be used if cross-thread wakeups are disabled.
+    pub(crate) fn blocking_pool(&self) -> &ThreadPool {
+        match self.try_blocking_pool() {
+            Some(pool) => pool,
+            None => panic!("spawn_blocking: cross-thread wakeups are disabled (runtime::Builder::allow_cross_thread_wake)"),
+        }
+    }
+
+    // Like blocking_pool, but returns None instead of panicking.
+    pub(crate) fn try_blocking_pool(&self) -> Option<&ThreadPool> {
+        self.wake_fd.as_ref().map(|_| &self.pool)
+    }
+
+    // Keep the TaskWaker of a finished task for reuse.
+    fn recycle(&self, task: Task) {
+        if let Some(task_waker) = task.into_task_waker() {
+            let mut pool = self.task_waker_pool.borrow_mut();
+            if pool.len() < TASK_WAKER_POOL_SIZE {
+                pool.push(task_waker);
+            }
+        }
+    }
+
+    // Call the task event hook, if set.
+    fn task_event(&self, task: &Task, kind: TaskEventKind) {
+        if let Some(hook) = self.on_task_event.as_ref() {
+            hook(TaskEvent { id: task.id, name: task.name.as_deref(), kind });
+        }
+    }
+
     // Queue a task onto the run queue.
     pub(crate) fn queue(&self, task_id: u64) {
         // If we're already the active task, just take a note.
@@ -180,26 +391,55 @@ impl InnerExecutor {
     }
 }
 
+// Consume one unit of the current task's budget. If the budget is
+// exhausted, the task is woken and yields: it will run again after
+// the reactor and timers had a chance to run.
+pub(crate) fn poll_budget(cx: &mut Context<'_>) -> Poll<()> {
+    EXECUTOR.with_borrow(|e| {
+        let Some(executor) = e.upgrade() else {
+            return Poll::Ready(());
+        };
+        let budget = executor.budget.get();
+        if budget > 0 {
+            executor.budget.set(budget - 1);
+            return Poll::Ready(());
+        }
+        executor.yielded.set(true);
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
+
+// Wake the current task and mark it as yielded, so that it runs again
+// after the reactor and timers instead of right away.
+pub(crate) fn yield_task(cx: &mut Context<'_>) {
+    EXECUTOR.with_borrow(|e| {
+        if let Some(executor) = e.upgrade() {
+            executor.yielded.set(true);
+        }
+    });
+    cx.waker().wake_by_ref();
+}
+
 struct ExecutorWaker;
 
 impl Wake for ExecutorWaker {
     fn wake(self: Arc<Self>) {
         EXECUTOR.with_borrow(|e| {
             let executor = e.upgrade().unwrap();
+            // First empty the eventfd or pipe, then take the ids. In this
+            // order, an id that is queued after we took them will cause a
+            // new write.
             let mut buf: [u8; 256] = [0; 256];
-            let mut fh = &executor.wake_pipe_rx;
+            let mut fh = &executor.wake_fd.as_ref().unwrap().rx;
             while let Ok(n) = fh.read(&mut buf) {
-                if n % 8 != 0 {
-                    panic!("read a non-multiple-of-8 from the pipe, expected u64");
-                }
-                for b in buf[..n].chunks(8) {
-                    let id = u64::from_ne_bytes(b.try_into().unwrap());
-                    executor.queue(id);
-                }
                 if n < buf.len() {
                     break;
                 }
             }
+            for id in executor.wake_queue.take() {
+                executor.queue(id);
+            }
         })
         // We really should re-use 'self' here as a Waker, but we cannot
         // call back into the reactor via Registration at this point
@@ -207,3 +447,59 @@ impl Wake for ExecutorWaker {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Barrier};
+    use std::time::{Duration, Instant};
+
+    use super::EXECUTOR;
+
+    fn wakeup_writes() -> usize {
+        EXECUTOR.with_borrow(|e| e.upgrade().unwrap().wake_queue.writes.load(Ordering::Relaxed))
+    }
+
+    // Jobs on the blocking pool that did not finish yet. A job counts
+    // until after it has set its result and woken its task.
+    fn blocking_busy() -> bool {
+        EXECUTOR.with_borrow(|e| e.upgrade().unwrap().pool.is_busy())
+    }
+
+    // 16 blocking tasks that finish at the same time, while the runtime
+    // thread is busy, cost a single write to the wakeup fd.
+    #[test]
+    fn blocking_fan_in_coalesces_wakeups() {
+        let rt = crate::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            const TASKS: usize = 16;
+            let barrier = Arc::new(Barrier::new(TASKS + 1));
+            let handles: Vec<_> = (0 .. TASKS).map(|i| {
+                let barrier = barrier.clone();
+                crate::task::spawn_blocking(move || {
+                    barrier.wait();
+                    i
+                })
+            }).collect();
+
+            // Poll all handles once, so that they have a waker.
+            let joiner = crate::task::spawn(futures::future::join_all(handles));
+            crate::task::yield_now().await;
+            let before = wakeup_writes();
+
+            // Release the blocking tasks together, and wait until they have
+            // all finished, without letting the runtime run.
+            barrier.wait();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while blocking_busy() {
+                assert!(Instant::now() < deadline, "blocking tasks did not finish");
+                std::thread::yield_now();
+            }
+
+            let results = joiner.await.unwrap();
+            assert_eq!(results.len(), TASKS);
+            assert!(results.into_iter().all(|r| r.is_ok()));
+            assert_eq!(wakeup_writes() - before, 1);
+        });
+    }
+}
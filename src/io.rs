@@ -2,20 +2,345 @@
 pub use std::io::{Error, ErrorKind, Result};
 pub use futures_io::{AsyncBufRead, AsyncRead, AsyncSeek, AsyncWrite};
 pub use futures_util::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-pub use futures_util::io::{BufReader, BufWriter};
+pub use futures_util::io::BufReader;
+pub use buffer_pool::{BufferPool, PooledBuffer};
+pub use bufwriter::BufWriter;
+pub use length_prefix::{read_length_prefixed, write_length_prefixed, LengthPrefix};
+pub use lines::{lines, Lines};
+pub use crate::reactor::{Interest, Readiness};
+
+/// Result of `copy_bidirectional_with_timeout`.
+///
+/// Both variants contain the number of bytes copied from `a` to `b`,
+/// and from `b` to `a`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyStatus {
+    /// Both directions reached end-of-file.
+    Finished(u64, u64),
+    /// No data was transferred in either direction for the idle timeout.
+    IdleTimeout(u64, u64),
+}
+
+/// Copy data in both directions between `a` and `b`.
+///
+/// When one side reaches end-of-file, the other side is shut down for
+/// writing. Returns when both directions are done, with the number of
+/// bytes copied from `a` to `b`, and from `b` to `a`.
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    match copy_bidirectional_inner(a, b, None).await? {
+        CopyStatus::Finished(a_to_b, b_to_a) => Ok((a_to_b, b_to_a)),
+        CopyStatus::IdleTimeout(..) => unreachable!(),
+    }
+}
+
+/// Like `copy_bidirectional`, but gives up when no data was transferred in
+/// either direction for `idle`.
+///
+/// A direction that has reached end-of-file does not keep the connection
+/// alive. After `CopyStatus::IdleTimeout` the streams should be closed,
+/// not used for further I/O.
+pub async fn copy_bidirectional_with_timeout<A, B>(a: &mut A, b: &mut B, idle: std::time::Duration) -> Result<CopyStatus>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    copy_bidirectional_inner(a, b, Some(idle)).await
+}
+
+async fn copy_bidirectional_inner<A, B>(a: &mut A, b: &mut B, idle: Option<std::time::Duration>) -> Result<CopyStatus>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    use std::future::Future;
+    use std::task::Poll;
+
+    let mut a_to_b = CopyBuf::new();
+    let mut b_to_a = CopyBuf::new();
+    let mut sleep = idle.map(crate::time::sleep);
+
+    std::future::poll_fn(|cx| {
+        // Yield every now and then, so we don't starve other tasks.
+        if crate::executor::poll_budget(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut progress = false;
+        let done1 = a_to_b.poll_copy(cx, a, b, &mut progress)?.is_ready();
+        let done2 = b_to_a.poll_copy(cx, b, a, &mut progress)?.is_ready();
+        if done1 && done2 {
+            return Poll::Ready(Ok(CopyStatus::Finished(a_to_b.amt, b_to_a.amt)));
+        }
+        if let (Some(sleep), Some(idle)) = (sleep.as_mut(), idle) {
+            if progress {
+                sleep.reset(std::time::Instant::now() + idle);
+            }
+            if std::pin::Pin::new(sleep).poll(cx).is_ready() {
+                return Poll::Ready(Ok(CopyStatus::IdleTimeout(a_to_b.amt, b_to_a.amt)));
+            }
+        }
+        Poll::Pending
+    }).await
+}
+
+/// Read until end-of-file or until `deadline`, appending to `buf`.
+///
+/// Returns the number of bytes appended to `buf`, both at end-of-file and
+/// when the deadline passes first. Either way, everything that was read is
+/// in `buf`, so no data is lost. To tell the two apart, check whether the
+/// deadline has passed; the read can be resumed by calling this function
+/// again with a new deadline.
+pub async fn read_to_end_deadline<R>(reader: &mut R, buf: &mut Vec<u8>, deadline: std::time::Instant) -> Result<usize>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    use std::future::Future;
+    use std::task::Poll;
+
+    const CHUNK: usize = 8192;
+    let start = buf.len();
+    let mut sleep = crate::time::sleep_until(deadline);
+
+    std::future::poll_fn(|cx| {
+        loop {
+            // Check the deadline first, also for a reader that is always ready.
+            if std::pin::Pin::new(&mut sleep).poll(cx).is_ready() {
+                return Poll::Ready(Ok(buf.len() - start));
+            }
+            // Yield every now and then, so we don't starve other tasks.
+            if crate::executor::poll_budget(cx).is_pending() {
+                return Poll::Pending;
+            }
+            // Read into the end of `buf`, and trim it back to what was read,
+            // so that `buf` only ever contains data when we return.
+            let len = buf.len();
+            buf.resize(len + CHUNK, 0);
+            let res = std::pin::Pin::new(&mut *reader).poll_read(cx, &mut buf[len..]);
+            let n = match &res {
+                Poll::Ready(Ok(n)) => *n,
+                _ => 0,
+            };
+            buf.truncate(len + n);
+            match res {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(buf.len() - start)),
+                Poll::Ready(Ok(_)) => {},
+                Poll::Ready(Err(e)) if e.kind() == ErrorKind::Interrupted => {},
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }).await
+}
+
+/// Something that buffers data and can be flushed.
+///
+/// This is implemented for every `AsyncWrite`, such as `BufWriter`, the
+/// channel writer from `unsync::mpsc::Sender::into_writer`, and
+/// `TcpStream`. It can be used as `&mut dyn Flushable`, so that a pipeline
+/// of different writers can be flushed uniformly, see `flush_all`.
+pub trait Flushable {
+    /// Attempt to flush the buffered data. Returns `Ready` when all of it
+    /// has been passed on to the layer below (for a `TcpStream`, the kernel).
+    fn poll_flush(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<()>>;
+
+    /// Flush the buffered data.
+    ///
+    /// Not named `flush`, so that it does not clash with
+    /// `AsyncWriteExt::flush` when both traits are in scope.
+    #[allow(async_fn_in_trait)]
+    async fn flush_buffered(&mut self) -> Result<()>
+    where
+        Self: Sized,
+    {
+        std::future::poll_fn(|cx| Flushable::poll_flush(self, cx)).await
+    }
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Flushable for W {
+    fn poll_flush(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<()>> {
+        AsyncWrite::poll_flush(std::pin::Pin::new(self), cx)
+    }
+}
+
+/// Flush all `writers` concurrently, and return when all are done.
+///
+/// If any of the flushes fails, the others still run to completion, and
+/// then the first error is returned.
+pub async fn flush_all(writers: &mut [&mut dyn Flushable]) -> Result<()> {
+    use std::task::Poll;
+
+    let mut done = vec![false; writers.len()];
+    let mut error = None;
+    std::future::poll_fn(|cx| {
+        for (writer, done) in writers.iter_mut().zip(done.iter_mut()) {
+            if *done {
+                continue;
+            }
+            if let Poll::Ready(res) = writer.poll_flush(cx) {
+                *done = true;
+                if let Err(e) = res {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if done.iter().all(|d| *d) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }).await;
+    error.map_or(Ok(()), Err)
+}
+
+#[path = "buffer_pool.rs"]
+mod buffer_pool;
+#[path = "bufwriter.rs"]
+mod bufwriter;
+#[path = "length_prefix.rs"]
+mod length_prefix;
+#[path = "lines.rs"]
+mod lines;
 
 //
 // Inner implementation details.
 //
 
+// One direction of a bidirectional copy.
+struct CopyBuf {
+    buf: PooledBuffer,
+    pos: usize,
+    cap: usize,
+    amt: u64,
+    read_done: bool,
+    done: bool,
+}
+
+impl CopyBuf {
+    fn new() -> CopyBuf {
+        CopyBuf { buf: BufferPool::local().get(), pos: 0, cap: 0, amt: 0, read_done: false, done: false }
+    }
+
+    // Copy from reader to writer until either would block, or until
+    // end-of-file, after which the writer is closed.
+    // `progress` is set if any data was transferred.
+    fn poll_copy<R, W>(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        reader: &mut R,
+        writer: &mut W,
+        progress: &mut bool,
+    ) -> std::task::Poll<Result<()>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        use std::pin::Pin;
+        use std::task::{ready, Poll};
+        while !self.done {
+            if self.pos == self.cap && !self.read_done {
+                match ready!(Pin::new(&mut *reader).poll_read(cx, &mut self.buf))? {
+                    0 => self.read_done = true,
+                    n => {
+                        self.pos = 0;
+                        self.cap = n;
+                        *progress = true;
+                    },
+                }
+            }
+            while self.pos < self.cap {
+                let n = ready!(Pin::new(&mut *writer).poll_write(cx, &self.buf[self.pos..self.cap]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                }
+                self.pos += n;
+                self.amt += n as u64;
+                *progress = true;
+            }
+            if self.read_done {
+                ready!(Pin::new(&mut *writer).poll_close(cx))?;
+                self.done = true;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Optional per-operation timeout for an I/O object.
+pub(crate) struct OpTimeout {
+    pub timeout: Option<std::time::Duration>,
+    sleep: Option<crate::time::Sleep>,
+}
+
+impl OpTimeout {
+    pub fn new() -> OpTimeout {
+        OpTimeout { timeout: None, sleep: None }
+    }
+
+    // Start a new operation. The timer of an earlier operation that was
+    // dropped before it completed must not carry over.
+    pub fn start(&mut self) {
+        self.sleep = None;
+    }
+
+    // Called with the result of an I/O operation. If the operation is
+    // pending, start or check the timer. On timeout, the waker that was
+    // registered for `interest` is removed and an error is returned.
+    pub fn poll<T>(
+        &mut self,
+        res: std::task::Poll<Result<T>>,
+        cx: &mut std::task::Context<'_>,
+        reg: &crate::reactor::Registration,
+        interest: crate::reactor::Interest,
+    ) -> std::task::Poll<Result<T>> {
+        use std::future::Future;
+        use std::task::Poll;
+        if res.is_ready() {
+            self.sleep = None;
+            return res;
+        }
+        let Some(timeout) = self.timeout else {
+            return Poll::Pending;
+        };
+        let sleep = self.sleep.get_or_insert_with(|| crate::time::sleep(timeout));
+        if std::pin::Pin::new(sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.sleep = None;
+        reg.remove_wake_when(interest);
+        Poll::Ready(Err(ErrorKind::TimedOut.into()))
+    }
+}
+
+
 // A macro that can be used to implement AsyncRead on a struct '$type'.
 //
 // That struct needs to have at least two members:
 // - $reader: an object that implements std::io::Read, and is set to non-blocking.
 // - $registration: a Registration struct.
+// - optional: $timeout - an OpTimeout member.
 //
 macro_rules! impl_async_read {
+    // helpers.
+    (@TIMEOUT $this: ident, $res: ident, $cx: ident, $registration: ident, _NONE) => {
+        $res
+    };
+    (@TIMEOUT $this: ident, $res: ident, $cx: ident, $registration: ident, $timeout: ident) => {
+        $this.$timeout.poll($res, $cx, &$this.$registration, $crate::reactor::Interest::READ)
+    };
+    (@START $this: ident, _NONE) => {};
+    (@START $this: ident, $timeout: ident) => {
+        $this.$timeout.start();
+    };
+
+    // entrypoint without timeout.
     ($type: ty, $reader: ident, $registration: ident) => {
+        $crate::io::impl_async_read!($type, $reader, $registration, _NONE);
+    };
+
+    // entrypoint with timeout.
+    ($type: ty, $reader: ident, $registration: ident, $timeout: ident) => {
         impl $crate::io::AsyncRead for $type {
             fn poll_read(
                 mut self: std::pin::Pin<&mut Self>,
@@ -23,22 +348,77 @@ macro_rules! impl_async_read {
                 buf: &mut [u8]
             ) -> std::task::Poll<std::io::Result<usize>> {
                 use std::io::Read;
-                let mut this = self.as_mut();
-                if !this.$registration.was_woken() {
-                    return std::task::Poll::Pending;
-                }
-                match this.$reader.read(buf) {
-                    Ok(n) => std::task::Poll::Ready(Ok(n)),
-                    Err(e) => {
-                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                let this = &mut *self;
+                let res = if !this.$registration.was_woken($crate::reactor::Interest::READ) {
+                    std::task::Poll::Pending
+                } else if let Err(e) = this.$registration.check_valid() {
+                    std::task::Poll::Ready(Err(e))
+                } else {
+                    match this.$reader.read(buf) {
+                        Ok(n) => std::task::Poll::Ready(Ok(n)),
+                        Err(e) => {
+                            if e.kind() == std::io::ErrorKind::WouldBlock {
+                                let waker = cx.waker().clone();
+                                this.$registration.wake_when($crate::reactor::Interest::READ, waker);
+                                std::task::Poll::Pending
+                            } else {
+                                std::task::Poll::Ready(Err(e))
+                            }
+                        }
+                    }
+                };
+                $crate::io::impl_async_read!(@TIMEOUT this, res, cx, $registration, $timeout)
+            }
+
+            // One readv(2). Buffers are filled in order, so a short read
+            // fills the first buffers and leaves the rest untouched.
+            fn poll_read_vectored(
+                mut self: std::pin::Pin<&mut Self>,
+                cx: &mut std::task::Context<'_>,
+                bufs: &mut [std::io::IoSliceMut<'_>]
+            ) -> std::task::Poll<std::io::Result<usize>> {
+                use std::io::Read;
+                let this = &mut *self;
+                let res = if !this.$registration.was_woken($crate::reactor::Interest::READ) {
+                    std::task::Poll::Pending
+                } else if let Err(e) = this.$registration.check_valid() {
+                    std::task::Poll::Ready(Err(e))
+                } else {
+                    match this.$reader.read_vectored(bufs) {
+                        Ok(n) => std::task::Poll::Ready(Ok(n)),
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                             let waker = cx.waker().clone();
-                            this.$registration.wake_when($crate::reactor::Interest::Read, waker);
+                            this.$registration.wake_when($crate::reactor::Interest::READ, waker);
                             std::task::Poll::Pending
-                        } else {
-                            std::task::Poll::Ready(Err(e))
-                        }
+                        },
+                        Err(e) => std::task::Poll::Ready(Err(e)),
                     }
-                }
+                };
+                $crate::io::impl_async_read!(@TIMEOUT this, res, cx, $registration, $timeout)
+            }
+        }
+
+        // Inherent methods, so that AsyncReadExt is not needed.
+        impl $type {
+            /// Read data into `buf`. Returns the number of bytes read,
+            /// 0 means end-of-file.
+            pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                $crate::io::impl_async_read!(@START self, $timeout);
+                std::future::poll_fn(|cx| {
+                    $crate::io::AsyncRead::poll_read(std::pin::Pin::new(&mut *self), cx, buf)
+                }).await
+            }
+
+            /// Read data into `bufs` with one readv(2) call. Returns the
+            /// total number of bytes read, 0 means end-of-file.
+            ///
+            /// The buffers are filled in order: if `n` bytes were read,
+            /// those are the first `n` bytes of `bufs` taken together.
+            pub async fn read_vectored(&mut self, bufs: &mut [std::io::IoSliceMut<'_>]) -> std::io::Result<usize> {
+                $crate::io::impl_async_read!(@START self, $timeout);
+                std::future::poll_fn(|cx| {
+                    $crate::io::AsyncRead::poll_read_vectored(std::pin::Pin::new(&mut *self), cx, bufs)
+                }).await
             }
         }
     }
@@ -51,6 +431,7 @@ pub(crate) use impl_async_read;
 // - $writer: an object that implements std::io::Write, and is set to non-blocking.
 // - $registration: a Registration struct.
 // - optional: $closer - method on $type to shut down the writer.
+// - optional: $timeout - an OpTimeout member.
 //
 macro_rules! impl_async_write {
     // helpers.
@@ -60,14 +441,29 @@ macro_rules! impl_async_write {
     (@CLOSE $self: ident, $closer: ident) => {
         $self.$closer()
     };
+    (@TIMEOUT $this: ident, $res: ident, $cx: ident, $registration: ident, _NONE) => {
+        $res
+    };
+    (@TIMEOUT $this: ident, $res: ident, $cx: ident, $registration: ident, $timeout: ident) => {
+        $this.$timeout.poll($res, $cx, &$this.$registration, $crate::reactor::Interest::WRITE)
+    };
+    (@START $this: ident, _NONE) => {};
+    (@START $this: ident, $timeout: ident) => {
+        $this.$timeout.start();
+    };
 
     // entrypoint without explicit closer.
     ($type: ty, $writer: ident, $registration: ident) => {
-        $crate::io::impl_async_write!($type, $writer, $registration, _NONE);
+        $crate::io::impl_async_write!($type, $writer, $registration, _NONE, _NONE);
     };
 
     // entrypoint with explicit closer.
     ($type: ty, $writer: ident, $registration: ident, $closer: ident) => {
+        $crate::io::impl_async_write!($type, $writer, $registration, $closer, _NONE);
+    };
+
+    // entrypoint with explicit closer and timeout.
+    ($type: ty, $writer: ident, $registration: ident, $closer: ident, $timeout: ident) => {
 
         impl ::futures_io::AsyncWrite for $type {
             fn poll_write(
@@ -76,19 +472,50 @@ macro_rules! impl_async_write {
                 buf: &[u8]
             ) -> std::task::Poll<std::io::Result<usize>> {
                 use std::io::Write;
-                let mut this = self.as_mut();
-                if !this.$registration.was_woken() {
-                    return std::task::Poll::Pending;
-                }
-                match this.$writer.write(buf) {
-                    Ok(n) => std::task::Poll::Ready(Ok(n)),
-                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        let waker = cx.waker().clone();
-                        this.$registration.wake_when($crate::reactor::Interest::Write, waker);
-                        std::task::Poll::Pending
-                    },
-                    Err(e) => std::task::Poll::Ready(Err(e)),
-                }
+                let this = &mut *self;
+                let res = if !this.$registration.was_woken($crate::reactor::Interest::WRITE) {
+                    std::task::Poll::Pending
+                } else if let Err(e) = this.$registration.check_valid() {
+                    std::task::Poll::Ready(Err(e))
+                } else {
+                    match this.$writer.write(buf) {
+                        Ok(n) => std::task::Poll::Ready(Ok(n)),
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                            let waker = cx.waker().clone();
+                            this.$registration.wake_when($crate::reactor::Interest::WRITE, waker);
+                            std::task::Poll::Pending
+                        },
+                        Err(e) => std::task::Poll::Ready(Err(e)),
+                    }
+                };
+                $crate::io::impl_async_write!(@TIMEOUT this, res, cx, $registration, $timeout)
+            }
+
+            // One writev(2). A partial write returns the number of bytes
+            // written; only if nothing could be written we wait.
+            fn poll_write_vectored(
+                mut self: std::pin::Pin<&mut Self>,
+                cx: &mut std::task::Context<'_>,
+                bufs: &[std::io::IoSlice<'_>]
+            ) -> std::task::Poll<std::io::Result<usize>> {
+                use std::io::Write;
+                let this = &mut *self;
+                let res = if !this.$registration.was_woken($crate::reactor::Interest::WRITE) {
+                    std::task::Poll::Pending
+                } else if let Err(e) = this.$registration.check_valid() {
+                    std::task::Poll::Ready(Err(e))
+                } else {
+                    match this.$writer.write_vectored(bufs) {
+                        Ok(n) => std::task::Poll::Ready(Ok(n)),
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                            let waker = cx.waker().clone();
+                            this.$registration.wake_when($crate::reactor::Interest::WRITE, waker);
+                            std::task::Poll::Pending
+                        },
+                        Err(e) => std::task::Poll::Ready(Err(e)),
+                    }
+                };
+                $crate::io::impl_async_write!(@TIMEOUT this, res, cx, $registration, $timeout)
             }
 
             fn poll_flush(
@@ -106,6 +533,56 @@ macro_rules! impl_async_write {
                 std::task::Poll::Ready($crate::io::impl_async_write!(@CLOSE _this, $closer))
             }
         }
+
+        // Inherent methods, so that AsyncWriteExt is not needed.
+        impl $type {
+            /// Write data from `buf`. Returns the number of bytes written.
+            pub async fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                $crate::io::impl_async_write!(@START self, $timeout);
+                std::future::poll_fn(|cx| {
+                    ::futures_io::AsyncWrite::poll_write(std::pin::Pin::new(&mut *self), cx, buf)
+                }).await
+            }
+
+            /// Write all of `buf`.
+            pub async fn write_all(&mut self, mut buf: &[u8]) -> std::io::Result<()> {
+                while !buf.is_empty() {
+                    match self.write(buf).await? {
+                        0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                        n => buf = &buf[n..],
+                    }
+                }
+                Ok(())
+            }
+
+            /// Write data from `bufs` with one writev(2) call. Returns the
+            /// number of bytes written, which can be less than the total.
+            pub async fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+                $crate::io::impl_async_write!(@START self, $timeout);
+                std::future::poll_fn(|cx| {
+                    ::futures_io::AsyncWrite::poll_write_vectored(std::pin::Pin::new(&mut *self), cx, bufs)
+                }).await
+            }
+
+            /// Whether `write_vectored` is efficient, i.e. uses writev(2).
+            /// Always true.
+            pub fn is_write_vectored(&self) -> bool {
+                true
+            }
+
+            /// Write all data in `bufs`. `bufs` is modified to keep track
+            /// of what was written, its contents are unspecified afterwards.
+            pub async fn write_all_vectored(&mut self, mut bufs: &mut [std::io::IoSlice<'_>]) -> std::io::Result<()> {
+                std::io::IoSlice::advance_slices(&mut bufs, 0);
+                while !bufs.is_empty() {
+                    match self.write_vectored(bufs).await? {
+                        0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                        n => std::io::IoSlice::advance_slices(&mut bufs, n),
+                    }
+                }
+                Ok(())
+            }
+        }
     }
 }
 pub(crate) use impl_async_write;
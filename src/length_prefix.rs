@@ -0,0 +1,116 @@
+use std::io;
+use std::pin::Pin;
+
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_util::AsyncReadExt;
+
+/// Read a frame that starts with a 4 byte big-endian length.
+///
+/// Same as `LengthPrefix::U32Be.read(reader, max_len)`.
+pub async fn read_length_prefixed<R>(reader: &mut R, max_len: usize) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    LengthPrefix::U32Be.read(reader, max_len).await
+}
+
+/// Write `data` as a frame that starts with a 4 byte big-endian length.
+///
+/// Same as `LengthPrefix::U32Be.write(writer, data)`.
+pub async fn write_length_prefixed<W>(writer: &mut W, data: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    LengthPrefix::U32Be.write(writer, data).await
+}
+
+/// Size and byte order of the length in front of a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LengthPrefix {
+    /// 2 bytes, big-endian.
+    U16Be,
+    /// 2 bytes, little-endian.
+    U16Le,
+    /// 4 bytes, big-endian. This is the default.
+    #[default]
+    U32Be,
+    /// 4 bytes, little-endian.
+    U32Le,
+    /// 8 bytes, big-endian.
+    U64Be,
+    /// 8 bytes, little-endian.
+    U64Le,
+}
+
+impl LengthPrefix {
+    /// Size of the length prefix in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            LengthPrefix::U16Be | LengthPrefix::U16Le => 2,
+            LengthPrefix::U32Be | LengthPrefix::U32Le => 4,
+            LengthPrefix::U64Be | LengthPrefix::U64Le => 8,
+        }
+    }
+
+    fn is_big_endian(self) -> bool {
+        matches!(self, LengthPrefix::U16Be | LengthPrefix::U32Be | LengthPrefix::U64Be)
+    }
+
+    /// Read a frame: the length, then that many bytes.
+    ///
+    /// If the length is more than `max_len`, this fails with
+    /// `io::ErrorKind::InvalidData` before anything is allocated, so a peer
+    /// can not make us allocate an arbitrary amount of memory. The frame
+    /// has not been read then, so the stream should not be used anymore.
+    /// End-of-file within a frame is an `io::ErrorKind::UnexpectedEof` error.
+    pub async fn read<R>(self, reader: &mut R, max_len: usize) -> io::Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut prefix = [0u8; 8];
+        let prefix = &mut prefix[..self.size()];
+        reader.read_exact(prefix).await?;
+        if !self.is_big_endian() {
+            prefix.reverse();
+        }
+        let len = prefix.iter().fold(0u64, |len, &b| (len << 8) | b as u64);
+        if len > max_len as u64 {
+            let msg = format!("frame length {} exceeds maximum of {}", len, max_len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        let mut data = vec![0u8; len as usize];
+        reader.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Write a frame: the length of `data`, then `data`.
+    ///
+    /// Both are written with writev(2) where possible. Fails with
+    /// `io::ErrorKind::InvalidInput` if the length does not fit in the prefix.
+    pub async fn write<W>(self, writer: &mut W, data: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let len = data.len() as u64;
+        if self.size() < 8 && len >> (self.size() * 8) != 0 {
+            let msg = format!("frame length {} does not fit in {} bytes", len, self.size());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let mut prefix = len.to_be_bytes();
+        let prefix = &mut prefix[8 - self.size()..];
+        if !self.is_big_endian() {
+            prefix.reverse();
+        }
+
+        let mut bufs = [io::IoSlice::new(prefix), io::IoSlice::new(data)];
+        let mut bufs = &mut bufs[..];
+        while !bufs.is_empty() {
+            let n = std::future::poll_fn(|cx| Pin::new(&mut *writer).poll_write_vectored(cx, bufs)).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            io::IoSlice::advance_slices(&mut bufs, n);
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ pub(crate) mod executor;
 pub(crate) mod reactor;
 pub(crate) mod syscall;
 pub(crate) mod threadpool;
+pub(crate) mod waiters;
 
 pub mod io;
 pub mod net;
@@ -11,7 +12,21 @@ pub mod time;
 
 #[path="."]
 pub mod sync {
+    pub mod broadcast;
+    mod cancellation;
     pub mod mpsc;
+    mod mutex;
+    mod notify;
+    mod priority_channel;
+    mod resettable;
+    mod semaphore;
+    pub mod watch;
+    pub use cancellation::CancellationToken;
+    pub use mutex::{Mutex, MutexGuard};
+    pub use notify::Notify;
+    pub use priority_channel::PriorityChannel;
+    pub use resettable::{Resettable, ResettableSender};
+    pub use semaphore::{Semaphore, SemaphorePermit};
 }
 
 #[path="."]
@@ -0,0 +1,112 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_core::Stream;
+use futures_io::AsyncBufRead;
+
+/// Read lines from `reader`, as a stream.
+///
+/// See `Lines`.
+pub fn lines<R: AsyncBufRead + Unpin>(reader: R) -> Lines<R> {
+    Lines { reader, buf: Vec::new(), done: false }
+}
+
+/// A stream of lines, returned by `lines`.
+///
+/// Every item is one line with the trailing `\n` or `\r\n` stripped. A
+/// final line without a trailing newline is returned as well. If a line is
+/// not valid UTF-8, an `io::ErrorKind::InvalidData` error is returned and
+/// the stream ends, without reading further. The rest of the data can then
+/// be read from the reader returned by `into_inner`.
+pub struct Lines<R> {
+    reader: R,
+    // The part of the current line that was read so far.
+    buf:    Vec<u8>,
+    done:   bool,
+}
+
+impl<R> Lines<R> {
+    /// Get a reference to the reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Get a mutable reference to the reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Return the reader. A partially read line is lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> Lines<R> {
+    // Turn the buffered line into a String.
+    fn take_line(&mut self) -> Option<io::Result<String>> {
+        let mut line = std::mem::take(&mut self.buf);
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        match String::from_utf8(line) {
+            Ok(line) => Some(Ok(line)),
+            Err(_) => {
+                self.done = true;
+                Some(Err(io::Error::new(io::ErrorKind::InvalidData, "line is not valid UTF-8")))
+            },
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> Stream for Lines<R> {
+    type Item = io::Result<String>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            let data = match ready!(Pin::new(&mut this.reader).poll_fill_buf(cx)) {
+                Ok(data) => data,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            };
+
+            // End of file.
+            if data.is_empty() {
+                this.done = true;
+                if this.buf.is_empty() {
+                    return Poll::Ready(None);
+                }
+                return Poll::Ready(this.take_line());
+            }
+
+            match data.iter().position(|&b| b == b'\n') {
+                Some(pos) => {
+                    this.buf.extend_from_slice(&data[..=pos]);
+                    Pin::new(&mut this.reader).consume(pos + 1);
+                    return Poll::Ready(this.take_line());
+                },
+                None => {
+                    let len = data.len();
+                    this.buf.extend_from_slice(data);
+                    Pin::new(&mut this.reader).consume(len);
+                },
+            }
+        }
+    }
+}
+
+impl<R: std::fmt::Debug> std::fmt::Debug for Lines<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lines")
+            .field("reader", &self.reader)
+            .field("buffered", &self.buf.len())
+            .finish()
+    }
+}
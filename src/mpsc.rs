@@ -1,14 +1,18 @@
 use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{TryRecvError, TrySendError};
 use std::task::{Context, Poll, Waker};
+use futures_core::Stream;
 
 // Re-exports.
-pub use std::sync::mpsc::{RecvError, SendError};
+pub use std::sync::mpsc::{RecvError, SendError, TryRecvError, TrySendError};
 
 #[derive(Clone)]
 pub struct Sender<T> {
     sender: std::sync::mpsc::SyncSender<T>,
+    capacity: usize,
+    len: Arc<AtomicUsize>,
     tx_waker: Arc<Mutex<Option<Waker>>>,
     rx_waker: Arc<Mutex<Option<Waker>>>,
 }
@@ -17,16 +21,17 @@ impl<T> Sender<T> {
     pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
         let mut store = Some(value);
         std::future::poll_fn(move |cx: &mut Context<'_>| {
+            // Yield every now and then, so we don't starve I/O.
+            if crate::executor::poll_budget(cx).is_pending() {
+                return Poll::Pending;
+            }
             let mut set_waker = false;
             let res = loop {
 
                 // Try to send.
                 let value = store.take().unwrap();
-                match self.sender.try_send(value) {
-                    Ok(()) => {
-                        self.rx_waker.lock().unwrap().take().map(|w| w.wake());
-                        break Ok(());
-                    },
+                match self.try_send(value) {
+                    Ok(()) => break Ok(()),
                     Err(TrySendError::Disconnected(v)) => break Err(SendError(v)),
                     Err(TrySendError::Full(v)) => store.replace(v),
                 };
@@ -55,11 +60,53 @@ impl<T> Sender<T> {
             Poll::Ready(res)
         }).await
     }
+
+    /// Send a message to the channel, without waiting.
+    ///
+    /// Fails if the channel is full, or if the receiver is gone.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        // Count the message before it can be received.
+        self.len.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.sender.try_send(value) {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        if let Some(w) = self.rx_waker.lock().unwrap().take() {
+            w.wake();
+        }
+        Ok(())
+    }
+
+    /// The capacity of the channel.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of messages in the channel.
+    ///
+    /// This is a snapshot: other senders and the receiver may change it
+    /// right after.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Is the channel empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender").field("capacity", &self.capacity).finish()
+    }
 }
 
 impl<T> Drop for Sender<T> {
     fn drop(&mut self) {
-        self.rx_waker.lock().unwrap().take().map(|w| w.wake());
+        if let Some(w) = self.rx_waker.lock().unwrap().take() {
+            w.wake();
+        }
     }
 }
 
@@ -68,94 +115,205 @@ pub struct Receiver<T> {
     tx_waker: Arc<Mutex<Option<Waker>>>,
     rx_waker: Arc<Mutex<Option<Waker>>>,
     buffer: VecDeque<Result<T, TryRecvError>>,
-    bounded: bool,
+    // usize::MAX means unbounded.
+    capacity: usize,
+    len: Arc<AtomicUsize>,
 }
 
 impl<T> Receiver<T> {
     pub async fn recv(&mut self) -> Option<T> {
-        std::future::poll_fn(move |cx: &mut Context<'_>| {
-            let mut set_waker = false;
-            let res = loop {
+        std::future::poll_fn(|cx: &mut Context<'_>| self.poll_recv(cx)).await
+    }
 
-                if !self.bounded {
-                    // If internal buffer is empty, fill it.
-                    if self.buffer.len() == 0 {
-                        let mut err = false;
-                        while !err {
-                            let res = self.receiver.try_recv();
-                            err = res.is_err();
-                            self.buffer.push_back(res);
-                        }
-                    }
-                    // Read next value from internal buffer.
-                    match self.buffer.pop_front().unwrap() {
-                        Ok(val) => break Some(val),
-                        Err(TryRecvError::Disconnected) => break None,
-                        Err(TryRecvError::Empty) => {},
-                    }
-                } else {
-                    match self.receiver.try_recv() {
-                        Ok(val) => {
-                            self.tx_waker.lock().unwrap().take().map(|w| w.wake());
-                            break Some(val);
-                        },
-                        Err(TryRecvError::Disconnected) => break None,
-                        Err(TryRecvError::Empty) => {},
-                    }
-                };
+    /// Poll for a message, see `recv`.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        // Yield every now and then, so we don't starve I/O.
+        if crate::executor::poll_budget(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut set_waker = false;
+        let res = loop {
 
-                // Second time through the loop?
-                if set_waker {
-                    return Poll::Pending;
+            if self.capacity == usize::MAX {
+                // If internal buffer is empty, fill it.
+                if self.buffer.is_empty() {
+                    let mut err = false;
+                    while !err {
+                        let res = self.receiver.try_recv();
+                        err = res.is_err();
+                        self.buffer.push_back(res);
+                    }
                 }
-
-                // Set a waker, then call `try_recv()` once more to prevent
-                // a race condition with the sender.
-                let mut rx_waker = self.rx_waker.lock().unwrap();
-                if let Some(w) = rx_waker.as_mut() {
-                    w.clone_from(cx.waker());
-                } else {
-                    rx_waker.replace(cx.waker().clone());
+                // Read next value from internal buffer.
+                match self.buffer.pop_front().unwrap() {
+                    Ok(val) => break Some(val),
+                    Err(TryRecvError::Disconnected) => break None,
+                    Err(TryRecvError::Empty) => {},
+                }
+            } else {
+                match self.receiver.try_recv() {
+                    Ok(val) => {
+                        if let Some(w) = self.tx_waker.lock().unwrap().take() {
+                            w.wake();
+                        }
+                        break Some(val);
+                    },
+                    Err(TryRecvError::Disconnected) => break None,
+                    Err(TryRecvError::Empty) => {},
                 }
-                set_waker = true;
             };
 
-            // We're ready. If we did set a waker we can remove it now.
+            // Second time through the loop?
             if set_waker {
-                let mut rx_waker = self.rx_waker.lock().unwrap();
-                rx_waker.take();
+                return Poll::Pending;
             }
-            Poll::Ready(res)
-        }).await
+
+            // Set a waker, then call `try_recv()` once more to prevent
+            // a race condition with the sender.
+            let mut rx_waker = self.rx_waker.lock().unwrap();
+            if let Some(w) = rx_waker.as_mut() {
+                w.clone_from(cx.waker());
+            } else {
+                rx_waker.replace(cx.waker().clone());
+            }
+            set_waker = true;
+        };
+
+        // We're ready. If we did set a waker we can remove it now.
+        if set_waker {
+            let mut rx_waker = self.rx_waker.lock().unwrap();
+            rx_waker.take();
+        }
+        if res.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        Poll::Ready(res)
+    }
+
+    /// Receive a message from the channel, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        // Values that `recv` already buffered come first.
+        while let Some(res) = self.buffer.pop_front() {
+            match res {
+                Err(TryRecvError::Empty) => {},
+                res => return self.received(res),
+            }
+        }
+        let res = self.receiver.try_recv();
+        if res.is_ok() && self.capacity != usize::MAX {
+            if let Some(w) = self.tx_waker.lock().unwrap().take() {
+                w.wake();
+            }
+        }
+        self.received(res)
+    }
+
+    // Uncount a message that is handed to the caller.
+    fn received(&self, res: Result<T, TryRecvError>) -> Result<T, TryRecvError> {
+        if res.is_ok() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// The capacity of the channel, `usize::MAX` if it is unbounded.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of messages in the channel.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Is the channel empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take all messages that are currently queued, without waiting.
+    ///
+    /// Useful to clean up or flush pending messages on shutdown.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.try_recv().ok())
+    }
+}
+
+// Nothing in the receiver is ever pinned.
+impl<T> Unpin for Receiver<T> {}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Receiver");
+        if self.capacity != usize::MAX {
+            d.field("capacity", &self.capacity);
+        }
+        d.finish()
     }
 }
 
 impl<T> Drop for Receiver<T> {
     fn drop(&mut self) {
-        self.tx_waker.lock().unwrap().take().map(|w| w.wake());
+        if let Some(w) = self.tx_waker.lock().unwrap().take() {
+            w.wake();
+        }
     }
 }
 
-#[derive(Clone)]
 pub struct UnboundedSender<T> {
     sender: std::sync::mpsc::Sender<T>,
+    len: Arc<AtomicUsize>,
     rx_waker: Arc<Mutex<Option<Waker>>>,
 }
+
+// Not derived, that would require T: Clone.
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        UnboundedSender {
+            sender: self.sender.clone(),
+            len: self.len.clone(),
+            rx_waker: self.rx_waker.clone(),
+        }
+    }
+}
 pub type UnboundedReceiver<T> = Receiver<T>;
 
 impl<T> UnboundedSender<T> {
     pub fn send(&self, value: T) -> Result<(), SendError<T>> {
-        self.sender.send(value)?;
-        self.rx_waker.lock().unwrap().take().map(|w| w.wake());
+        // Count the message before it can be received.
+        self.len.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.sender.send(value) {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        if let Some(w) = self.rx_waker.lock().unwrap().take() {
+            w.wake();
+        }
         Ok(())
     }
 }
 
+impl<T> std::fmt::Debug for UnboundedSender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UnboundedSender").finish()
+    }
+}
+
 impl<T> Drop for UnboundedSender<T> {
     fn drop(&mut self) {
         let mut rx_waker = self.rx_waker.lock().unwrap();
         if Arc::strong_count(&self.rx_waker) == 2 {
-            rx_waker.take().map(|w| w.wake());
+            if let Some(w) = rx_waker.take() {
+                w.wake();
+            }
         }
     }
 }
@@ -166,8 +324,15 @@ pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
     let buffer = VecDeque::new();
     let tx_waker = Arc::new(Mutex::new(None));
     let rx_waker = Arc::new(Mutex::new(None));
-    let tx = Sender { sender, tx_waker: tx_waker.clone(), rx_waker: rx_waker.clone() };
-    let rx = Receiver { receiver, tx_waker, rx_waker, buffer, bounded: true };
+    let len = Arc::new(AtomicUsize::new(0));
+    let tx = Sender {
+        sender,
+        capacity,
+        len: len.clone(),
+        tx_waker: tx_waker.clone(),
+        rx_waker: rx_waker.clone(),
+    };
+    let rx = Receiver { receiver, tx_waker, rx_waker, buffer, capacity, len };
     (tx, rx)
 }
 
@@ -177,7 +342,8 @@ pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
     let buffer = VecDeque::new();
     let tx_waker = Arc::new(Mutex::new(None));
     let rx_waker = Arc::new(Mutex::new(None));
-    let tx = UnboundedSender { sender, rx_waker: rx_waker.clone() };
-    let rx = UnboundedReceiver { receiver, tx_waker, rx_waker, buffer, bounded: false };
+    let len = Arc::new(AtomicUsize::new(0));
+    let tx = UnboundedSender { sender, len: len.clone(), rx_waker: rx_waker.clone() };
+    let rx = UnboundedReceiver { receiver, tx_waker, rx_waker, buffer, capacity: usize::MAX, len };
     (tx, rx)
 }
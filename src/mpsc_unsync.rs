@@ -1,19 +1,22 @@
 use std::cell::RefCell;
+use std::pin::Pin;
 use std::rc::Rc;
-use std::task::{Context, Poll, Waker};
+use std::task::{ready, Context, Poll, Waker};
 use std::collections::VecDeque;
+use futures_core::Stream;
+
+use crate::waiters::{WaitQueue, WaiterGuard};
 
 // Re-exports.
-pub use std::sync::mpsc::{RecvError, SendError};
+pub use std::sync::mpsc::{RecvError, SendError, TryRecvError, TrySendError};
 
 // Shared channel struct.
 struct Channel<T> {
     queue: VecDeque<T>,
     capacity: usize,
-    tx_wakers: VecDeque<(u64, Waker)>,
+    tx_wakers: WaitQueue,
     rx_waker: Option<Waker>,
     recv_gone: bool,
-    last_id: u64,
 }
 
 impl<T> Channel<T> {
@@ -21,18 +24,54 @@ impl<T> Channel<T> {
         Rc::new(RefCell::new(Channel {
             queue: VecDeque::new(),
             capacity,
-            tx_wakers: VecDeque::new(),
+            tx_wakers: WaitQueue::new(),
             rx_waker: None,
             recv_gone: false,
-            last_id: 1,
         }))
     }
+
+    // Wake the waiting senders that can use a free slot.
+    fn wake_senders(&self) {
+        let free = self.capacity - self.queue.len();
+        self.tx_wakers.wake_first(free);
+    }
+
+    // Remove waiting sender `id`, and let the next one have its slot.
+    fn remove_sender(&mut self, id: u64) {
+        if self.tx_wakers.remove(id).is_some() {
+            self.wake_senders();
+        }
+    }
+}
+
+impl<T> Channel<T> {
+    // Debug helper. Does not panic if the channel is already borrowed.
+    fn fmt_debug(
+        this: &RefCell<Channel<T>>,
+        name: &str,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        let mut d = f.debug_struct(name);
+        match this.try_borrow() {
+            Ok(channel) => {
+                if channel.capacity != usize::MAX {
+                    d.field("capacity", &channel.capacity);
+                }
+                d.field("len", &channel.queue.len());
+            },
+            Err(_) => {
+                d.field("channel", &format_args!("<borrowed>"));
+            },
+        }
+        d.finish()
+    }
 }
 
 /// Create a new channel.
 pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
     let channel = Channel::new(capacity);
-    (Sender { id: 1, channel: channel.clone() }, Receiver { channel })
+    let id = channel.borrow_mut().tx_wakers.next_id();
+    (Sender { id, channel: channel.clone() }, Receiver { channel })
 }
 
 /// Create a new unbounded channel
@@ -54,43 +93,224 @@ pub struct Sender<T> {
 
 impl<T> Clone for Sender<T> {
     fn clone(&self) -> Self {
-        let id = {
-            let mut channel = self.channel.borrow_mut();
-            channel.last_id += 1;
-            channel.last_id
-        };
+        let id = self.channel.borrow_mut().tx_wakers.next_id();
         Sender { id, channel: self.channel.clone() }
     }
 }
 
 impl<T> Sender<T> {
     /// Send a value to the receiver.
+    ///
+    /// If the channel is full, senders are served in FIFO order.
     pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
         let mut store = Some(value);
+        // If this future is dropped while waiting, give up our place in the queue.
+        let _guard = WaiterGuard::new(self.id, &self.channel, Channel::remove_sender);
         std::future::poll_fn(|cx: &mut Context<'_>| {
-
-            // See if the receiver is still there.
-            let mut channel = self.channel.borrow_mut();
-            if channel.recv_gone {
-                return Poll::Ready(Err(SendError(store.take().unwrap())));
-            }
-
-            // If under capacity, just push.
-            if channel.queue.len() < channel.capacity {
-                channel.queue.push_back(store.take().unwrap());
-                // Wake receiver.
-                channel.rx_waker.take().map(|w| w.wake());
-                return Poll::Ready(Ok(()));
+            // Yield every now and then, so we don't starve I/O.
+            if crate::executor::poll_budget(cx).is_pending() {
+                return Poll::Pending;
             }
+            self.poll_send(cx, &mut store)
+        }).await
+    }
 
-            // Arrange for us to be woken when the receiver runs.
-            if let Some(w) = channel.tx_wakers.iter_mut().find(|w| w.0 == self.id) {
-                w.1.clone_from(cx.waker());
-            } else {
-                channel.tx_wakers.push_back((self.id, cx.waker().clone()));
-            }
+    /// Wait for room in the channel, without having a value to send yet.
+    ///
+    /// Returns `Ready(Ok(()))` when there is a slot for this sender, and
+    /// then the next `try_send` on this sender is guaranteed to succeed.
+    /// The slot stays reserved until this sender sends, or is dropped.
+    /// If the channel is full, the task is woken up when there is room.
+    /// Fails if the receiver is gone.
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), SendError<()>>> {
+        let mut channel = self.channel.borrow_mut();
+        if channel.recv_gone {
+            return Poll::Ready(Err(SendError(())));
+        }
+        // Queue up as a waiting sender, also if there is room right now:
+        // our place in the queue is what reserves the slot.
+        let free = channel.capacity - channel.queue.len();
+        if channel.tx_wakers.register(self.id, cx.waker(), ()) < free {
+            Poll::Ready(Ok(()))
+        } else {
             Poll::Pending
-        }).await
+        }
+    }
+
+    /// Send a value to the receiver, without waiting.
+    ///
+    /// Fails if the channel is full, or if the receiver is gone. After
+    /// `poll_ready` returned `Ready(Ok(()))` this always succeeds.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        // See if the receiver is still there.
+        let mut channel = self.channel.borrow_mut();
+        if channel.recv_gone {
+            return Err(TrySendError::Disconnected(value));
+        }
+
+        // If under capacity, push, unless senders that were waiting
+        // before us need the free slots.
+        let free = channel.capacity - channel.queue.len();
+        let pos = channel.tx_wakers.position(self.id);
+        if pos.unwrap_or(channel.tx_wakers.len()) >= free {
+            return Err(TrySendError::Full(value));
+        }
+        if pos.is_some() {
+            channel.tx_wakers.remove(self.id);
+        }
+        channel.queue.push_back(value);
+        // Wake receiver.
+        if let Some(w) = channel.rx_waker.take() {
+            w.wake();
+        }
+        Ok(())
+    }
+
+    /// The capacity of the channel.
+    pub fn capacity(&self) -> usize {
+        self.channel.borrow().capacity
+    }
+
+    /// Number of messages in the channel.
+    pub fn len(&self) -> usize {
+        self.channel.borrow().queue.len()
+    }
+
+    /// Is the channel empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Try to send the value in `store`. If the channel is full, queue up
+    // as a waiting sender and return Pending.
+    fn poll_send(&self, cx: &mut Context<'_>, store: &mut Option<T>) -> Poll<Result<(), SendError<T>>> {
+        match self.try_send(store.take().unwrap()) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Disconnected(value)) => Poll::Ready(Err(SendError(value))),
+            Err(TrySendError::Full(value)) => {
+                // Arrange for us to be woken when the receiver runs.
+                *store = Some(value);
+                self.channel.borrow_mut().tx_wakers.register(self.id, cx.waker(), ());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/// `poll_ready` reserves a slot, `start_send` fills it. Messages are
+/// delivered as soon as they are sent, so `poll_flush` has nothing to do.
+/// `poll_close` gives up a reserved slot; the receiver sees the end of
+/// the stream when the last sender is dropped (`StreamExt::forward` drops
+/// the sink after closing it).
+impl<T> futures_sink::Sink<T> for Sender<T> {
+    type Error = SendError<()>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sender::poll_ready(&self, cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        match self.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => Err(SendError(())),
+            Err(TrySendError::Full(_)) => panic!("Sink::start_send called without poll_ready"),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.channel.borrow_mut().remove_sender(self.id);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Sender<Vec<u8>> {
+    /// Turn this sender into an `AsyncWrite`. Every write is sent as one chunk.
+    pub fn into_writer(self) -> ChannelWriter {
+        ChannelWriter { tx: Some(self), pending: None }
+    }
+}
+
+/// An `AsyncWrite` that sends the written data over a channel,
+/// returned by `Sender::into_writer`.
+///
+/// A write is accepted right away, but it can still be waiting for room
+/// in the channel. `flush` waits until it has been queued in the channel.
+/// `close` does the same and then drops the sender, so the receiver sees
+/// the end of the stream after it has received all the data.
+///
+/// If the receiver is gone, writes fail with `io::ErrorKind::BrokenPipe`.
+pub struct ChannelWriter {
+    tx: Option<Sender<Vec<u8>>>,
+    // Chunk that did not fit in the channel yet.
+    pending: Option<Vec<u8>>,
+}
+
+impl ChannelWriter {
+    // Send the pending chunk, if any.
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let Some(tx) = self.tx.as_ref() else {
+            return Poll::Ready(Err(std::io::ErrorKind::NotConnected.into()));
+        };
+        if self.pending.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        match ready!(tx.poll_send(cx, &mut self.pending)) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(_) => Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+impl futures_io::AsyncWrite for ChannelWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        this.pending = Some(buf.to_vec());
+        // Try to send it right away. If the channel is full, we're queued
+        // as a waiting sender, and the next write or flush continues.
+        if let Poll::Ready(Err(e)) = this.poll_send_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.tx.is_some() {
+            ready!(this.poll_send_pending(cx))?;
+            this.tx = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl std::fmt::Debug for ChannelWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelWriter")
+            .field("closed", &self.tx.is_none())
+            .field("pending", &self.pending.as_ref().map(|p| p.len()))
+            .finish()
+    }
+}
+
+impl<T> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Channel::fmt_debug(&self.channel, "Sender", f)
     }
 }
 
@@ -98,10 +318,12 @@ impl<T> Drop for Sender<T> {
     fn drop(&mut self) {
         let mut channel = self.channel.borrow_mut();
         // Remove any wakers.
-        channel.tx_wakers.retain(|w| w.0 != self.id);
+        channel.remove_sender(self.id);
         if Rc::strong_count(&self.channel) == 2 {
             // Last sender, notify receiver.
-            channel.rx_waker.take().map(|w| w.wake());
+            if let Some(w) = channel.rx_waker.take() {
+                w.wake();
+            }
         }
     }
 }
@@ -122,17 +344,27 @@ impl<T> UnboundedSender<T> {
         }
         // Push and wake receiver.
         channel.queue.push_back(value);
-        channel.rx_waker.take().map(|w| w.wake());
+        if let Some(w) = channel.rx_waker.take() {
+            w.wake();
+        }
         Ok(())
     }
 }
 
+impl<T> std::fmt::Debug for UnboundedSender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Channel::fmt_debug(&self.channel, "UnboundedSender", f)
+    }
+}
+
 impl<T> Drop for UnboundedSender<T> {
     fn drop(&mut self) {
         let mut channel = self.channel.borrow_mut();
         if Rc::strong_count(&self.channel) == 2 {
             // Last sender, notify receiver.
-            channel.rx_waker.take().map(|w| w.wake());
+            if let Some(w) = channel.rx_waker.take() {
+                w.wake();
+            }
         }
     }
 }
@@ -146,30 +378,88 @@ pub type UnboundedReceiver<T> = Receiver<T>;
 impl<T> Receiver<T> {
     /// Receive a message from the channel.
     pub async fn recv(&mut self) -> Option<T> {
-        std::future::poll_fn(move |cx: &mut Context<'_>| {
-            let mut channel = self.channel.borrow_mut();
+        std::future::poll_fn(|cx: &mut Context<'_>| self.poll_recv(cx)).await
+    }
 
-            // See if there is data.
-            if let Some(value) = channel.queue.pop_front() {
-                if channel.capacity != usize::MAX {
-                    channel.tx_wakers.pop_front().map(|w| w.1.wake());
-                }
-                return Poll::Ready(Some(value));
-            }
+    /// Poll for a message, see `recv`.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        // Yield every now and then, so we don't starve I/O.
+        if crate::executor::poll_budget(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut channel = self.channel.borrow_mut();
 
-            // See if there are any senders left.
-            if Rc::strong_count(&self.channel) == 1 {
-                return Poll::Ready(None);
+        // See if there is data.
+        if let Some(value) = channel.queue.pop_front() {
+            if channel.capacity != usize::MAX {
+                channel.wake_senders();
             }
+            return Poll::Ready(Some(value));
+        }
+
+        // See if there are any senders left.
+        if Rc::strong_count(&self.channel) == 1 {
+            return Poll::Ready(None);
+        }
 
-            // Set a waker.
-            if let Some(w) = channel.rx_waker.as_mut() {
-                w.clone_from(cx.waker());
-            } else {
-                channel.rx_waker.replace(cx.waker().clone());
+        // Set a waker.
+        if let Some(w) = channel.rx_waker.as_mut() {
+            w.clone_from(cx.waker());
+        } else {
+            channel.rx_waker.replace(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// Receive a message from the channel, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut channel = self.channel.borrow_mut();
+        if let Some(value) = channel.queue.pop_front() {
+            if channel.capacity != usize::MAX {
+                channel.wake_senders();
             }
-            Poll::Pending
-        }).await
+            return Ok(value);
+        }
+        if Rc::strong_count(&self.channel) == 1 {
+            return Err(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    /// The capacity of the channel, `usize::MAX` if it is unbounded.
+    pub fn capacity(&self) -> usize {
+        self.channel.borrow().capacity
+    }
+
+    /// Number of messages in the channel.
+    pub fn len(&self) -> usize {
+        self.channel.borrow().queue.len()
+    }
+
+    /// Is the channel empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take all messages that are currently queued, without waiting.
+    ///
+    /// Useful to clean up or flush pending messages on shutdown.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.try_recv().ok())
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Channel::fmt_debug(&self.channel, "Receiver", f)
     }
 }
 
@@ -178,6 +468,6 @@ impl<T> Drop for Receiver<T> {
         // Notify all senders that we're gone.
         let mut channel = self.channel.borrow_mut();
         channel.recv_gone = true;
-        channel.tx_wakers.drain(..).for_each(|w| w.1.wake());
+        channel.tx_wakers.wake_all();
     }
 }
@@ -0,0 +1,86 @@
+use std::cell::{RefCell, RefMut};
+use std::ops::{Deref, DerefMut};
+
+use super::semaphore::{Semaphore, SemaphorePermit};
+
+/// An async mutex, for state that is shared between tasks on a runtime.
+///
+/// Unlike a `RefCell`, the lock can be held across an `.await`. When the
+/// mutex is contended, `lock` waits; waiters get the lock in FIFO order.
+pub struct Mutex<T> {
+    semaphore: Semaphore,
+    value: RefCell<T>,
+}
+
+/// Guard returned by `Mutex::lock`. The mutex is unlocked when it is dropped.
+pub struct MutexGuard<'a, T> {
+    // Declared before the permit, so the value is released first.
+    value: RefMut<'a, T>,
+    _permit: SemaphorePermit,
+}
+
+impl<T> Mutex<T> {
+    /// Create a new, unlocked mutex.
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex { semaphore: Semaphore::new(1), value: RefCell::new(value) }
+    }
+
+    /// Lock the mutex, waiting until it is available.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        MutexGuard { value: self.value.borrow_mut(), _permit: permit }
+    }
+
+    /// Lock the mutex if it is not locked and nobody is waiting for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire()?;
+        Some(MutexGuard { value: self.value.borrow_mut(), _permit: permit })
+    }
+
+    /// Get a mutable reference to the value, without locking.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Consume the mutex and return the value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        match self.value.try_borrow() {
+            Ok(value) => d.field("value", &*value),
+            Err(_) => d.field("value", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&*self.value, f)
+    }
+}
@@ -1,10 +1,25 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime};
 
-use socket2::{Socket, Domain, Type};
+use socket2::{Socket, SockRef, Domain, Type};
+use crate::io::{Interest, OpTimeout, Readiness};
 use crate::reactor::Registration;
+use crate::syscall;
+
+pub use unix::{UnixListener, UnixStream};
+
+#[path = "unix.rs"]
+mod unix;
 
 //
 // First, an async ToSocketAddrs trait, plus a bunch of implementations.
@@ -14,17 +29,197 @@ pub trait ToSocketAddrs {
     async fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>>;
 }
 
-// Helper for DNS lookups.
-async fn to_socket_addrs<A>(addr: A) -> io::Result<Vec<SocketAddr>>
-where
-    A: std::net::ToSocketAddrs + Send + 'static ,
-{
+/// A custom DNS resolver.
+///
+/// See `set_resolver`.
+pub trait Resolver: 'static {
+    /// Resolve `host` to a list of socket addresses with port `port`.
+    #[allow(async_fn_in_trait)]
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+// Object safe version of Resolver, so that we can store it.
+trait DynResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + 'a>>;
+}
+
+impl<R: Resolver> DynResolver for R {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + 'a>> {
+        Box::pin(Resolver::resolve(self, host, port))
+    }
+}
+
+thread_local! {
+    static RESOLVER: RefCell<Option<Rc<dyn DynResolver>>> = const { RefCell::new(None) };
+    static ADDRESS_SORT: std::cell::Cell<AddressFamilyPreference> = const {
+        std::cell::Cell::new(AddressFamilyPreference::System)
+    };
+}
+
+/// How to order the addresses a hostname resolves to. See `set_address_sort`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamilyPreference {
+    /// Keep the order of the resolver (`getaddrinfo` follows RFC 6724 / gai.conf).
+    #[default]
+    System,
+    /// IPv6 addresses first, then IPv4.
+    Ipv6First,
+    /// IPv4 addresses first, then IPv6.
+    Ipv4First,
+    /// Alternate between the families, starting with the family of the
+    /// first address. `connect` then tries both families early.
+    Interleaved,
+}
+
+impl AddressFamilyPreference {
+    fn sort(self, addrs: &mut Vec<SocketAddr>) {
+        match self {
+            AddressFamilyPreference::System => {},
+            AddressFamilyPreference::Ipv6First => addrs.sort_by_key(|a| a.is_ipv4()),
+            AddressFamilyPreference::Ipv4First => addrs.sort_by_key(|a| a.is_ipv6()),
+            AddressFamilyPreference::Interleaved => {
+                let Some(first) = addrs.first() else { return };
+                let first_v6 = first.is_ipv6();
+                let (same, other): (Vec<_>, Vec<_>) = addrs.drain(..).partition(|a| a.is_ipv6() == first_v6);
+                let mut other = other.into_iter();
+                for a in same {
+                    addrs.push(a);
+                    addrs.extend(other.next());
+                }
+                addrs.extend(other);
+            },
+        }
+    }
+}
+
+/// Set how the addresses that a hostname resolves to are ordered, on
+/// this thread. `connect` tries them in that order.
+///
+/// If IPv6 is broken on a network, every connect first has to wait for
+/// the IPv6 attempts to time out. `Interleaved` or `Ipv4First` avoid
+/// that. The default is `System`. Literal IP addresses are not affected.
+pub fn set_address_sort(preference: AddressFamilyPreference) {
+    ADDRESS_SORT.with(|s| s.set(preference));
+}
+
+/// Set the DNS resolver used by `ToSocketAddrs` on this thread.
+///
+/// By default hostnames are resolved by calling `getaddrinfo` on
+/// the blocking threadpool.
+pub fn set_resolver(resolver: impl Resolver) {
+    RESOLVER.with_borrow_mut(|r| *r = Some(Rc::new(resolver)));
+}
+
+/// A DNS resolver that caches lookups for a fixed time.
+///
+/// Lookups are done by calling `getaddrinfo` on the blocking threadpool,
+/// like the default resolver, but a hostname is only looked up again
+/// when its cached result is older than the TTL. Failed lookups are not
+/// cached.
+///
+/// The cache holds at most `DEFAULT_CAPACITY` entries, or the number
+/// given to `with_capacity`. When it is full, the oldest entry is evicted.
+///
+/// Clones share the cache. Install it with `set_resolver` so that every
+/// `connect` on this thread uses it.
+#[derive(Clone)]
+pub struct CachedResolver {
+    inner: Rc<CachedResolverInner>,
+}
+
+// Host and port to expiry time and addresses.
+type ResolverCache = HashMap<(String, u16), (Instant, Vec<SocketAddr>)>;
+
+struct CachedResolverInner {
+    ttl:        Duration,
+    capacity:   usize,
+    cache:      RefCell<ResolverCache>,
+}
+
+impl CachedResolver {
+    /// The number of entries `new` keeps at most.
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    /// New CachedResolver that keeps results for `ttl`.
+    pub fn new(ttl: Duration) -> CachedResolver {
+        CachedResolver::with_capacity(ttl, Self::DEFAULT_CAPACITY)
+    }
+
+    /// New CachedResolver that keeps results for `ttl`, and at most
+    /// `capacity` of them. A capacity of zero caches nothing.
+    pub fn with_capacity(ttl: Duration, capacity: usize) -> CachedResolver {
+        CachedResolver {
+            inner: Rc::new(CachedResolverInner {
+                ttl,
+                capacity,
+                cache: RefCell::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Forget all cached results.
+    pub fn clear(&self) {
+        self.inner.cache.borrow_mut().clear();
+    }
+}
+
+impl Resolver for CachedResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let key = (host.to_string(), port);
+        if let Some((expires, addrs)) = self.inner.cache.borrow().get(&key) {
+            if *expires > Instant::now() {
+                return Ok(addrs.clone());
+            }
+        }
+        let addrs = getaddrinfo(host, port).await?;
+        let now = Instant::now();
+        let mut cache = self.inner.cache.borrow_mut();
+        cache.retain(|_, (expires, _)| *expires > now);
+        if self.inner.capacity == 0 {
+            return Ok(addrs);
+        }
+        // Every entry has the same TTL, so the first to expire is the oldest.
+        if cache.len() >= self.inner.capacity && !cache.contains_key(&key) {
+            let oldest = cache.iter().min_by_key(|(_, (expires, _))| *expires).map(|(k, _)| k.clone());
+            cache.remove(&oldest.unwrap());
+        }
+        cache.insert(key, (now + self.inner.ttl, addrs.clone()));
+        Ok(addrs)
+    }
+}
+
+impl std::fmt::Debug for CachedResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("CachedResolver");
+        d.field("ttl", &self.inner.ttl);
+        d.field("capacity", &self.inner.capacity);
+        if let Ok(cache) = self.inner.cache.try_borrow() {
+            d.field("entries", &cache.len());
+        }
+        d.finish()
+    }
+}
+
+// The default resolver: getaddrinfo on the blocking threadpool.
+async fn getaddrinfo(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let addr = (host.to_string(), port);
     crate::task::spawn_blocking(move || {
         let a = std::net::ToSocketAddrs::to_socket_addrs(&addr)?.collect::<Vec<_>>();
         Ok::<_, io::Error>(a)
     }).await.unwrap()
 }
 
+// Helper for DNS lookups.
+async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let resolver = RESOLVER.with_borrow(|r| r.clone());
+    let mut addrs = match resolver {
+        Some(resolver) => resolver.resolve(host, port).await?,
+        None => getaddrinfo(host, port).await?,
+    };
+    ADDRESS_SORT.with(|s| s.get()).sort(&mut addrs);
+    Ok(addrs)
+}
+
 impl ToSocketAddrs for str {
     async fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
         // First try to parse as literal IP address and port.
@@ -32,7 +227,10 @@ impl ToSocketAddrs for str {
             return Ok(vec![ addr ]);
         }
         // Contains a hostname, so do DNS lookup.
-        to_socket_addrs(self.to_string()).await
+        let (host, port) = self.rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid socket address"))?;
+        resolve(host, port).await
     }
 }
 
@@ -41,12 +239,37 @@ impl ToSocketAddrs for (&str, u16) {
         if let Ok(ip) = std::net::IpAddr::from_str(self.0) {
             return Ok(vec![SocketAddr::new(ip, self.1)]);
         }
-        to_socket_addrs((self.0.to_string(), self.1)).await
+        resolve(self.0, self.1).await
+    }
+}
+
+// implementations for owned and borrowed strings, delegate to str.
+impl ToSocketAddrs for &str {
+    async fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        (**self).to_socket_addrs().await
+    }
+}
+
+impl ToSocketAddrs for String {
+    async fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.as_str().to_socket_addrs().await
+    }
+}
+
+impl ToSocketAddrs for &String {
+    async fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.as_str().to_socket_addrs().await
+    }
+}
+
+impl ToSocketAddrs for (String, u16) {
+    async fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        (self.0.as_str(), self.1).to_socket_addrs().await
     }
 }
 
 // implementation for slices.
-impl<'a> ToSocketAddrs for &'a [std::net::SocketAddr] {
+impl ToSocketAddrs for &[std::net::SocketAddr] {
     async fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
         Ok(self.to_vec())
     }
@@ -69,7 +292,63 @@ to_socket_addrs_impl!(std::net::SocketAddr);
 to_socket_addrs_impl!(std::net::SocketAddrV4);
 to_socket_addrs_impl!(std::net::SocketAddrV6);
 
+/// TCP keepalive parameters, see `TcpSocket::set_tcp_keepalive`.
+///
+/// Parameters that are not set keep the system default. Durations are
+/// rounded down to whole seconds, with a minimum of one second.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    idle:       Option<Duration>,
+    interval:   Option<Duration>,
+    retries:    Option<u32>,
+}
+
+impl TcpKeepalive {
+    /// New TcpKeepalive, with all parameters at the system default.
+    pub fn new() -> TcpKeepalive {
+        TcpKeepalive::default()
+    }
+
+    /// How long the connection must be idle before the first probe is
+    /// sent (TCP_KEEPIDLE).
+    pub fn with_idle(mut self, idle: Duration) -> TcpKeepalive {
+        self.idle = Some(idle);
+        self
+    }
+
+    /// Time between probes (TCP_KEEPINTVL).
+    pub fn with_interval(mut self, interval: Duration) -> TcpKeepalive {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Number of unanswered probes before the connection is dropped
+    /// (TCP_KEEPCNT).
+    pub fn with_retries(mut self, retries: u32) -> TcpKeepalive {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Get the idle time.
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle
+    }
+
+    /// Get the probe interval.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Get the number of probes.
+    pub fn retries(&self) -> Option<u32> {
+        self.retries
+    }
+}
+
 /// An unconnected TCP socket.
+///
+/// Socket options that affect binding, such as SO_REUSEADDR and
+/// SO_REUSEPORT, have no effect after `bind`, so set them first.
 pub struct TcpSocket {
     sock:   Socket,
     regfd:  Registration,
@@ -79,7 +358,7 @@ impl TcpSocket {
     fn new(dom: Domain) -> io::Result<TcpSocket> {
         let sock = Socket::new(dom, Type::STREAM, None)?;
         let _ = sock.set_nonblocking(true);
-        Ok(TcpSocket { regfd: Registration::new(sock.as_raw_fd()), sock })
+        Ok(TcpSocket { regfd: Registration::new(sock.as_raw_fd())?, sock })
     }
 
     /// New IPv4 TcpSocket.
@@ -92,60 +371,1306 @@ impl TcpSocket {
         Self::new(Domain::IPV6)
     }
 
+    /// Set SO_REUSEADDR. Must be called before `bind`.
+    pub fn set_reuseaddr(&self, reuseaddr: bool) -> io::Result<()> {
+        self.sock.set_reuse_address(reuseaddr)
+    }
+
+    /// Get the value of SO_REUSEADDR.
+    pub fn reuseaddr(&self) -> io::Result<bool> {
+        self.sock.reuse_address()
+    }
+
+    /// Set SO_REUSEPORT. Must be called before `bind`.
+    pub fn set_reuseport(&self, reuseport: bool) -> io::Result<()> {
+        set_reuseport(&self.sock, reuseport)
+    }
+
+    /// Get the value of SO_REUSEPORT.
+    pub fn reuseport(&self) -> io::Result<bool> {
+        syscall::getsockopt_int(self.sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_REUSEPORT).map(|v| v != 0)
+    }
+
+    /// Bind the socket to a network interface (SO_BINDTODEVICE), or remove
+    /// the binding with `None`. Linux only.
+    ///
+    /// This needs the CAP_NET_RAW capability, without it this fails with
+    /// `io::ErrorKind::PermissionDenied`.
+    #[cfg(target_os = "linux")]
+    pub fn bind_device(&self, interface: Option<&str>) -> io::Result<()> {
+        bind_device(self.sock.as_raw_fd(), interface)
+    }
+
+    /// Bind the socket to a local address.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<()> {
+        self.sock.bind(&addr.into())
+    }
+
+    /// Turn the socket into a listener, with a queue of at most `backlog`
+    /// pending connections. Call `bind` first.
+    pub fn listen(self, backlog: i32) -> io::Result<TcpListener> {
+        self.sock.listen(backlog)?;
+        Ok(TcpListener { lstn: self.sock.into(), regfd: self.regfd, max_accepts_per_sec: None })
+    }
+
+    /// Set the size of the receive buffer (SO_RCVBUF).
+    ///
+    /// The kernel may double or cap the requested value. To be effective
+    /// for the TCP window, set this before connecting.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.sock.set_recv_buffer_size(size)
+    }
+
+    /// Get the size of the receive buffer (SO_RCVBUF).
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        self.sock.recv_buffer_size()
+    }
+
+    /// Set the size of the send buffer (SO_SNDBUF).
+    ///
+    /// The kernel may double or cap the requested value. Setting it
+    /// disables send buffer autotuning on some systems.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.sock.set_send_buffer_size(size)
+    }
+
+    /// Get the size of the send buffer (SO_SNDBUF).
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        self.sock.send_buffer_size()
+    }
+
+    /// Set TCP_NODELAY, which disables Nagle's algorithm.
+    ///
+    /// The option is kept on the `TcpStream` that `connect` returns.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.sock.set_nodelay(nodelay)
+    }
+
+    /// Get the value of TCP_NODELAY.
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.sock.nodelay()
+    }
+
+    /// Enable SO_KEEPALIVE with an idle time of `idle` before the first
+    /// probe, or disable it with `None`.
+    ///
+    /// The option is kept on the `TcpStream` that `connect` returns.
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        match idle {
+            Some(idle) => self.set_tcp_keepalive(&TcpKeepalive::new().with_idle(idle)),
+            None => self.sock.set_keepalive(false),
+        }
+    }
+
+    /// Get the keepalive idle time, or `None` if SO_KEEPALIVE is off.
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        Ok(self.tcp_keepalive()?.and_then(|ka| ka.idle))
+    }
+
+    /// Enable SO_KEEPALIVE with the parameters in `keepalive`.
+    ///
+    /// The options are kept on the `TcpStream` that `connect` returns.
+    pub fn set_tcp_keepalive(&self, keepalive: &TcpKeepalive) -> io::Result<()> {
+        set_tcp_keepalive(&self.sock, keepalive)
+    }
+
+    /// Get the keepalive parameters, or `None` if SO_KEEPALIVE is off.
+    pub fn tcp_keepalive(&self) -> io::Result<Option<TcpKeepalive>> {
+        tcp_keepalive(&self.sock)
+    }
+
     /// Connect to a remote host.
-    async fn connect(self, addr: SocketAddr) -> io::Result<TcpStream> {
+    ///
+    /// If the future is dropped before the connection is established, the
+    /// socket is closed and deregistered from the reactor.
+    pub async fn connect(self, addr: SocketAddr) -> io::Result<TcpStream> {
         let addr = addr.into();
         loop {
             match self.sock.connect(&addr) {
-                Ok(()) => return Ok(TcpStream { strm: self.sock.into(), regfd: self.regfd }),
+                Ok(()) => return Ok(TcpStream::new(self.sock.into(), self.regfd)),
                 Err(e) => {
                     if e.raw_os_error() != Some(libc::EINPROGRESS) &&
                        e.raw_os_error() != Some(libc::EALREADY) {
                         return Err(e);
                     }
-                    self.regfd.write_ready().await;
+                    self.regfd.write_ready().await?;
                 },
             }
         }
     }
+
+    /// Connect to a remote host, sending `data` with the SYN if possible
+    /// (TCP Fast Open).
+    ///
+    /// Returns the stream, and whether Fast Open was used, that is, if
+    /// (part of) `data` went out with the SYN. If the kernel has no Fast
+    /// Open cookie for the server yet, or Fast Open is not available,
+    /// this falls back to connecting first and writing `data` after.
+    /// Either way, all of `data` has been written when this returns.
+    ///
+    /// On Linux, Fast Open for clients is enabled by bit 0 of the
+    /// `net.ipv4.tcp_fastopen` sysctl, which is set by default. The
+    /// server has to enable it as well, see `TcpListener::set_fastopen`.
+    /// On other platforms this always falls back.
+    pub async fn connect_with_data(self, addr: SocketAddr, data: &[u8]) -> io::Result<(TcpStream, bool)> {
+        // On success the connection is in progress. If Fast Open is not
+        // supported or there is no cookie yet, `connect` starts or continues
+        // it. Any other error is a real one.
+        let sent = match send_fastopen(&self.sock, addr, data) {
+            Ok(sent) => sent,
+            Err(e) if fastopen_unavailable(&e) => 0,
+            Err(e) => return Err(e),
+        };
+        let mut strm = self.connect(addr).await?;
+        strm.write_all(&data[sent..]).await?;
+        Ok((strm, sent > 0))
+    }
+}
+
+impl std::fmt::Debug for TcpSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("TcpSocket");
+        if let Some(addr) = self.sock.local_addr().ok().and_then(|a| a.as_socket()) {
+            d.field("addr", &addr);
+        }
+        d.field("fd", &self.sock.as_raw_fd()).finish()
+    }
+}
+
+/// A TCP socket server, listening for connections.
+pub struct TcpListener {
+    lstn:   std::net::TcpListener,
+    regfd:  Registration,
+    // Accept rate limit for `serve`, in connections per second.
+    max_accepts_per_sec:    Option<u32>,
+}
+
+impl TcpListener {
+    /// Create a new TcpListener bound to the given address.
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        Self::bind_with(addr, false).await
+    }
+
+    /// Like `bind`, but sets SO_REUSEPORT on the socket before binding.
+    ///
+    /// This allows multiple listeners to bind to the same address and port.
+    /// On Linux, the kernel then load-balances incoming connections over
+    /// them. That makes it possible to scale a server over all cores by
+    /// running one single-threaded nara runtime per core, each on its own
+    /// thread with its own listener. Each thread creates a `Runtime`, calls
+    /// `bind_reuseport` on the same address from within `block_on`, and
+    /// runs its own accept loop, spawning a task per connection.
+    ///
+    /// Every listener on the port must set SO_REUSEPORT, otherwise `bind`
+    /// fails with `io::ErrorKind::AddrInUse`.
+    pub async fn bind_reuseport<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        Self::bind_with(addr, true).await
+    }
+
+    /// Listen on `port` on all IPv6 and IPv4 addresses.
+    ///
+    /// This binds one IPv6 socket with IPV6_V6ONLY cleared, which accepts
+    /// both families (IPv4 peers show up as IPv4-mapped IPv6 addresses).
+    /// If IPV6_V6ONLY can not be cleared, it transparently falls back to
+    /// two sockets, one for each family. If there is no IPv6 at all
+    /// (EAFNOSUPPORT or EADDRNOTAVAIL), only IPv4 is used. Other errors,
+    /// such as the port being in use, are returned. With port 0, both
+    /// sockets get the same port.
+    pub fn bind_dual_stack(port: u16) -> io::Result<DualStackListener> {
+        let any6 = SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+        let any4 = SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, port));
+        let ipv4_only = || Ok(DualStackListener::new(vec![Self::listen(any4, false, None)?]));
+
+        // One socket for both families.
+        match Self::listen(any6, false, Some(false)) {
+            Ok(lstn) => return Ok(DualStackListener::new(vec![lstn])),
+            Err(e) if no_ipv6(&e) => return ipv4_only(),
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {},
+            Err(e) => return Err(e),
+        }
+
+        // Two sockets. The IPv4 one uses the port the IPv6 one got.
+        let lstn6 = match Self::listen(any6, false, Some(true)) {
+            Ok(lstn) => lstn,
+            Err(e) if no_ipv6(&e) => return ipv4_only(),
+            Err(e) => return Err(e),
+        };
+        let any4 = SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, lstn6.local_addr()?.port()));
+        let lstn4 = Self::listen(any4, false, None)?;
+        Ok(DualStackListener::new(vec![lstn6, lstn4]))
+    }
+
+    // Create a socket, bind it to `addr` and listen on it.
+    fn listen(addr: SocketAddr, reuseport: bool, only_v6: Option<bool>) -> io::Result<TcpListener> {
+        let sock = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        sock.set_reuse_address(true)?;
+        if reuseport {
+            set_reuseport(&sock, true)?;
+        }
+        if let Some(only_v6) = only_v6 {
+            sock.set_only_v6(only_v6)?;
+            if sock.only_v6()? != only_v6 {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "cannot change IPV6_V6ONLY"));
+            }
+        }
+        sock.bind(&addr.into())?;
+        sock.listen(1024)?;
+        TcpListener::from_std(sock.into())
+    }
+
+    async fn bind_with<A: ToSocketAddrs>(addr: A, reuseport: bool) -> io::Result<TcpListener> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs().await? {
+            let res = Self::listen(addr, reuseport, None);
+            match res {
+                Ok(lstn) => return Ok(lstn),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+        }))
+    }
+
+    /// Construct a nara::TcpListener from a std::net::TcpListener.
+    ///
+    /// Fails if not called from within a runtime context.
+    pub fn from_std(lstn: std::net::TcpListener) -> io::Result<TcpListener> {
+        lstn.set_nonblocking(true)?;
+        let fd = lstn.as_raw_fd();
+        Ok(TcpListener { lstn, regfd: Registration::new(fd)?, max_accepts_per_sec: None })
+    }
+
+    /// Limit the rate at which `serve` and `serve_with_shutdown` accept
+    /// connections to `n` per second, with bursts of at most `n`.
+    ///
+    /// Connections over the limit are not accepted right away: they queue
+    /// up in the kernel's backlog, where they may time out. This bounds
+    /// the rate at which new connections are admitted, while the `limit`
+    /// argument of `serve` bounds the number of handlers running at the
+    /// same time. `accept` itself is not limited. A rate of 0 is taken as 1.
+    pub fn max_accepts_per_sec(mut self, n: u32) -> TcpListener {
+        self.max_accepts_per_sec = Some(n);
+        self
+    }
+
+    /// Enable TCP Fast Open (TCP_FASTOPEN) on the listener, allowing at
+    /// most `queue_len` pending Fast Open connections. Linux only.
+    ///
+    /// The kernel only accepts Fast Open connections if bit 1 of the
+    /// `net.ipv4.tcp_fastopen` sysctl is set as well (value 2 or 3);
+    /// the default only enables the client side.
+    #[cfg(target_os = "linux")]
+    pub fn set_fastopen(&self, queue_len: u32) -> io::Result<()> {
+        let qlen = queue_len.min(libc::c_int::MAX as u32) as libc::c_int;
+        syscall::setsockopt_int(self.lstn.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN, qlen)
+    }
+
+    /// Accept a new incoming connection.
+    ///
+    /// This is cancel-safe: if the future is dropped while waiting, no
+    /// connection is lost (it stays queued in the kernel) and the waker is
+    /// removed from the reactor. So to stop an accept loop, use
+    /// `select!` on `accept()` and `CancellationToken::cancelled()`, and
+    /// break out of the loop when the token is cancelled.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        loop {
+            match self.lstn.accept() {
+                Ok((strm, addr)) => return Ok((TcpStream::from_std(strm)?, addr)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.read_ready().await?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Get the local address of this listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.lstn.local_addr()
+    }
+
+    /// Accept connections, and spawn a task running `handler` for each one.
+    ///
+    /// At most `limit` handlers run at the same time. When that limit is
+    /// reached, no new connections are accepted until a handler finishes,
+    /// so that a connection flood queues up in the kernel instead of eating
+    /// up file descriptors and memory.
+    ///
+    /// Errors on individual connections are ignored. Other errors, such as
+    /// running out of file descriptors, are returned. A `limit` of 0 is an
+    /// error, since no connection would ever be handled.
+    pub async fn serve<F, Fut>(self, limit: usize, handler: F) -> io::Result<()>
+    where
+        F: FnMut(TcpStream, SocketAddr) -> Fut,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let never = crate::sync::CancellationToken::new();
+        self.serve_with_shutdown(limit, &never, None, handler).await.map(|_| ())
+    }
+
+    /// Like `serve`, but with graceful shutdown.
+    ///
+    /// When `shutdown` is cancelled, no new connections are accepted, and
+    /// the handlers that are still running are allowed to finish. If
+    /// `grace` is set, handlers still running after that period are aborted.
+    /// Then this returns how many handlers completed and how many were aborted.
+    ///
+    /// The listener is dropped when this returns, so new connections are
+    /// refused from then on.
+    pub async fn serve_with_shutdown<F, Fut>(
+        self,
+        limit: usize,
+        shutdown: &crate::sync::CancellationToken,
+        grace: Option<Duration>,
+        mut handler: F,
+    ) -> io::Result<ServeSummary>
+    where
+        F: FnMut(TcpStream, SocketAddr) -> Fut,
+        Fut: Future<Output = ()> + 'static,
+    {
+        if limit == 0 {
+            let msg = "serve: limit must be at least 1";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let semaphore = crate::sync::Semaphore::new(limit);
+        let completed = Rc::new(std::cell::Cell::new(0));
+        let mut handlers = Vec::new();
+        let mut bucket = self.max_accepts_per_sec.map(TokenBucket::new);
+
+        loop {
+            // Wait for a free slot, the rate limit, and a connection, or for shutdown.
+            let conn = async {
+                let permit = semaphore.acquire().await;
+                if let Some(bucket) = bucket.as_mut() {
+                    while let Some(delay) = bucket.take() {
+                        crate::time::sleep(delay).await;
+                    }
+                }
+                (permit, self.accept().await)
+            };
+            let mut conn = std::pin::pin!(conn);
+            let mut cancelled = std::pin::pin!(shutdown.cancelled());
+            let res = std::future::poll_fn(|cx| {
+                if cancelled.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(None);
+                }
+                conn.as_mut().poll(cx).map(Some)
+            }).await;
+
+            let (permit, strm, addr) = match res {
+                None => break,
+                Some((permit, Ok((strm, addr)))) => (permit, strm, addr),
+                Some((_, Err(e))) if is_connection_error(&e) => continue,
+                Some((_, Err(e))) => return Err(e),
+            };
+            let fut = handler(strm, addr);
+            let completed = completed.clone();
+            handlers.push(crate::task::spawn(async move {
+                fut.await;
+                completed.set(completed.get() + 1);
+                drop(permit);
+            }));
+
+            // Forget about the handlers that are done.
+            if handlers.len() > limit {
+                handlers.retain(|h| !h.is_finished());
+            }
+        }
+        drop(self);
+
+        // Wait for the handlers to finish, or for the grace period to end.
+        let mut sleep = grace.map(crate::time::sleep);
+        let mut aborted = 0;
+        for handle in handlers {
+            let mut handle = std::pin::pin!(handle);
+            let done = std::future::poll_fn(|cx| {
+                if handle.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(true);
+                }
+                match sleep.as_mut() {
+                    Some(sleep) => sleep.poll_elapsed(cx).map(|_| false),
+                    None => Poll::Pending,
+                }
+            }).await;
+            if !done {
+                handle.abort();
+                aborted += 1;
+            }
+        }
+
+        Ok(ServeSummary { completed: completed.get(), aborted })
+    }
+}
+
+// Token bucket for TcpListener::max_accepts_per_sec.
+struct TokenBucket {
+    rate:   f64,
+    tokens: f64,
+    last:   std::time::Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> TokenBucket {
+        let rate = rate.max(1) as f64;
+        TokenBucket { rate, tokens: rate, last: std::time::Instant::now() }
+    }
+
+    // Take a token. If there is none, return how long to wait for the next one.
+    fn take(&mut self) -> Option<Duration> {
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+}
+
+/// Returned by `TcpListener::serve_with_shutdown`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServeSummary {
+    /// Number of connection handlers that ran to completion.
+    pub completed: usize,
+    /// Number of connection handlers that were aborted at the end of the grace period.
+    pub aborted: usize,
+}
+
+/// Listens on both IPv6 and IPv4, with one or two sockets.
+///
+/// Returned by `TcpListener::bind_dual_stack`.
+pub struct DualStackListener {
+    listeners:  Vec<TcpListener>,
+    // Listener to check first, so that one busy socket can not starve the other.
+    next:       std::cell::Cell<usize>,
+}
+
+impl DualStackListener {
+    fn new(listeners: Vec<TcpListener>) -> DualStackListener {
+        DualStackListener { listeners, next: std::cell::Cell::new(0) }
+    }
+
+    /// Accept a new incoming connection on any of the sockets.
+    ///
+    /// This is cancel-safe, like `TcpListener::accept`.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut accepts: Vec<_> = self.listeners.iter().map(|l| Box::pin(l.accept())).collect();
+        std::future::poll_fn(|cx| {
+            let start = self.next.get();
+            for i in 0 .. accepts.len() {
+                let idx = (start + i) % accepts.len();
+                if let Poll::Ready(res) = accepts[idx].as_mut().poll(cx) {
+                    self.next.set(idx + 1);
+                    return Poll::Ready(res);
+                }
+            }
+            Poll::Pending
+        }).await
+    }
+
+    /// A stream of incoming connections on any of the sockets.
+    pub fn incoming(&self) -> Incoming<'_> {
+        let accepts = self.listeners.iter().map(Incoming::accept).collect();
+        Incoming { listeners: &self.listeners, accepts, next: 0 }
+    }
+
+    /// The underlying listeners: one dual-stack socket, or one per family.
+    pub fn listeners(&self) -> &[TcpListener] {
+        &self.listeners
+    }
+
+    /// Get the local addresses of the listeners.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|l| l.local_addr()).collect()
+    }
+}
+
+impl std::fmt::Debug for DualStackListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DualStackListener").field("listeners", &self.listeners).finish()
+    }
+}
+
+type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<TcpStream>>>>;
+
+/// Settings for `TcpStream::connect_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HappyEyeballsConfig {
+    attempt_delay:  Duration,
+    max_parallel:   usize,
+}
+
+impl Default for HappyEyeballsConfig {
+    fn default() -> HappyEyeballsConfig {
+        HappyEyeballsConfig {
+            attempt_delay: Duration::from_millis(250),
+            max_parallel: 2,
+        }
+    }
+}
+
+impl HappyEyeballsConfig {
+    /// New HappyEyeballsConfig, with an attempt delay of 250ms (the value
+    /// that RFC 8305 recommends) and at most 2 parallel attempts.
+    pub fn new() -> HappyEyeballsConfig {
+        HappyEyeballsConfig::default()
+    }
+
+    /// How long to wait for an attempt before starting the next one.
+    pub fn with_attempt_delay(mut self, delay: Duration) -> HappyEyeballsConfig {
+        self.attempt_delay = delay;
+        self
+    }
+
+    /// How many attempts can be in progress at the same time. A value
+    /// of 1 (or 0) makes `connect_with` try the addresses one by one.
+    /// While the maximum is reached, the next address is only tried when
+    /// one of the attempts fails.
+    pub fn with_max_parallel(mut self, max: usize) -> HappyEyeballsConfig {
+        self.max_parallel = max;
+        self
+    }
+
+    /// Get the attempt delay.
+    pub fn attempt_delay(&self) -> Duration {
+        self.attempt_delay
+    }
+
+    /// Get the maximum number of parallel attempts.
+    pub fn max_parallel(&self) -> usize {
+        self.max_parallel
+    }
+}
+
+type AcceptFuture<'a> = Pin<Box<dyn Future<Output = io::Result<(TcpStream, SocketAddr)>> + 'a>>;
+
+/// Stream of incoming connections, returned by `DualStackListener::incoming`.
+pub struct Incoming<'a> {
+    listeners:  &'a [TcpListener],
+    accepts:    Vec<AcceptFuture<'a>>,
+    next:       usize,
+}
+
+impl<'a> Incoming<'a> {
+    fn accept(listener: &'a TcpListener) -> AcceptFuture<'a> {
+        Box::pin(listener.accept())
+    }
+}
+
+impl futures_core::Stream for Incoming<'_> {
+    type Item = io::Result<(TcpStream, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        for i in 0 .. this.accepts.len() {
+            let idx = (this.next + i) % this.accepts.len();
+            if let Poll::Ready(res) = this.accepts[idx].as_mut().poll(cx) {
+                this.accepts[idx] = Incoming::accept(&this.listeners[idx]);
+                this.next = idx + 1;
+                return Poll::Ready(Some(res));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl std::fmt::Debug for Incoming<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Incoming").field("listeners", &self.listeners).finish()
+    }
+}
+
+// socket2 only has bind_device with the "all" feature.
+#[cfg(target_os = "linux")]
+fn bind_device(fd: std::os::fd::RawFd, interface: Option<&str>) -> io::Result<()> {
+    let name = interface.unwrap_or("").as_bytes();
+    syscall::setsockopt_bytes(fd, libc::SOL_SOCKET, libc::SO_BINDTODEVICE, name).map_err(|e| {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            io::Error::new(e.kind(), format!("SO_BINDTODEVICE needs CAP_NET_RAW: {}", e))
+        } else {
+            e
+        }
+    })
+}
+
+// Errors from accept() that only affect that one connection.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(e.kind(),
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::Interrupted)
+}
+
+// Errors from socket() or bind() that mean there is no (usable) IPv6.
+fn no_ipv6(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EAFNOSUPPORT) | Some(libc::EADDRNOTAVAIL))
+}
+
+impl std::fmt::Debug for TcpListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("TcpListener");
+        if let Ok(addr) = self.lstn.local_addr() {
+            d.field("addr", &addr);
+        }
+        d.field("fd", &self.lstn.as_raw_fd()).finish()
+    }
 }
 
 /// A TCP stream.
 pub struct TcpStream {
     strm:   std::net::TcpStream,
     regfd:  Registration,
+    read_timeout:   OpTimeout,
+    write_timeout:  OpTimeout,
 }
 
 impl TcpStream {
+    fn new(strm: std::net::TcpStream, regfd: Registration) -> TcpStream {
+        TcpStream {
+            strm,
+            regfd,
+            read_timeout: OpTimeout::new(),
+            write_timeout: OpTimeout::new(),
+        }
+    }
+
     /// Construct a nara::TcpStream from a std::net::TcpStream.
+    ///
+    /// Fails if not called from within a runtime context.
     pub fn from_std(stream: std::net::TcpStream) -> io::Result<TcpStream> {
         stream.set_nonblocking(true)?;
         let fd = stream.as_raw_fd();
-        Ok(TcpStream {
-            strm: stream,
-            regfd: Registration::new(fd),
-        })
+        Ok(TcpStream::new(stream, Registration::new(fd)?))
     }
 
     /// Connect to a remote host.
+    ///
+    /// The resolved addresses are tried in order. It is safe to drop the
+    /// future, e.g. on a timeout: the socket of the attempt in progress is
+    /// closed, and no further addresses are tried. To try several
+    /// addresses in parallel, see `connect_with`.
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        Self::connect_report(addr, |_, _| {}).await
+    }
+
+    /// Connect to a remote host, reporting the outcome of every attempt.
+    ///
+    /// `report` is called once for each resolved address that was tried,
+    /// with the address and the result of the connection attempt. This is
+    /// useful for logging, since `connect` only returns the last error
+    /// if all addresses fail.
+    pub async fn connect_report<A, F>(addr: A, mut report: F) -> io::Result<TcpStream>
+    where
+        A: ToSocketAddrs,
+        F: FnMut(SocketAddr, &io::Result<()>),
+    {
         let addrs = addr.to_socket_addrs().await?;
         let mut err: io::Error = io::ErrorKind::NotFound.into();
         for addr in addrs.into_iter() {
             let sock = if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() };
-            match sock?.connect(addr).await {
-                Ok(strm) => return Ok(strm),
+            let res = match sock {
+                Ok(sock) => sock.connect(addr).await,
+                Err(e) => Err(e),
+            };
+            let (strm, res) = match res {
+                Ok(strm) => (Some(strm), Ok(())),
+                Err(e) => (None, Err(e)),
+            };
+            report(addr, &res);
+            match strm {
+                Some(strm) => return Ok(strm),
+                None => err = res.unwrap_err(),
+            }
+        }
+        Err(err)
+    }
+
+    /// Connect to a remote host, giving up on an address after `timeout`.
+    ///
+    /// The resolved addresses are tried in order. If an attempt does not
+    /// complete within `timeout`, its socket is closed and the next
+    /// address is tried. If that was the last one, this fails with
+    /// `io::ErrorKind::TimedOut`. A zero `timeout` is an error, like with
+    /// `std::net::TcpStream::connect_timeout`.
+    pub async fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> io::Result<TcpStream> {
+        check_timeout(Some(timeout))?;
+        let addrs = addr.to_socket_addrs().await?;
+        let mut err: io::Error = io::ErrorKind::NotFound.into();
+        for addr in addrs.into_iter() {
+            let sock = if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() };
+            let sock = match sock {
+                Ok(sock) => sock,
+                Err(e) => {
+                    err = e;
+                    continue;
+                },
+            };
+            match crate::time::timeout(timeout, sock.connect(addr)).await {
+                Ok(Ok(strm)) => return Ok(strm),
+                Ok(Err(e)) => err = e,
+                Err(_) => err = io::Error::new(io::ErrorKind::TimedOut, "connection timed out"),
+            }
+        }
+        Err(err)
+    }
+
+    /// Connect to a remote host, sending `data` with the SYN if possible
+    /// (TCP Fast Open). See `TcpSocket::connect_with_data`.
+    ///
+    /// The resolved addresses are tried in order. Returns the stream, and
+    /// whether Fast Open was used.
+    pub async fn connect_with_data<A: ToSocketAddrs>(addr: A, data: &[u8]) -> io::Result<(TcpStream, bool)> {
+        let addrs = addr.to_socket_addrs().await?;
+        let mut err: io::Error = io::ErrorKind::NotFound.into();
+        for addr in addrs.into_iter() {
+            let sock = if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() };
+            match sock {
+                Ok(sock) => match sock.connect_with_data(addr, data).await {
+                    Ok(res) => return Ok(res),
+                    Err(e) => err = e,
+                },
                 Err(e) => err = e,
             }
         }
         Err(err)
     }
 
+    /// Connect to a remote host, racing attempts to several addresses
+    /// ("Happy Eyeballs", RFC 8305).
+    ///
+    /// The resolved addresses are tried in order, but if an attempt has
+    /// not completed after `config`'s attempt delay, the next one is
+    /// started while the first is still in progress, up to the maximum
+    /// number of parallel attempts. A failed attempt starts the next one
+    /// right away. The first connection to succeed is returned and the
+    /// other attempts are closed. If all fail, the last error is returned.
+    ///
+    /// The addresses are not reordered: use `set_address_sort` with
+    /// `Interleaved` to alternate between IPv6 and IPv4.
+    pub async fn connect_with<A: ToSocketAddrs>(addr: A, config: HappyEyeballsConfig) -> io::Result<TcpStream> {
+        let mut addrs = addr.to_socket_addrs().await?.into_iter();
+        let mut err: io::Error = io::ErrorKind::NotFound.into();
+        let mut attempts: Vec<ConnectFuture> = Vec::new();
+        let mut next_attempt = crate::time::sleep(config.attempt_delay);
+        let mut start_now = true;
+        let max_parallel = config.max_parallel.max(1);
+
+        std::future::poll_fn(|cx| {
+            loop {
+                let mut idx = 0;
+                while idx < attempts.len() {
+                    match attempts[idx].as_mut().poll(cx) {
+                        Poll::Ready(Ok(strm)) => return Poll::Ready(Ok(strm)),
+                        Poll::Ready(Err(e)) => {
+                            err = e;
+                            drop(attempts.swap_remove(idx));
+                            start_now = true;
+                        },
+                        Poll::Pending => idx += 1,
+                    }
+                }
+                if attempts.len() < max_parallel && addrs.len() > 0 &&
+                   (start_now || attempts.is_empty() || next_attempt.poll_elapsed(cx).is_ready()) {
+                    let addr = addrs.next().unwrap();
+                    let sock = if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() };
+                    match sock {
+                        Ok(sock) => {
+                            attempts.push(Box::pin(sock.connect(addr)));
+                            next_attempt.reset(Instant::now() + config.attempt_delay);
+                            start_now = false;
+                        },
+                        Err(e) => err = e,
+                    }
+                    continue;
+                }
+                if attempts.is_empty() {
+                    return Poll::Ready(Err(std::mem::replace(&mut err, io::ErrorKind::NotFound.into())));
+                }
+                return Poll::Pending;
+            }
+        }).await
+    }
+
+    /// Connect to a remote host, from a local port in the range `ports`.
+    ///
+    /// The ports are tried in order, with SO_REUSEADDR set. If a port
+    /// is in use, the next one is tried. This is useful for NAT traversal,
+    /// where the local port must be predictable.
+    pub async fn connect_port_range(
+        addr: SocketAddr,
+        ports: RangeInclusive<u16>,
+    ) -> io::Result<TcpStream> {
+        let ip: std::net::IpAddr = if addr.is_ipv4() {
+            std::net::Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            std::net::Ipv6Addr::UNSPECIFIED.into()
+        };
+        for port in ports {
+            let sock = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            sock.set_reuseaddr(true)?;
+            match sock.bind(SocketAddr::new(ip, port)) {
+                Ok(()) => {},
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+            match sock.connect(addr).await {
+                // The (local, remote) tuple can still be in use (TIME_WAIT).
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => continue,
+                res => return res,
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "no free port in range"))
+    }
+
+    /// Set the size of the receive buffer (SO_RCVBUF).
+    ///
+    /// The kernel may double or cap the requested value.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        SockRef::from(&self.strm).set_recv_buffer_size(size)
+    }
+
+    /// Get the size of the receive buffer (SO_RCVBUF).
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        SockRef::from(&self.strm).recv_buffer_size()
+    }
+
+    /// Set the size of the send buffer (SO_SNDBUF).
+    ///
+    /// The kernel may double or cap the requested value.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        SockRef::from(&self.strm).set_send_buffer_size(size)
+    }
+
+    /// Get the size of the send buffer (SO_SNDBUF).
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        SockRef::from(&self.strm).send_buffer_size()
+    }
+
+    /// Set TCP_NODELAY, which disables Nagle's algorithm.
+    ///
+    /// Small writes are then sent right away, instead of being held back
+    /// until earlier data has been acknowledged.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.strm.set_nodelay(nodelay)
+    }
+
+    /// Get the value of TCP_NODELAY.
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.strm.nodelay()
+    }
+
+    /// Cork the stream (TCP_CORK) until the returned guard is dropped.
+    ///
+    /// While corked, the kernel does not send partial segments, so a
+    /// header and body written separately go out as one segment. When the
+    /// guard is dropped the stream is uncorked and pending data is sent,
+    /// also if a write failed. Write through the guard, it dereferences
+    /// to the `TcpStream`.
+    ///
+    /// This is a no-op on platforms without TCP_CORK.
+    pub fn cork(&mut self) -> io::Result<CorkGuard<'_>> {
+        set_cork(&self.strm, true)?;
+        Ok(CorkGuard { strm: self })
+    }
+
+    /// Try to read data from the stream, without waiting.
+    ///
+    /// If no data is available, this returns `io::ErrorKind::WouldBlock`.
+    /// No waker is registered with the reactor, so this can also be
+    /// called outside of a task.
+    pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::Read;
+        (&self.strm).read(buf)
+    }
+
+    /// Try to write data to the stream, without waiting.
+    ///
+    /// If the socket buffer is full, this returns `io::ErrorKind::WouldBlock`.
+    /// No waker is registered with the reactor, so this can also be
+    /// called outside of a task.
+    pub fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+        use std::io::Write;
+        (&self.strm).write(buf)
+    }
+
+    /// Receive data from the stream without removing it from the socket's
+    /// receive queue, so that the next read returns the same bytes.
+    ///
+    /// Waits until data is available, and returns 0 at end-of-file. Useful
+    /// for protocol sniffing, e.g. to tell a TLS ClientHello from plaintext.
+    pub async fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match syscall::peek(self.strm.as_raw_fd(), buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.read_ready().await?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Wait until the stream is readable and/or writable.
+    ///
+    /// This registers one waiter for all of `interest`, which makes it a good
+    /// fit for full-duplex loops: await `ready(Interest::READ | Interest::WRITE)`,
+    /// then call `try_read` and/or `try_write` depending on the `Readiness`.
+    /// Those can still return `io::ErrorKind::WouldBlock` on a spurious wakeup.
+    pub async fn ready(&self, interest: Interest) -> io::Result<Readiness> {
+        self.regfd.ready(interest).await
+    }
+
+    /// Set the read timeout.
+    ///
+    /// If set, every read that does not complete within `timeout` fails
+    /// with `io::ErrorKind::TimedOut`. A zero duration is an error.
+    ///
+    /// The timer starts anew with every `read` call, also after an earlier
+    /// one was dropped while pending. Through the `AsyncRead` trait a
+    /// dropped operation cannot be detected, so there the next operation
+    /// keeps its timer.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.read_timeout.timeout = timeout;
+        Ok(())
+    }
+
+    /// Get the read timeout.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout.timeout
+    }
+
+    /// Set the write timeout.
+    ///
+    /// If set, every write that does not complete within `timeout` fails
+    /// with `io::ErrorKind::TimedOut`. A zero duration is an error.
+    ///
+    /// The timer starts anew with every `write` call, also after an earlier
+    /// one was dropped while pending. Through the `AsyncWrite` trait a
+    /// dropped operation cannot be detected, so there the next operation
+    /// keeps its timer.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.write_timeout.timeout = timeout;
+        Ok(())
+    }
+
+    /// Get the write timeout.
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout.timeout
+    }
+
+    /// Close the connection abortively.
+    ///
+    /// This sets SO_LINGER to zero and closes the socket, so that any
+    /// unsent data is discarded and a RST is sent to the peer.
+    pub fn reset(self) -> io::Result<()> {
+        SockRef::from(&self.strm).set_linger(Some(Duration::ZERO))?;
+        // Deregister from the reactor first, then close.
+        let TcpStream { strm, regfd, .. } = self;
+        drop(regfd);
+        drop(strm);
+        Ok(())
+    }
+
     /// Shutdown the write part of the socket.
     pub fn shutdown(&self) -> io::Result<()> {
         self.strm.shutdown(std::net::Shutdown::Write)
     }
 }
 
-crate::io::impl_async_read!(TcpStream, strm, regfd);
-crate::io::impl_async_write!(TcpStream, strm, regfd, shutdown);
+impl std::fmt::Debug for TcpStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("TcpStream");
+        if let Ok(addr) = self.strm.local_addr() {
+            d.field("addr", &addr);
+        }
+        if let Ok(peer) = self.strm.peer_addr() {
+            d.field("peer", &peer);
+        }
+        d.field("fd", &self.strm.as_raw_fd()).finish()
+    }
+}
+
+// socket2 only has set_reuse_port with the "all" feature.
+fn set_reuseport(sock: &Socket, reuseport: bool) -> io::Result<()> {
+    syscall::setsockopt_int(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_REUSEPORT, reuseport as libc::c_int)
+}
+
+/// Guard returned by `TcpStream::cork`. Uncorks the stream when dropped.
+pub struct CorkGuard<'a> {
+    strm: &'a mut TcpStream,
+}
+
+impl std::ops::Deref for CorkGuard<'_> {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        self.strm
+    }
+}
+
+impl std::ops::DerefMut for CorkGuard<'_> {
+    fn deref_mut(&mut self) -> &mut TcpStream {
+        self.strm
+    }
+}
+
+impl std::fmt::Debug for CorkGuard<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CorkGuard").field("stream", &self.strm).finish()
+    }
+}
+
+impl Drop for CorkGuard<'_> {
+    fn drop(&mut self) {
+        let _ = set_cork(&self.strm.strm, false);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_cork(strm: &std::net::TcpStream, cork: bool) -> io::Result<()> {
+    syscall::setsockopt_int(strm.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_CORK, cork as libc::c_int)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cork(_strm: &std::net::TcpStream, _cork: bool) -> io::Result<()> {
+    Ok(())
+}
+
+fn set_tcp_keepalive(sock: &Socket, keepalive: &TcpKeepalive) -> io::Result<()> {
+    let secs = |d: Duration| d.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+    let fd = sock.as_raw_fd();
+    if let Some(idle) = keepalive.idle {
+        syscall::setsockopt_int(fd, libc::IPPROTO_TCP, syscall::TCP_KEEPIDLE, secs(idle))?;
+    }
+    if let Some(interval) = keepalive.interval {
+        syscall::setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs(interval))?;
+    }
+    if let Some(retries) = keepalive.retries {
+        let retries = retries.min(libc::c_int::MAX as u32) as libc::c_int;
+        syscall::setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, retries)?;
+    }
+    sock.set_keepalive(true)
+}
+
+fn tcp_keepalive(sock: &Socket) -> io::Result<Option<TcpKeepalive>> {
+    if !sock.keepalive()? {
+        return Ok(None);
+    }
+    let fd = sock.as_raw_fd();
+    let secs = |v: libc::c_int| Duration::from_secs(v as u64);
+    Ok(Some(TcpKeepalive {
+        idle: Some(secs(syscall::getsockopt_int(fd, libc::IPPROTO_TCP, syscall::TCP_KEEPIDLE)?)),
+        interval: Some(secs(syscall::getsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL)?)),
+        retries: Some(syscall::getsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT)? as u32),
+    }))
+}
+
+// Start a connection with sendto(MSG_FASTOPEN), which puts the data in the SYN
+// if the kernel has a cookie for the server. Returns how much data was sent.
+#[cfg(target_os = "linux")]
+fn send_fastopen(sock: &Socket, addr: SocketAddr, data: &[u8]) -> io::Result<usize> {
+    sock.send_to_with_flags(data, &addr.into(), libc::MSG_FASTOPEN | libc::MSG_NOSIGNAL)
+}
+
+// Errors from send_fastopen after which we connect and write instead.
+fn fastopen_unavailable(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported ||
+        matches!(e.raw_os_error(),
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOPROTOOPT) | Some(libc::EINPROGRESS))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_fastopen(_sock: &Socket, _addr: SocketAddr, _data: &[u8]) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Same check as std::net::TcpStream::set_read_timeout.
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot set a 0 duration timeout"));
+    }
+    Ok(())
+}
+
+crate::io::impl_async_read!(TcpStream, strm, regfd, read_timeout);
+crate::io::impl_async_write!(TcpStream, strm, regfd, shutdown, write_timeout);
+
+/// A UDP socket.
+pub struct UdpSocket {
+    sock:   std::net::UdpSocket,
+    regfd:  Registration,
+}
+
+/// Time at which a datagram was received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvTimestamp {
+    /// The time the datagram was received.
+    pub time:   SystemTime,
+    /// `true` if the timestamp came from the kernel, `false` if the kernel
+    /// did not supply one and the time was taken when the datagram was read.
+    pub from_kernel: bool,
+}
+
+impl UdpSocket {
+    /// Create a UDP socket bound to the given address.
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs().await? {
+            match std::net::UdpSocket::bind(addr) {
+                Ok(sock) => return UdpSocket::from_std(sock),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+        }))
+    }
+
+    /// Construct a nara::UdpSocket from a std::net::UdpSocket.
+    pub fn from_std(sock: std::net::UdpSocket) -> io::Result<UdpSocket> {
+        sock.set_nonblocking(true)?;
+        let fd = sock.as_raw_fd();
+        Ok(UdpSocket { sock, regfd: Registration::new(fd)? })
+    }
+
+    /// Get the local address of this socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    /// Get the address of the peer, if the socket is connected.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    /// Connect the socket to a remote address.
+    ///
+    /// Then `send` and `recv` can be used, and only datagrams from that
+    /// address are received. If `addr` resolves to multiple addresses,
+    /// the first one that works is used.
+    pub async fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs().await? {
+            match self.sock.connect(addr) {
+                Ok(()) => return Ok(()),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+        }))
+    }
+
+    /// Receive a datagram.
+    ///
+    /// If the datagram is larger than `buf`, the rest of it is discarded.
+    /// On Linux, the returned length is then the real length of the
+    /// datagram, which is larger than `buf.len()`, so that truncation can
+    /// be detected. Elsewhere it is the number of bytes put in `buf`.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            match syscall::recv_from(self.sock.as_raw_fd(), buf) {
+                Ok((len, addr)) => {
+                    let addr = addr.as_socket().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid peer address")
+                    })?;
+                    return Ok((len, addr));
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.read_ready().await?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Receive a datagram on a connected socket.
+    ///
+    /// A datagram that is larger than `buf` is truncated, see `recv_from`.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match syscall::recv_from(self.sock.as_raw_fd(), buf) {
+                Ok((len, _)) => return Ok(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.read_ready().await?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send a datagram to `addr`.
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        loop {
+            match self.sock.send_to(buf, addr) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.write_ready().await?,
+                res => return res,
+            }
+        }
+    }
+
+    /// Send a datagram on a connected socket.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.sock.send(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.write_ready().await?,
+                res => return res,
+            }
+        }
+    }
+
+    /// Bind the socket to a network interface (SO_BINDTODEVICE), or remove
+    /// the binding with `None`. Linux only.
+    ///
+    /// This needs the CAP_NET_RAW capability, without it this fails with
+    /// `io::ErrorKind::PermissionDenied`.
+    #[cfg(target_os = "linux")]
+    pub fn bind_device(&self, interface: Option<&str>) -> io::Result<()> {
+        bind_device(self.sock.as_raw_fd(), interface)
+    }
+
+    /// Enable or disable kernel receive timestamps (SO_TIMESTAMPNS on Linux,
+    /// SO_TIMESTAMP elsewhere).
+    pub fn set_timestamping(&self, on: bool) -> io::Result<()> {
+        let fd = self.sock.as_raw_fd();
+        syscall::setsockopt_int(fd, libc::SOL_SOCKET, syscall::SO_TIMESTAMP, on as libc::c_int)
+    }
+
+    /// Receive a datagram, together with the time it was received.
+    ///
+    /// The timestamp is taken from the kernel if timestamping was enabled
+    /// with `set_timestamping`. Otherwise the current time is used, and
+    /// `RecvTimestamp::from_kernel` is `false`. A datagram that is larger
+    /// than `buf` is truncated, see `recv_from`.
+    pub async fn recv_from_with_timestamp(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, RecvTimestamp)> {
+        let fd = self.sock.as_raw_fd();
+        loop {
+            match syscall::recvmsg_timestamp(fd, buf) {
+                Ok((len, addr, time)) => {
+                    let addr = addr.as_socket().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid peer address")
+                    })?;
+                    let ts = match time {
+                        Some(time) => RecvTimestamp { time, from_kernel: true },
+                        None => RecvTimestamp { time: SystemTime::now(), from_kernel: false },
+                    };
+                    return Ok((len, addr, ts));
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.read_ready().await?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for UdpSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("UdpSocket");
+        if let Ok(addr) = self.sock.local_addr() {
+            d.field("addr", &addr);
+        }
+        d.field("fd", &self.sock.as_raw_fd()).finish()
+    }
+}
@@ -0,0 +1,123 @@
+use std::cell::RefCell;
+use std::task::{Context, Poll};
+
+use crate::waiters::{WaitQueue, WaiterGuard};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Notified {
+    No,
+    // by notify_one
+    One,
+    // by notify_waiters
+    All,
+}
+
+// Shared notify state.
+struct Inner {
+    permit: bool,
+    waiters: WaitQueue<Notified>,
+}
+
+impl Inner {
+    // Notify the oldest waiter that was not notified yet, or store a permit.
+    fn notify_one(&mut self) {
+        match self.waiters.iter_mut().find(|w| w.data == Notified::No) {
+            Some(w) => {
+                w.data = Notified::One;
+                w.waker.wake_by_ref();
+            },
+            None => self.permit = true,
+        }
+    }
+
+    // Remove waiter `id`. A notification from notify_one that it did not
+    // consume is passed on.
+    fn remove_waiter(&mut self, id: u64) {
+        if let Some(w) = self.waiters.remove(id) {
+            if w.data == Notified::One {
+                self.notify_one();
+            }
+        }
+    }
+}
+
+/// Signal a task that something happened, without sending a value.
+///
+/// A task waits with `notified().await`. `notify_one` wakes the task
+/// that has been waiting the longest; if no task is waiting, it stores
+/// a permit, so that the next `notified()` returns right away. At most
+/// one permit is stored.
+pub struct Notify {
+    inner: RefCell<Inner>,
+}
+
+impl Notify {
+    /// Create a new `Notify`, without a permit.
+    pub fn new() -> Notify {
+        let inner = Inner { permit: false, waiters: WaitQueue::new() };
+        Notify { inner: RefCell::new(inner) }
+    }
+
+    /// Wake the oldest waiting task, or store a permit if none is waiting.
+    pub fn notify_one(&self) {
+        self.inner.borrow_mut().notify_one();
+    }
+
+    /// Wake all tasks that are waiting right now.
+    ///
+    /// Only `notified()` futures that have been polled count as waiting.
+    /// No permit is stored.
+    pub fn notify_waiters(&self) {
+        let mut inner = self.inner.borrow_mut();
+        for w in inner.waiters.iter_mut().filter(|w| w.data == Notified::No) {
+            w.data = Notified::All;
+            w.waker.wake_by_ref();
+        }
+    }
+
+    /// Wait for a notification.
+    pub async fn notified(&self) {
+        let id = self.inner.borrow_mut().waiters.next_id();
+        // If this future is dropped while waiting, give up our place in the queue.
+        let _guard = WaiterGuard::new(id, &self.inner, Inner::remove_waiter);
+        std::future::poll_fn(|cx: &mut Context<'_>| {
+            let mut inner = self.inner.borrow_mut();
+            let notified = inner.waiters.iter().find(|w| w.id == id).map(|w| w.data);
+            match notified {
+                Some(Notified::No) => {
+                    inner.waiters.register(id, cx.waker(), Notified::No);
+                    Poll::Pending
+                },
+                Some(_) => {
+                    inner.waiters.remove(id);
+                    Poll::Ready(())
+                },
+                None if inner.permit => {
+                    inner.permit = false;
+                    Poll::Ready(())
+                },
+                None => {
+                    inner.waiters.register(id, cx.waker(), Notified::No);
+                    Poll::Pending
+                },
+            }
+        }).await
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+impl std::fmt::Debug for Notify {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Notify");
+        if let Ok(inner) = self.inner.try_borrow() {
+            d.field("permit", &inner.permit);
+            d.field("waiters", &inner.waiters.len());
+        }
+        d.finish()
+    }
+}
@@ -0,0 +1,268 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::waiters::{WaitQueue, WaiterGuard};
+
+pub use std::sync::mpsc::{SendError, TryRecvError};
+
+// An item in the queue. Ordered by priority, and then by insertion order.
+struct Entry<T> {
+    priority: u32,
+    seq: u64,
+    value: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    // BinaryHeap is a max-heap: highest priority first, then lowest seq.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+// Shared channel struct.
+struct Channel<T> {
+    queue: BinaryHeap<Entry<T>>,
+    capacity: usize,
+    tx_wakers: WaitQueue,
+    rx_wakers: WaitQueue,
+    closed: bool,
+    seq: u64,
+}
+
+impl<T> Channel<T> {
+    // Wake the waiting senders that can use a free slot.
+    fn wake_senders(&self) {
+        let free = self.capacity - self.queue.len();
+        self.tx_wakers.wake_first(free);
+    }
+
+    // Wake the waiting receivers that can take an item.
+    fn wake_receivers(&self) {
+        self.rx_wakers.wake_first(self.queue.len());
+    }
+
+    // Remove waiting sender `id`, and let the next one have its slot.
+    fn remove_sender(&mut self, id: u64) {
+        if self.tx_wakers.remove(id).is_some() {
+            self.wake_senders();
+        }
+    }
+
+    // Remove waiting receiver `id`, and let the next one have its item.
+    fn remove_receiver(&mut self, id: u64) {
+        if self.rx_wakers.remove(id).is_some() {
+            self.wake_receivers();
+        }
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let entry = self.queue.pop()?;
+        if self.capacity != usize::MAX {
+            self.wake_senders();
+        }
+        Some(entry.value)
+    }
+
+    fn push(&mut self, value: T, priority: u32) {
+        self.seq += 1;
+        self.queue.push(Entry { priority, seq: self.seq, value });
+        self.wake_receivers();
+    }
+}
+
+/// A channel that delivers items with a higher priority first.
+///
+/// Items with the same priority are delivered in the order they were sent.
+/// The channel is a work queue: clones of it share the same queue, and any
+/// of them can send and receive. Waiting senders and receivers are served
+/// in FIFO order.
+///
+/// Because every handle can send, the channel does not close when
+/// handles are dropped. Call `close` to make receivers return `None`
+/// once the queue is empty.
+pub struct PriorityChannel<T> {
+    channel: Rc<RefCell<Channel<T>>>,
+}
+
+impl<T> Clone for PriorityChannel<T> {
+    fn clone(&self) -> Self {
+        PriorityChannel { channel: self.channel.clone() }
+    }
+}
+
+impl<T> PriorityChannel<T> {
+    /// Create a new channel that holds at most `capacity` items.
+    pub fn new(capacity: usize) -> PriorityChannel<T> {
+        let channel = Channel {
+            queue: BinaryHeap::new(),
+            capacity,
+            tx_wakers: WaitQueue::new(),
+            rx_wakers: WaitQueue::new(),
+            closed: false,
+            seq: 0,
+        };
+        PriorityChannel { channel: Rc::new(RefCell::new(channel)) }
+    }
+
+    /// Create a new channel without a limit on the number of items.
+    pub fn unbounded() -> PriorityChannel<T> {
+        PriorityChannel::new(usize::MAX)
+    }
+
+    /// Send a value with a priority. Higher priorities are received first.
+    ///
+    /// If the channel is full, wait until there is room. Fails if the
+    /// channel was closed.
+    pub async fn send(&self, value: T, priority: u32) -> Result<(), SendError<T>> {
+        let mut store = Some(value);
+        let id = self.channel.borrow_mut().tx_wakers.next_id();
+        // If this future is dropped while waiting, give up our place in the queue.
+        let _guard = WaiterGuard::new(id, &self.channel, Channel::remove_sender);
+        std::future::poll_fn(|cx: &mut Context<'_>| {
+
+            // Yield every now and then, so we don't starve I/O.
+            if crate::executor::poll_budget(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            let mut channel = self.channel.borrow_mut();
+            if channel.closed {
+                return Poll::Ready(Err(SendError(store.take().unwrap())));
+            }
+
+            // If under capacity, push, unless senders that were waiting
+            // before us need the free slots.
+            let free = channel.capacity - channel.queue.len();
+            let pos = channel.tx_wakers.position(id);
+            if pos.unwrap_or(channel.tx_wakers.len()) < free {
+                channel.tx_wakers.remove(id);
+                channel.push(store.take().unwrap(), priority);
+                return Poll::Ready(Ok(()));
+            }
+
+            // Arrange for us to be woken when an item is received.
+            channel.tx_wakers.register(id, cx.waker(), ());
+            Poll::Pending
+        }).await
+    }
+
+    /// Send a value with a priority, without waiting.
+    ///
+    /// Fails if the channel is full or closed.
+    pub fn try_send(&self, value: T, priority: u32) -> Result<(), SendError<T>> {
+        let mut channel = self.channel.borrow_mut();
+        if channel.closed || !channel.tx_wakers.is_empty() || channel.queue.len() >= channel.capacity {
+            return Err(SendError(value));
+        }
+        channel.push(value, priority);
+        Ok(())
+    }
+
+    /// Receive the item with the highest priority.
+    ///
+    /// Returns `None` if the channel was closed and is empty.
+    pub async fn recv(&self) -> Option<T> {
+        let id = self.channel.borrow_mut().rx_wakers.next_id();
+        // If this future is dropped while waiting, give up our place in the queue.
+        let _guard = WaiterGuard::new(id, &self.channel, Channel::remove_receiver);
+        std::future::poll_fn(|cx: &mut Context<'_>| {
+
+            // Yield every now and then, so we don't starve I/O.
+            if crate::executor::poll_budget(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            // Take an item, unless receivers that were waiting before us need them.
+            let mut channel = self.channel.borrow_mut();
+            let pos = channel.rx_wakers.position(id);
+            if pos.unwrap_or(channel.rx_wakers.len()) < channel.queue.len() {
+                channel.rx_wakers.remove(id);
+                return Poll::Ready(channel.pop());
+            }
+
+            if channel.closed {
+                return Poll::Ready(None);
+            }
+
+            // Arrange for us to be woken when an item is sent.
+            channel.rx_wakers.register(id, cx.waker(), ());
+            Poll::Pending
+        }).await
+    }
+
+    /// Receive the item with the highest priority, without waiting.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut channel = self.channel.borrow_mut();
+        if channel.rx_wakers.is_empty() {
+            if let Some(value) = channel.pop() {
+                return Ok(value);
+            }
+        }
+        if channel.closed && channel.queue.is_empty() {
+            return Err(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    /// Close the channel.
+    ///
+    /// Senders fail from now on. Items already in the queue can still
+    /// be received, after that receivers get `None`.
+    pub fn close(&self) {
+        let mut channel = self.channel.borrow_mut();
+        channel.closed = true;
+        channel.tx_wakers.wake_all();
+        channel.rx_wakers.wake_all();
+    }
+
+    /// Has the channel been closed?
+    pub fn is_closed(&self) -> bool {
+        self.channel.borrow().closed
+    }
+
+    /// Number of items in the queue.
+    pub fn len(&self) -> usize {
+        self.channel.borrow().queue.len()
+    }
+
+    /// Is the queue empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> std::fmt::Debug for PriorityChannel<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("PriorityChannel");
+        match self.channel.try_borrow() {
+            Ok(channel) => {
+                if channel.capacity != usize::MAX {
+                    d.field("capacity", &channel.capacity);
+                }
+                d.field("len", &channel.queue.len());
+                d.field("closed", &channel.closed);
+            },
+            Err(_) => {
+                d.field("channel", &format_args!("<borrowed>"));
+            },
+        }
+        d.finish()
+    }
+}
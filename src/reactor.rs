@@ -2,12 +2,18 @@
 // A simple reactor that uses poll(2) to react to I/O events.
 // Why poll(2)? Because it's ubiquitous, works on any unix variant.
 //
-use std::cell::{Cell, RefCell};
+// On Linux there is an epoll(7) backend as well. poll(2) has to pass all
+// file descriptors to the kernel on every call, epoll only returns the ones
+// that are ready, which scales a lot better with thousands of connections.
+//
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::os::fd::RawFd;
 use std::rc::{Rc, Weak};
 use std::task::Waker;
 use std::time::Duration;
 
+use crate::runtime::ReactorBackend;
 use crate::syscall;
 
 // Reactor handle.
@@ -17,58 +23,291 @@ pub struct Reactor {
 
 // Actual reactor.
 pub struct InnerReactor {
-    pollfds: Vec<libc::pollfd>,
-    fd_info: Vec<FdWaiters>,
+    backend: Backend,
+    fd_info: HashMap<RawFd, FdWaiters>,
     next_id: u64,
 }
 
+enum Backend {
+    // Scratch buffer, filled from `fd_info` on every react().
+    Poll(Vec<libc::pollfd>),
+    #[cfg(target_os = "linux")]
+    Epoll(Epoll),
+}
+
+#[cfg(target_os = "linux")]
+struct Epoll {
+    epfd:   std::os::fd::OwnedFd,
+    events: Vec<libc::epoll_event>,
+}
+
 thread_local! {
     // Valid after Reactor::activate(), invalid after Reactor::deactivate()
     static REACTOR: RefCell<Weak<RefCell<InnerReactor>>> = RefCell::default();
 }
 
-// Interest.
-#[repr(u16)]
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum Interest {
-    Read = libc::POLLIN as _,
-    Write = libc::POLLOUT as _,
+/// Readiness to wait for.
+///
+/// Can be combined: `Interest::READ | Interest::WRITE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interest(i16);
+
+impl Interest {
+    /// Interest in readability.
+    pub const READ: Interest = Interest(libc::POLLIN);
+    /// Interest in writability.
+    pub const WRITE: Interest = Interest(libc::POLLOUT);
+
+    /// Includes READ.
+    pub fn is_readable(self) -> bool {
+        self.0 & libc::POLLIN != 0
+    }
+
+    /// Includes WRITE.
+    pub fn is_writable(self) -> bool {
+        self.0 & libc::POLLOUT != 0
+    }
+}
+
+impl std::ops::BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+}
+
+/// Readiness of an I/O object, returned by `ready()`.
+///
+/// An error or hangup condition is reported as readable and writable (if
+/// that was the interest), so that the next read or write returns it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Readiness(i16);
+
+impl Readiness {
+    /// Reading will not block.
+    pub fn is_readable(self) -> bool {
+        self.0 & libc::POLLIN != 0
+    }
+
+    /// Writing will not block.
+    pub fn is_writable(self) -> bool {
+        self.0 & libc::POLLOUT != 0
+    }
 }
 
 // One waiter.
 #[derive(Debug)]
 struct FdWaiter {
     reg_id:     u64,
+    // Unique per wait, so that several futures can wait on one Registration.
+    wait_id:    u64,
     interest:   Interest,
     waker:      Waker,
 }
 
+// List of waiters. Most fds have just one waiter, so the first one is
+// stored inline, and only the others need a heap allocation.
+#[derive(Default, Debug)]
+struct WaiterList {
+    // If `first` is None, `rest` is empty.
+    first:      Option<FdWaiter>,
+    rest:       Vec<FdWaiter>,
+}
+
+impl WaiterList {
+    fn push(&mut self, waiter: FdWaiter) {
+        if self.first.is_none() {
+            self.first = Some(waiter);
+        } else {
+            self.rest.push(waiter);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.first.is_none()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &FdWaiter> {
+        self.first.iter().chain(self.rest.iter())
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut FdWaiter> {
+        self.first.iter_mut().chain(self.rest.iter_mut())
+    }
+
+    // Remove the waiters for which `pred` returns true, in place,
+    // and pass them to `f`.
+    fn extract(&mut self, mut pred: impl FnMut(&FdWaiter) -> bool, mut f: impl FnMut(FdWaiter)) {
+        if self.first.as_ref().is_some_and(&mut pred) {
+            f(self.first.take().unwrap());
+        }
+        let mut i = 0;
+        while i < self.rest.len() {
+            if pred(&self.rest[i]) {
+                f(self.rest.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        if self.first.is_none() && !self.rest.is_empty() {
+            self.first = Some(self.rest.remove(0));
+        }
+    }
+
+    // Remove the waiters for which `pred` returns true.
+    fn remove_if(&mut self, pred: impl FnMut(&FdWaiter) -> bool) {
+        self.extract(pred, drop);
+    }
+}
+
 // A list of waiters on a fd.
 #[derive(Default, Debug)]
 struct FdWaiters {
     refcount:   usize,
-    waiters:    Vec<FdWaiter>,
+    waiters:    WaiterList,
+    // poll() returned POLLNVAL, the fd was closed behind our back.
+    invalid:    bool,
+    // epoll does not support the fd (a regular file), it is always ready.
+    always_ready: bool,
+    // epoll: the events the fd is armed for. EPOLLONESHOT disarms it
+    // when an event is reported.
+    armed:      i16,
+    // The events reported by the last wakeup.
+    revents:    i16,
 }
 
 impl FdWaiters {
     // Calculate the event mask for poll() for this fd.
     fn poll_bits(&self) -> i16 {
         self.waiters.iter()
-            .map(|w| w.interest as i16)
-            .fold(0, |mask, i| mask | i) as i16
+            .map(|w| w.interest.0)
+            .fold(0, |mask, i| mask | i)
+    }
+
+    // Wake up and remove the waiters that are interested in `revents`,
+    // keep the others.
+    fn wake(&mut self, revents: i16) {
+        const INTERESTING: i16 = libc::POLLERR | libc::POLLHUP;
+        self.revents = revents;
+        if revents & libc::POLLNVAL != 0 {
+            // The fd was closed while registered. Wake up all waiters,
+            // they will see the error, and stop polling the fd so that
+            // we do not spin on it.
+            self.invalid = true;
+            self.waiters.extract(|_| true, |w| w.waker.wake());
+            return;
+        }
+        self.waiters.extract(
+            |w| (w.interest.0 | INTERESTING) & revents != 0,
+            |w| w.waker.wake(),
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Epoll {
+    const EVENTS: usize = 256;
+
+    fn new() -> std::io::Result<Epoll> {
+        let event = libc::epoll_event { events: 0, u64: 0 };
+        Ok(Epoll {
+            epfd: syscall::epoll_create()?,
+            events: vec![event; Self::EVENTS],
+        })
+    }
+
+    fn epfd(&self) -> RawFd {
+        use std::os::fd::AsRawFd;
+        self.epfd.as_raw_fd()
+    }
+
+    // Add a new fd, not armed yet.
+    fn register(&self, fd: RawFd, fd_waiters: &mut FdWaiters) -> std::io::Result<()> {
+        match syscall::epoll_ctl(self.epfd(), libc::EPOLL_CTL_ADD, fd, libc::EPOLLONESHOT as u32) {
+            Ok(()) => Ok(()),
+            // Regular files and directories cannot be polled, and are always ready.
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                fd_waiters.always_ready = true;
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn deregister(&self, fd: RawFd) {
+        // If the fd was closed already, the kernel has removed it.
+        let _ = syscall::epoll_ctl(self.epfd(), libc::EPOLL_CTL_DEL, fd, 0);
+    }
+
+    // Arm the fd for the events the waiters are interested in, if needed.
+    fn arm(&self, fd: RawFd, fd_waiters: &mut FdWaiters) {
+        let bits = fd_waiters.poll_bits();
+        if bits & !fd_waiters.armed == 0 {
+            return;
+        }
+        let mut events = libc::EPOLLONESHOT as u32;
+        if bits & libc::POLLIN != 0 {
+            events |= libc::EPOLLIN as u32;
+        }
+        if bits & libc::POLLOUT != 0 {
+            events |= libc::EPOLLOUT as u32;
+        }
+        match syscall::epoll_ctl(self.epfd(), libc::EPOLL_CTL_MOD, fd, events) {
+            Ok(()) => fd_waiters.armed = bits,
+            // Closed behind our back. This is what POLLNVAL is for poll().
+            Err(_) => fd_waiters.wake(libc::POLLNVAL),
+        }
+    }
+
+    fn react(&mut self, fd_info: &mut HashMap<RawFd, FdWaiters>, timeout: Option<Duration>) {
+        let n = match syscall::epoll_wait(self.epfd(), &mut self.events, timeout) {
+            Ok(n) => n,
+            Err(_) => return,
+        };
+        for idx in 0 .. n {
+            let event = self.events[idx];
+            let fd = event.u64 as RawFd;
+            let Some(fd_waiters) = fd_info.get_mut(&fd) else { continue };
+
+            // Translate to poll() bits.
+            let mut revents = 0;
+            for (ebit, pbit) in [
+                (libc::EPOLLIN, libc::POLLIN),
+                (libc::EPOLLOUT, libc::POLLOUT),
+                (libc::EPOLLERR, libc::POLLERR),
+                (libc::EPOLLHUP, libc::POLLHUP),
+            ] {
+                if event.events & ebit as u32 != 0 {
+                    revents |= pbit;
+                }
+            }
+
+            // The fd is disarmed now. Re-arm if there are waiters left.
+            fd_waiters.armed = 0;
+            fd_waiters.wake(revents);
+            if !fd_waiters.waiters.is_empty() {
+                self.arm(fd, fd_waiters);
+            }
+        }
     }
 }
 
 impl Reactor {
 
     // Create a new reactor.
-    pub fn new() -> Reactor {
+    pub fn new(backend: ReactorBackend) -> std::io::Result<Reactor> {
+        let backend = match backend {
+            ReactorBackend::Poll => Backend::Poll(Vec::new()),
+            #[cfg(target_os = "linux")]
+            ReactorBackend::Epoll => Backend::Epoll(Epoll::new()?),
+        };
         let inner = InnerReactor {
-            pollfds: Vec::new(),
-            fd_info: Vec::new(),
+            backend,
+            fd_info: HashMap::new(),
             next_id: 1,
         };
-        Reactor{ inner: Rc::new(RefCell::new(inner)) }
+        Ok(Reactor{ inner: Rc::new(RefCell::new(inner)) })
     }
 
     // Activate the thread-local reference.
@@ -83,10 +322,18 @@ impl Reactor {
 
     // Like Registration::new(), but optimized for the case
     // where you already have a Reactor handle.
-    pub fn registration(&self, fd: RawFd) -> Registration {
+    pub fn registration(&self, fd: RawFd) -> std::io::Result<Registration> {
         Registration::new_with_reactor(fd, &self.inner)
     }
 
+    // Is anyone, except `except`, waiting for an event?
+    pub fn has_waiters(&self, except: Option<&Registration>) -> bool {
+        let inner = self.inner.borrow();
+        let except = except.map(|r| r.id);
+        let res = inner.fd_info.values().flat_map(|f| f.waiters.iter()).any(|w| Some(w.reg_id) != except);
+        res
+    }
+
     // Run the reactor.
     pub fn react(&self, timeout: Option<Duration>) {
         // We need to delegate this to impl InnerReactor.
@@ -99,158 +346,214 @@ impl InnerReactor {
 
     // Run the reactor.
     fn react(&mut self, timeout: Option<Duration>) {
-        const INTERESTING: u32 = (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) as u32;
+        match &mut self.backend {
+            Backend::Poll(pollfds) => Self::react_poll(pollfds, &mut self.fd_info, timeout),
+            #[cfg(target_os = "linux")]
+            Backend::Epoll(epoll) => epoll.react(&mut self.fd_info, timeout),
+        }
+    }
+
+    fn react_poll(pollfds: &mut Vec<libc::pollfd>, fd_info: &mut HashMap<RawFd, FdWaiters>, timeout: Option<Duration>) {
+
+        // Only poll the fds that someone is waiting on.
+        pollfds.clear();
+        pollfds.extend(fd_info.iter()
+            .filter(|(_, f)| !f.waiters.is_empty())
+            .map(|(fd, f)| libc::pollfd{ fd: *fd, events: f.poll_bits(), revents: 0 }));
 
         // Run the poll system call.
-        let mut todo = match syscall::poll(&mut self.pollfds, timeout) {
+        let mut todo = match syscall::poll(pollfds, timeout) {
             Ok(n) => n,
             Err(_) => return,
         };
 
         // Find all waiters with matching interest.
-        for i in 0 .. self.pollfds.len() {
-
-            let pollfd = &mut self.pollfds[i];
+        for pollfd in pollfds.iter() {
+            if todo == 0 {
+                break;
+            }
             if pollfd.revents != 0 {
-
                 // An event happened on this fd.
-                let fd_waiters = &mut self.fd_info[i];
-
-                let waiters = fd_waiters
-                    .waiters
-                    .drain(..)
-                    .filter_map(|w| {
-                        // See if this waiter is interested.
-                        let active = (w.interest as u32 | INTERESTING) & pollfd.revents as u32;
-                        if active != 0 {
-                            // Yes, wakeup, and remove.
-                            w.waker.wake();
-                            None
-                        } else {
-                            // No, keep.
-                            Some(w)
-                        }
-                    }).collect::<Vec<_>>();
-
-                // Put back any left over waiters.
-                fd_waiters.waiters = waiters;
-
-                if fd_waiters.waiters.len() > 0 {
-                    // We still have waiters, so calculate new events bits.
-                    pollfd.events = fd_waiters.poll_bits().try_into().unwrap();
-                } else {
-                    // No active waiters, so let poll() ignore this fd.
-                    pollfd.events = 0;
-                    pollfd.fd = -pollfd.fd;
+                if let Some(fd_waiters) = fd_info.get_mut(&pollfd.fd) {
+                    fd_waiters.wake(pollfd.revents);
                 }
-                pollfd.revents = 0;
-
                 todo -= 1;
-                if todo == 0 {
-                    break;
-                }
             }
         }
     }
 
-    // Find the filedescriptor, starting at 'reg.fd_index' and then going back
-    // from there. This works because we only ever remove entries from the
-    // middle of the Vec, and we only ever add them to the end. If the index
-    // changed, update the value in `reg` for the next lookup.
-    fn fd_index(&self, reg: &Registration, do_update: bool) -> usize {
-        let index_hint = reg.fd_index.get();
-        let start = std::cmp::min(self.pollfds.len(), index_hint + 1);
-        for idx in (0 .. start).rev() {
-            if self.pollfds[idx].fd.abs() == reg.fd {
-                if idx != index_hint && do_update {
-                    reg.fd_index.set(idx);
-                }
-                return idx;
-            }
-        }
-        panic!("cannot find file descriptor {} registered with the reactor", reg.fd);
+    // The waiters of a registered fd. O(1), and since a Registration keeps
+    // its fd registered until it is dropped, the fd is always there.
+    fn fd_waiters(&self, reg: &Registration) -> &FdWaiters {
+        self.fd_info.get(&reg.fd).unwrap_or_else(|| not_registered(reg.fd))
+    }
+
+    fn fd_waiters_mut(&mut self, reg: &Registration) -> &mut FdWaiters {
+        self.fd_info.get_mut(&reg.fd).unwrap_or_else(|| not_registered(reg.fd))
     }
 
     // Register a file descriptor to be monitored.
-    fn register_fd(&mut self, fd: RawFd) -> usize {
+    fn register_fd(&mut self, fd: RawFd) -> std::io::Result<()> {
+
+        if fd < 0 {
+            let msg = format!("cannot register invalid fd {}", fd);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+        }
 
         // See if we can find 'fd' already registered.
-        if let Some((idx, _)) = self.pollfds.iter().enumerate().find(|(_, p)| p.fd == fd) {
+        if let Some(fd_waiters) = self.fd_info.get_mut(&fd) {
             // Already have it, just increase refcount.
-            self.fd_info[idx].refcount += 1;
-            idx
-        } else {
-            // Need to add this file descriptor.
-            self.pollfds.push(libc::pollfd{ fd: -fd, events: 0, revents: 0 });
-            self.fd_info.push(FdWaiters{ refcount: 1, waiters: Vec::new() });
-            self.fd_info.len() - 1
+            fd_waiters.refcount += 1;
+            return Ok(());
+        }
+
+        // Need to add this file descriptor. Don't abort if we can't.
+        let oom = |_| std::io::Error::from(std::io::ErrorKind::OutOfMemory);
+        self.fd_info.try_reserve(1).map_err(oom)?;
+        let mut fd_waiters = FdWaiters{ refcount: 1, ..FdWaiters::default() };
+        match &mut self.backend {
+            Backend::Poll(pollfds) => pollfds.try_reserve(self.fd_info.len() + 1).map_err(oom)?,
+            #[cfg(target_os = "linux")]
+            Backend::Epoll(epoll) => epoll.register(fd, &mut fd_waiters)?,
         }
+        self.fd_info.insert(fd, fd_waiters);
+        Ok(())
     }
 
     // Deregister file descriptor.
     fn deregister_fd(&mut self, reg: &Registration) {
-        let idx = self.fd_index(reg, false);
-        if self.fd_info[idx].refcount == 1 {
+        let fd_waiters = self.fd_waiters_mut(reg);
+        if fd_waiters.refcount == 1 {
             // Last reference, so remove it from the reactor.
-            self.pollfds.remove(idx);
-            self.fd_info.remove(idx);
+            self.fd_info.remove(&reg.fd);
+            #[cfg(target_os = "linux")]
+            if let Backend::Epoll(epoll) = &self.backend {
+                epoll.deregister(reg.fd);
+            }
         } else {
             // Just decrements refcount.
-            self.fd_info[idx].refcount -= 1;
+            fd_waiters.refcount -= 1;
         }
     }
 
     // Request to be woken up when event of interest happens on fd.
-    fn add_wake_when(&mut self, reg: &Registration, interest: Interest, waker: Waker) {
-        let idx = self.fd_index(reg, true);
+    // Returns the id of the waiter.
+    fn add_wake_when(&mut self, reg: &Registration, interest: Interest, waker: Waker) -> u64 {
+        let wait_id = self.next_id;
+        self.next_id += 1;
+        // Not fd_waiters_mut(), we need to borrow `backend` as well.
+        let fd_waiters = self.fd_info.get_mut(&reg.fd).unwrap_or_else(|| not_registered(reg.fd));
+        // Never poll an invalid fd again, wake up right away instead.
+        if fd_waiters.invalid || fd_waiters.always_ready {
+            waker.wake();
+            return wait_id;
+        }
         // Add the waiter to the list, and update events to listen for.
-        self.fd_info[idx].waiters.push(FdWaiter{ interest, reg_id: reg.id, waker });
-        self.pollfds[idx].events = self.fd_info[idx].poll_bits().try_into().unwrap();
-        self.pollfds[idx].revents = 0;
-        self.pollfds[idx].fd = reg.fd;
+        fd_waiters.waiters.push(FdWaiter{ interest, reg_id: reg.id, wait_id, waker });
+        #[cfg(target_os = "linux")]
+        if let Backend::Epoll(epoll) = &self.backend {
+            epoll.arm(reg.fd, fd_waiters);
+        }
+        wait_id
+    }
+
+    // Is the waiter still waiting? If so, update its waker.
+    fn refresh_waiter(&mut self, reg: &Registration, wait_id: u64, waker: &Waker) -> bool {
+        match self.fd_waiters_mut(reg).waiters.iter_mut().find(|w| w.wait_id == wait_id) {
+            Some(w) => {
+                if !w.waker.will_wake(waker) {
+                    w.waker.clone_from(waker);
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Remove one waiter.
+    //
+    // With epoll, the fd might stay armed for events that nobody waits
+    // for anymore. That causes at most one spurious event.
+    fn remove_waiter(&mut self, reg: &Registration, wait_id: u64) {
+        self.fd_waiters_mut(reg).waiters.remove_if(|w| w.wait_id == wait_id);
     }
 
     // Remove waker.
     fn remove_wake_when(&mut self, reg: &Registration, interest: Interest) {
-        let idx = self.fd_index(reg, true);
-        self.fd_info[idx].waiters.retain(|w| w.reg_id != reg.id && w.interest != interest);
-        self.pollfds[idx].events = self.fd_info[idx].poll_bits().try_into().unwrap();
+        self.fd_waiters_mut(reg).waiters.remove_if(|w| w.reg_id == reg.id && w.interest == interest);
+    }
+
+    // Error if poll() reported the fd as invalid.
+    fn check_valid(&self, reg: &Registration) -> std::io::Result<()> {
+        if self.fd_waiters(reg).invalid {
+            let msg = format!("fd {} was closed while registered with the reactor (POLLNVAL)", reg.fd);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+        }
+        Ok(())
+    }
+
+    // The events of `interest` that the last wakeup reported for the fd.
+    // An error or hangup makes it ready for everything.
+    fn readiness(&self, reg: &Registration, interest: Interest) -> i16 {
+        let fd_waiters = self.fd_waiters(reg);
+        if fd_waiters.always_ready {
+            return interest.0;
+        }
+        let mut revents = fd_waiters.revents;
+        if revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+            revents |= interest.0;
+        }
+        revents & interest.0
+    }
+
+    // With epoll, probing the fd before waiting would cost an extra syscall.
+    // Arming an fd that is already ready reports it on the next turn anyway.
+    fn probe_first(&self) -> bool {
+        matches!(self.backend, Backend::Poll(_))
     }
 
     // Check for spurious wakeup.
-    fn was_woken(&self, reg: &Registration) -> bool {
-        // If we have an entry with our registration id, we weren't woken up!
-        let idx = self.fd_index(reg, true);
-        self.fd_info[idx].waiters.iter().find(|w| w.reg_id == reg.id).is_none()
+    fn was_woken(&self, reg: &Registration, interest: Interest) -> bool {
+        // If we have an entry with our registration id and interest, we weren't woken up!
+        !self.fd_waiters(reg).waiters.iter().any(|w| w.reg_id == reg.id && w.interest == interest)
     }
 }
 
+#[cold]
+fn not_registered(fd: RawFd) -> ! {
+    panic!("cannot find file descriptor {} registered with the reactor", fd);
+}
+
 // A filedescriptor handle with connection to the Reactor.
 pub struct Registration {
     id:         u64,
     fd:         RawFd,
-    fd_index:   Cell<usize>,
     reactor:    Weak<RefCell<InnerReactor>>,
 }
 
 impl Registration {
-    pub fn new(fd: RawFd) -> Registration {
+    // Register `fd` with the reactor of the current runtime. Fails if
+    // there is no runtime, or if the fd can not be registered.
+    pub fn new(fd: RawFd) -> std::io::Result<Registration> {
         REACTOR.with_borrow(|inner| {
-            let inner = inner.upgrade().unwrap();
+            let Some(inner) = inner.upgrade() else {
+                let msg = "not in a nara runtime context";
+                return Err(std::io::Error::other(msg));
+            };
             Registration::new_with_reactor(fd, &inner)
         })
     }
 
-    fn new_with_reactor(fd: RawFd, inner: &Rc<RefCell<InnerReactor>>) -> Registration {
+    fn new_with_reactor(fd: RawFd, inner: &Rc<RefCell<InnerReactor>>) -> std::io::Result<Registration> {
         let mut inner2 = inner.borrow_mut();
+        inner2.register_fd(fd)?;
         let id = inner2.next_id;
         inner2.next_id += 1;
-        Registration {
+        Ok(Registration {
             id,
             fd,
-            fd_index: Cell::new(inner2.register_fd(fd)),
             reactor: Rc::downgrade(inner),
-        }
+        })
     }
 
     pub fn wake_when(&self, interest: Interest, waker: Waker) {
@@ -263,27 +566,74 @@ impl Registration {
         inner.borrow_mut().remove_wake_when(self, interest);
     }
 
-    pub fn was_woken(&self) -> bool {
+    pub fn was_woken(&self, interest: Interest) -> bool {
         let inner = self.reactor.upgrade().unwrap();
-        let res = inner.borrow().was_woken(self);
+        let res = inner.borrow().was_woken(self, interest);
         res
     }
 
-    pub async fn write_ready(&self) {
-        FdReady { reg: self, has_no_waker: true, interest: Interest::Write }.await;
+    // Returns an error if the fd was found to be invalid.
+    pub fn check_valid(&self) -> std::io::Result<()> {
+        let inner = self.reactor.upgrade().unwrap();
+        let res = inner.borrow().check_valid(self);
+        res
+    }
+
+    pub async fn read_ready(&self) -> std::io::Result<()> {
+        FdReady { reg: self, wait_id: None, interest: Interest::READ }.await;
+        self.check_valid()
+    }
+
+    pub async fn write_ready(&self) -> std::io::Result<()> {
+        FdReady { reg: self, wait_id: None, interest: Interest::WRITE }.await;
+        self.check_valid()
+    }
+
+    // Wait until the fd is ready for any of `interest`.
+    pub async fn ready(&self, interest: Interest) -> std::io::Result<Readiness> {
+        self.check_valid()?;
+        // Check first, it might be ready already.
+        if self.reactor.upgrade().unwrap().borrow().probe_first() {
+            let mut pollfd = [libc::pollfd { fd: self.fd, events: interest.0, revents: 0 }];
+            if syscall::poll(&mut pollfd, Some(Duration::ZERO))? > 0 {
+                let mut revents = pollfd[0].revents;
+                if revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                    revents |= interest.0;
+                }
+                let ready = revents & interest.0;
+                if ready != 0 {
+                    return Ok(Readiness(ready));
+                }
+            }
+        }
+        loop {
+            FdReady { reg: self, wait_id: None, interest }.await;
+            self.check_valid()?;
+            // Use what the reactor saw, instead of asking the kernel again.
+            let ready = self.reactor.upgrade().unwrap().borrow().readiness(self, interest);
+            if ready != 0 {
+                return Ok(Readiness(ready));
+            }
+        }
     }
 }
 
 impl Drop for Registration {
     fn drop(&mut self) {
-        self.reactor.upgrade().map(|r| r.borrow_mut().deregister_fd(&self));
+        if let Some(r) = self.reactor.upgrade() {
+            r.borrow_mut().deregister_fd(self);
+        }
     }
 }
 
 // Implement as struct, so that we can implement Drop on the struct.
+//
+// Each FdReady has its own waiter in the reactor, so several of them can wait
+// on the same Registration (e.g. concurrent accepts on a shared listener), and
+// dropping one only removes its own waiter.
 struct FdReady<'a> {
     reg: &'a Registration,
-    has_no_waker: bool,
+    wait_id: Option<u64>,
     interest: Interest,
 }
 
@@ -296,22 +646,26 @@ impl<'a> std::future::Future for FdReady<'a> {
         let this = self.get_mut();
         let reactor = this.reg.reactor.upgrade().unwrap();
         let mut reactor = reactor.borrow_mut();
-        if !reactor.was_woken(this.reg) {
-            return Poll::Pending;
+        match this.wait_id {
+            None => {
+                this.wait_id = Some(reactor.add_wake_when(this.reg, this.interest, cx.waker().clone()));
+                Poll::Pending
+            },
+            Some(wait_id) if reactor.refresh_waiter(this.reg, wait_id, cx.waker()) => Poll::Pending,
+            Some(_) => {
+                this.wait_id = None;
+                Poll::Ready(())
+            },
         }
-        if std::mem::take(&mut this.has_no_waker) {
-            reactor.add_wake_when(this.reg, this.interest, cx.waker().clone());
-            return Poll::Pending;
-        }
-        this.has_no_waker = true;
-        Poll::Ready(())
     }
 }
 
 impl<'a> Drop for FdReady<'a> {
     fn drop(&mut self) {
-        if !self.has_no_waker {
-            self.reg.remove_wake_when(self.interest);
+        if let Some(wait_id) = self.wait_id {
+            if let Some(reactor) = self.reg.reactor.upgrade() {
+                reactor.borrow_mut().remove_waiter(self.reg, wait_id);
+            }
         }
     }
 }
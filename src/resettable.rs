@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use std::sync::mpsc::{RecvError, SendError};
+
+// Shared slot.
+struct Slot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    generation: u64,
+    sender_gone: bool,
+    // The Resettable is gone.
+    closed: bool,
+}
+
+/// A oneshot channel that can be reused.
+///
+/// After the value has been received, `reset()` hands out a new sender for
+/// the next round, without allocating a new channel. A sender from an earlier
+/// round can no longer send; its `send` returns an error.
+pub struct Resettable<T> {
+    slot: Rc<RefCell<Slot<T>>>,
+}
+
+/// Sender side of a `Resettable`. Only valid for one round.
+pub struct ResettableSender<T> {
+    slot: Rc<RefCell<Slot<T>>>,
+    generation: u64,
+}
+
+impl<T> Resettable<T> {
+    /// Create a new `Resettable` and the sender for the first round.
+    pub fn new() -> (Resettable<T>, ResettableSender<T>) {
+        let slot = Rc::new(RefCell::new(Slot {
+            value: None,
+            waker: None,
+            generation: 1,
+            sender_gone: false,
+            closed: false,
+        }));
+        let tx = ResettableSender { slot: slot.clone(), generation: 1 };
+        (Resettable { slot }, tx)
+    }
+
+    /// Receive the value of this round.
+    ///
+    /// Returns an error if the sender was dropped without sending a value,
+    /// or if the value was already received.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        std::future::poll_fn(|cx: &mut Context<'_>| {
+            let mut slot = self.slot.borrow_mut();
+            if let Some(value) = slot.value.take() {
+                return Poll::Ready(Ok(value));
+            }
+            if slot.sender_gone {
+                return Poll::Ready(Err(RecvError));
+            }
+            if let Some(w) = slot.waker.as_mut() {
+                w.clone_from(cx.waker());
+            } else {
+                slot.waker.replace(cx.waker().clone());
+            }
+            Poll::Pending
+        }).await
+    }
+
+    /// Start a new round, returning the sender for it.
+    ///
+    /// A value that was sent but not received yet is dropped. Senders from
+    /// previous rounds are invalidated.
+    pub fn reset(&mut self) -> ResettableSender<T> {
+        let mut slot = self.slot.borrow_mut();
+        slot.generation += 1;
+        slot.value = None;
+        slot.sender_gone = false;
+        ResettableSender { slot: self.slot.clone(), generation: slot.generation }
+    }
+}
+
+impl<T> std::fmt::Debug for Resettable<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Resettable");
+        if let Ok(slot) = self.slot.try_borrow() {
+            d.field("generation", &slot.generation);
+            d.field("has_value", &slot.value.is_some());
+        }
+        d.finish()
+    }
+}
+
+impl<T> Drop for Resettable<T> {
+    fn drop(&mut self) {
+        let mut slot = self.slot.borrow_mut();
+        slot.closed = true;
+        slot.value = None;
+    }
+}
+
+impl<T> ResettableSender<T> {
+    /// Send the value. Fails if the `Resettable` was reset or dropped since
+    /// this sender was handed out.
+    pub fn send(self, value: T) -> Result<(), SendError<T>> {
+        let mut slot = self.slot.borrow_mut();
+        if slot.generation != self.generation || slot.closed {
+            return Err(SendError(value));
+        }
+        slot.value = Some(value);
+        if let Some(w) = slot.waker.take() {
+            w.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> std::fmt::Debug for ResettableSender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResettableSender").field("generation", &self.generation).finish()
+    }
+}
+
+impl<T> Drop for ResettableSender<T> {
+    fn drop(&mut self) {
+        let mut slot = self.slot.borrow_mut();
+        if slot.generation == self.generation {
+            // Let the receiver know this round is over.
+            slot.sender_gone = true;
+            if let Some(w) = slot.waker.take() {
+                w.wake();
+            }
+        }
+    }
+}
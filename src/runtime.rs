@@ -3,9 +3,11 @@ use std::future::Future;
 use std::io;
 use std::rc::{self, Rc};
 use std::thread_local;
+use std::time::Duration;
 
-use crate::executor::Executor;
+use crate::executor::{Executor, InnerExecutor, TaskEventHook};
 use crate::reactor::Reactor;
+use crate::task::{JoinHandle, LocalJoinHandle, SpawnError, TaskEvent};
 use crate::time::Timer;
 
 /// Nara Runtime.
@@ -15,58 +17,407 @@ pub struct Runtime {
 
 // thread local reference to the inner runtime.
 thread_local! {
-    pub(crate) static EXECUTOR: RefCell<rc::Weak<Executor>> = RefCell::new(rc::Weak::new());
+    pub(crate) static EXECUTOR: RefCell<rc::Weak<Executor>> = const { RefCell::new(rc::Weak::new()) };
+}
+
+impl std::fmt::Debug for Runtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Runtime").finish_non_exhaustive()
+    }
 }
 
 impl Runtime {
     /// Create a new nara Runtime.
+    ///
+    /// Same as `new_current_thread`.
     pub fn new() -> io::Result<Runtime> {
-        let reactor = Reactor::new();
-        let timer = Timer::new();
-        let executor = Rc::new(Executor::new(reactor, timer));
-        Ok(Runtime { executor })
+        Self::new_current_thread()
+    }
+
+    /// Create a new single-threaded Runtime.
+    ///
+    /// All tasks run on the thread that calls `block_on`, so futures passed
+    /// to `spawn` and `block_on` do not need to be `Send`.
+    pub fn new_current_thread() -> io::Result<Runtime> {
+        Builder::new().build()
+    }
+
+    /// Create a new Runtime with `workers` worker threads.
+    ///
+    /// Reserved for a future multi-threaded runtime. That will require
+    /// spawned futures to be `Send + 'static`. Currently this always returns
+    /// an `io::ErrorKind::Unsupported` error; use `new_current_thread`.
+    pub fn new_multi_thread(workers: usize) -> io::Result<Runtime> {
+        let _ = workers;
+        Err(io::Error::new(io::ErrorKind::Unsupported, "multi-threaded runtime is not supported"))
     }
 
     /// Run a future on the executor.
+    ///
+    /// On a current-thread runtime, the future does not have to be `Send`
+    /// or `'static`, it is polled on the calling thread.
     pub fn block_on<F: Future>(&self, fut: F) -> F::Output {
         let _guard = self.enter();
         self.executor.block_on(fut)
     }
 
+    /// Like `block_on`, but returns `Err(Stalled)` instead of blocking forever
+    /// if the future can never complete.
+    ///
+    /// That is the case if the future is pending, and no task is ready to run,
+    /// waiting for I/O, a timer, or a blocking task. Useful in tests, to turn a
+    /// hang into an error. Wakeups from threads outside of the runtime are not
+    /// accounted for.
+    pub fn block_on_until_idle<F: Future>(&self, fut: F) -> Result<F::Output, Stalled> {
+        let _guard = self.enter();
+        self.executor.block_on_until_idle(fut).ok_or(Stalled)
+    }
+
+    /// Run one iteration of the scheduler.
+    ///
+    /// This runs all tasks that are ready, then waits at most `timeout` for
+    /// I/O or timers (`None` means: until something happens), and runs the
+    /// expired timers. Returns `true` if tasks were woken up and are ready
+    /// to run, in which case `turn` should be called again soon.
+    ///
+    /// This can be used to drive the runtime from an external event loop.
+    /// Tasks must be started with `spawn` from within an `enter()` context.
+    pub fn turn(&self, timeout: Option<Duration>) -> bool {
+        let _guard = self.enter();
+        self.executor.turn(timeout)
+    }
+
+    /// Start `n` threads for `spawn_blocking` right away, instead of on demand.
+    ///
+    /// This avoids the thread creation latency for the first burst of blocking
+    /// tasks. The number of threads is capped at the pool's maximum, and idle
+    /// threads still exit after a while, one by one.
+    pub fn prewarm_blocking_threads(&self, n: usize) {
+        self.executor.pool().prewarm(n);
+    }
+
+    /// Return a handle to this runtime.
+    pub fn handle(&self) -> Handle {
+        Handle { executor: self.executor.downgrade() }
+    }
+
     /// Activate the runtime context. Returns an `EnterGuard`.
     ///
     /// This is only needed to initialize objects like `TcpSocket`s that need an
     /// active runtime context while you're not within `Runtime::block_on`.
     /// This context is deactivated once the `EnterGuard is dropped, or after
     /// `Runtime::block_on` exits.
-    pub fn enter(&self) -> EnterGuard {
+    pub fn enter(&self) -> EnterGuard<'_> {
         EnterGuard::new(self)
     }
 }
 
+/// Builds a `Runtime` with custom settings.
+pub struct Builder {
+    on_task_event: Option<TaskEventHook>,
+    cross_thread_wake: bool,
+    schedule_order: ScheduleOrder,
+    reactor_backend: ReactorBackend,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            on_task_event: None,
+            cross_thread_wake: true,
+            schedule_order: ScheduleOrder::default(),
+            reactor_backend: ReactorBackend::default(),
+        }
+    }
+}
+
+/// The system call the reactor uses to wait for I/O events.
+///
+/// Set with `Builder::reactor_backend`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReactorBackend {
+    /// poll(2). Works on every unix, but passes all file descriptors that
+    /// are waited on to the kernel on every call. This is the default.
+    #[default]
+    Poll,
+    /// epoll(7). Only returns the file descriptors that are ready, so it
+    /// scales better to many connections.
+    ///
+    /// Unlike with poll(2), a file descriptor that is closed while a task
+    /// is waiting on it is not detected: the task is never woken up.
+    #[cfg(target_os = "linux")]
+    Epoll,
+}
+
+/// The order in which tasks that are ready to run are polled.
+///
+/// Set with `Builder::schedule_order`. Correct code does not depend on the
+/// order; changing it is a way to find code that does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScheduleOrder {
+    /// The task that was woken last runs first. This is the default.
+    #[default]
+    Lifo,
+    /// Tasks run in the order in which they were woken.
+    Fifo,
+    /// Tasks run in a random order, determined by the seed. The same
+    /// seed gives the same order, as long as the tasks are woken in the
+    /// same order, so a failing run can be reproduced.
+    Random(u64),
+}
+
+impl Builder {
+    /// Return a new Builder with default settings.
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// Allow tasks to be woken from other threads (default: `true`).
+    ///
+    /// This needs an eventfd (on Linux) or a pipe, which costs two file
+    /// descriptors, one of which the reactor always waits on. A purely
+    /// single-threaded program can turn it off.
+    ///
+    /// With it turned off, waking a task from another thread panics, and
+    /// so do `task::spawn_blocking` and its variants, because the blocking
+    /// threads wake up tasks. `Handle::spawn_blocking` returns
+    /// `SpawnError::CrossThreadWakeDisabled` instead. Note that `net`
+    /// resolves hostnames with `spawn_blocking`, unless a resolver was set
+    /// with `net::set_resolver`.
+    pub fn allow_cross_thread_wake(mut self, allow: bool) -> Builder {
+        self.cross_thread_wake = allow;
+        self
+    }
+
+    /// Set a hook that is called on every task state transition.
+    ///
+    /// The hook is called synchronously from the executor, so it should
+    /// be fast.
+    pub fn on_task_event<F>(mut self, f: F) -> Builder
+    where
+        F: Fn(TaskEvent<'_>) + 'static,
+    {
+        self.on_task_event = Some(Box::new(f));
+        self
+    }
+
+    /// Set the order in which ready tasks are polled (default: `ScheduleOrder::Lifo`).
+    ///
+    /// Meant for testing: with `ScheduleOrder::Random(seed)`, running a test
+    /// with different seeds surfaces bugs that depend on task ordering.
+    pub fn schedule_order(mut self, order: ScheduleOrder) -> Builder {
+        self.schedule_order = order;
+        self
+    }
+
+    /// Set the reactor backend (default: `ReactorBackend::Poll`).
+    ///
+    /// On Linux, `ReactorBackend::Epoll` is faster for servers with many
+    /// connections.
+    pub fn reactor_backend(mut self, backend: ReactorBackend) -> Builder {
+        self.reactor_backend = backend;
+        self
+    }
+
+    /// Build the `Runtime`.
+    pub fn build(self) -> io::Result<Runtime> {
+        let reactor = Reactor::new(self.reactor_backend)?;
+        let timer = Timer::new();
+        let executor = Rc::new(Executor::new(
+            reactor,
+            timer,
+            self.on_task_event,
+            self.cross_thread_wake,
+            self.schedule_order,
+        ));
+        Ok(Runtime { executor })
+    }
+}
+
+impl std::fmt::Debug for Builder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Builder")
+            .field("on_task_event", &self.on_task_event.is_some())
+            .field("cross_thread_wake", &self.cross_thread_wake)
+            .field("schedule_order", &self.schedule_order)
+            .field("reactor_backend", &self.reactor_backend)
+            .finish()
+    }
+}
+
+/// Handle to a runtime.
+///
+/// Unlike the free `spawn` functions, spawning through a `Handle` does not
+/// panic if the runtime is gone, but returns `SpawnError::RuntimeShuttingDown`.
+///
+/// Channels need no handle: when the runtime is dropped, so are its tasks
+/// and the channel ends they own. A sender on another thread then gets
+/// a `SendError`, as with any other closed channel.
+#[derive(Clone, Debug)]
+pub struct Handle {
+    executor: rc::Weak<InnerExecutor>,
+}
+
+impl Handle {
+    /// Return a handle to the current runtime.
+    ///
+    /// Panics if not called from within a runtime context.
+    pub fn current() -> Handle {
+        match Handle::try_current() {
+            Some(handle) => handle,
+            None => panic!("Handle::current: not in a nara runtime context"),
+        }
+    }
+
+    /// Return a handle to the current runtime, or `None` if not called
+    /// from within a runtime context.
+    pub fn try_current() -> Option<Handle> {
+        let executor = crate::executor::EXECUTOR.with_borrow(|e| e.clone());
+        executor.upgrade()?;
+        Some(Handle { executor })
+    }
+
+    /// Spawn a task on the runtime.
+    pub fn spawn<F, T>(&self, fut: F) -> Result<LocalJoinHandle<T>, SpawnError>
+    where
+        F: Future<Output = T> + 'static,
+        T: 'static,
+    {
+        let executor = self.executor.upgrade().ok_or(SpawnError::RuntimeShuttingDown)?;
+        Ok(executor.spawn(fut))
+    }
+
+    /// Run a blocking closure on the runtime's threadpool.
+    ///
+    /// Fails with `SpawnError::CrossThreadWakeDisabled` if the runtime was
+    /// built with `allow_cross_thread_wake(false)`.
+    pub fn spawn_blocking<F, R>(&self, f: F) -> Result<JoinHandle<R>, SpawnError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let executor = self.executor.upgrade().ok_or(SpawnError::RuntimeShuttingDown)?;
+        let pool = executor.try_blocking_pool().ok_or(SpawnError::CrossThreadWakeDisabled)?;
+        Ok(pool.spawn(f))
+    }
+}
+
+/// Return a handle to the current runtime, or `None` if not called
+/// from within a runtime context. Same as `Handle::try_current`.
+pub fn try_current() -> Option<Handle> {
+    Handle::try_current()
+}
+
+/// Are we running within a runtime context?
+///
+/// If so, `spawn` and the other free functions in `task` can be used.
+pub fn in_context() -> bool {
+    crate::executor::EXECUTOR.with_borrow(|e| e.strong_count() > 0)
+}
+
+// A job sent from a blocking thread, to be run on the runtime thread.
+type BlockingJob = Box<dyn FnOnce() + Send>;
+
+/// Handle to a runtime, for use on a blocking thread.
+///
+/// Passed to the closure of `task::spawn_blocking_with_handle`. It can be
+/// cloned and sent to other threads.
+#[derive(Clone, Debug)]
+pub struct BlockingHandle {
+    tx: crate::sync::mpsc::UnboundedSender<BlockingJob>,
+}
+
+impl BlockingHandle {
+    // Create a BlockingHandle, and spawn the task on the current runtime
+    // that runs the futures passed to `block_on`.
+    pub(crate) fn new() -> BlockingHandle {
+        let (tx, mut rx) = crate::sync::mpsc::unbounded_channel::<BlockingJob>();
+        crate::task::spawn(async move {
+            while let Some(job) = rx.recv().await {
+                job();
+            }
+        });
+        BlockingHandle { tx }
+    }
+
+    /// Run a future on the runtime and wait for its output.
+    ///
+    /// The future is spawned as a task on the runtime thread, while the
+    /// calling thread blocks until the task has finished.
+    ///
+    /// Deadlocks: the runtime thread must be free to run the future. Never
+    /// call this from the runtime thread itself (that panics), and be careful
+    /// when the runtime thread is waiting on this blocking task, for example
+    /// in a blocking call of its own. Also note that every blocked call keeps
+    /// a threadpool thread busy.
+    ///
+    /// Returns `SpawnError::RuntimeShuttingDown` if the runtime went away
+    /// before the future completed.
+    pub fn block_on<F>(&self, fut: F) -> Result<F::Output, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        if crate::executor::EXECUTOR.with_borrow(|e| e.upgrade().is_some()) {
+            panic!("BlockingHandle::block_on: called from a runtime thread");
+        }
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        let job = Box::new(move || {
+            crate::task::spawn(async move {
+                let _ = tx.send(fut.await);
+            });
+        });
+        self.tx.send(job).map_err(|_| SpawnError::RuntimeShuttingDown)?;
+        rx.recv().map_err(|_| SpawnError::RuntimeShuttingDown)
+    }
+}
+
+/// Error returned by `Runtime::block_on_until_idle` if the future can
+/// never complete.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stalled;
+
+impl std::fmt::Display for Stalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "runtime stalled: no task can make progress")
+    }
+}
+
+impl std::error::Error for Stalled {}
+
 // Creating an EnterGuard puts a Weak pointer to the inner executor in
 // the thread-local EXECUTOR. As soon as the EnterGuard is dropped the
 // reference is removed again. So only when holding an EnterGuard, or
 // when calling block_on(), is the runtime context active.
+//
+// Guards can nest (say, `turn()` while holding `enter()`). Only the
+// outermost one deactivates the context when it is dropped.
+#[derive(Debug)]
 pub struct EnterGuard<'a> {
     lifetime: std::marker::PhantomData<&'a Runtime>,
+    nested: bool,
 }
 
 impl<'a> EnterGuard<'a> {
     // The EnterGuard has a lifetime that's tied to the Runtime.
-    fn new(runtime: &'a Runtime) -> EnterGuard {
-        EXECUTOR.with_borrow_mut(|rt| {
+    fn new(runtime: &'a Runtime) -> EnterGuard<'a> {
+        let nested = EXECUTOR.with_borrow_mut(|rt| {
             if let Some(rt) = rt.upgrade() {
                 if !Rc::ptr_eq(&runtime.executor, &rt) {
                     panic!("already in a runtime context!");
                 }
+                true
             } else {
                 *rt = Rc::downgrade(&runtime.executor);
+                false
             }
         });
-        runtime.executor.activate();
+        if !nested {
+            runtime.executor.activate();
+        }
         EnterGuard {
             lifetime: std::marker::PhantomData,
+            nested,
         }
     }
 }
@@ -74,8 +425,13 @@ impl<'a> EnterGuard<'a> {
 // This makes sure all resources get released.
 impl<'a> Drop for EnterGuard<'a> {
     fn drop(&mut self) {
+        if self.nested {
+            return;
+        }
         EXECUTOR.with_borrow_mut(|rt| {
-            rt.upgrade().map(|rt| rt.deactivate());
+            if let Some(rt) = rt.upgrade() {
+                rt.deactivate();
+            }
             *rt = rc::Weak::new();
         });
     }
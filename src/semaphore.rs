@@ -0,0 +1,152 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::waiters::{WaitQueue, WaiterGuard};
+
+// Shared semaphore state.
+struct Inner {
+    permits: usize,
+    // Waiters, with the number of permits they want.
+    waiters: WaitQueue<usize>,
+}
+
+impl Inner {
+    // Number of permits wanted by the first `n` waiters.
+    fn wanted(&self, n: usize) -> usize {
+        self.waiters.iter().take(n).map(|w| w.data).sum()
+    }
+
+    // Wake the waiters at the front of the queue that can get their permits.
+    fn wake_waiters(&self) {
+        let mut permits = self.permits;
+        for w in self.waiters.iter() {
+            if w.data > permits {
+                break;
+            }
+            permits -= w.data;
+            w.waker.wake_by_ref();
+        }
+    }
+
+    // Remove waiter `id`, and let the waiters behind it have a go.
+    fn remove_waiter(&mut self, id: u64) {
+        if self.waiters.remove(id).is_some() {
+            self.wake_waiters();
+        }
+    }
+
+    // Take `n` permits if they are available and nobody is waiting for them.
+    fn try_acquire(&mut self, n: usize) -> bool {
+        if self.waiters.is_empty() && n <= self.permits {
+            self.permits -= n;
+            return true;
+        }
+        false
+    }
+}
+
+/// A semaphore, to limit the number of tasks that do something at the
+/// same time.
+///
+/// Waiters are served in FIFO order: a waiter that wants more permits than
+/// are available holds up the waiters behind it.
+pub struct Semaphore {
+    inner: Rc<RefCell<Inner>>,
+}
+
+/// Permits from a `Semaphore`. They are released when this is dropped.
+pub struct SemaphorePermit {
+    inner: Rc<RefCell<Inner>>,
+    permits: usize,
+}
+
+impl Semaphore {
+    /// Create a new semaphore with `permits` permits.
+    pub fn new(permits: usize) -> Semaphore {
+        let inner = Inner { permits, waiters: WaitQueue::new() };
+        Semaphore { inner: Rc::new(RefCell::new(inner)) }
+    }
+
+    /// Number of permits that are available right now.
+    pub fn available_permits(&self) -> usize {
+        self.inner.borrow().permits
+    }
+
+    /// Add `n` permits to the semaphore.
+    pub fn add_permits(&self, n: usize) {
+        let mut inner = self.inner.borrow_mut();
+        inner.permits += n;
+        inner.wake_waiters();
+    }
+
+    /// Wait for a permit.
+    pub async fn acquire(&self) -> SemaphorePermit {
+        self.acquire_many(1).await
+    }
+
+    /// Wait for `n` permits.
+    ///
+    /// Waits forever if `n` is more than the semaphore will ever have.
+    pub async fn acquire_many(&self, n: usize) -> SemaphorePermit {
+        let id = self.inner.borrow_mut().waiters.next_id();
+        // If this future is dropped while waiting, give up our place in the queue.
+        let _guard = WaiterGuard::new(id, &self.inner, Inner::remove_waiter);
+        std::future::poll_fn(|cx: &mut Context<'_>| {
+            let mut inner = self.inner.borrow_mut();
+
+            // Take the permits, unless waiters that were there before us need them.
+            let pos = inner.waiters.position(id);
+            if inner.wanted(pos.unwrap_or(inner.waiters.len())) + n <= inner.permits {
+                if pos.is_some() {
+                    inner.waiters.remove(id);
+                }
+                inner.permits -= n;
+                return Poll::Ready(SemaphorePermit { inner: self.inner.clone(), permits: n });
+            }
+
+            // Arrange for us to be woken when permits are released.
+            inner.waiters.register(id, cx.waker(), n);
+            Poll::Pending
+        }).await
+    }
+
+    /// Take a permit if one is available right away and nobody is waiting
+    /// for it.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        self.try_acquire_many(1)
+    }
+
+    /// Like `try_acquire`, for `n` permits.
+    pub fn try_acquire_many(&self, n: usize) -> Option<SemaphorePermit> {
+        if !self.inner.borrow_mut().try_acquire(n) {
+            return None;
+        }
+        Some(SemaphorePermit { inner: self.inner.clone(), permits: n })
+    }
+}
+
+impl std::fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Semaphore");
+        if let Ok(inner) = self.inner.try_borrow() {
+            d.field("permits", &inner.permits);
+            d.field("waiters", &inner.waiters.len());
+        }
+        d.finish()
+    }
+}
+
+impl std::fmt::Debug for SemaphorePermit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SemaphorePermit").field("permits", &self.permits).finish()
+    }
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.permits += self.permits;
+        inner.wake_waiters();
+    }
+}
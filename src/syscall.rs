@@ -12,6 +12,7 @@ fn result(val: isize) -> io::Result<usize> {
     }
 }
 
+#[cfg(not(target_os = "linux"))]
 fn non_blocking(fd: RawFd) {
     // SAFETY: very basic linux system calls, no pointers.
     unsafe {
@@ -20,9 +21,15 @@ fn non_blocking(fd: RawFd) {
     }
 }
 
+// Timeout in milliseconds for poll(2) and epoll_wait(2).
+fn timeout_ms(timeout: Option<Duration>) -> c_int {
+    // Round up, otherwise we wake up just before the deadline and spin.
+    timeout.map(|t| t.as_nanos().div_ceil(1_000_000).clamp(0, c_int::MAX as u128) as c_int).unwrap_or(-1)
+}
+
 pub fn poll(pollfds: &mut [libc::pollfd], timeout: Option<Duration>) -> io::Result<usize> {
 
-    let t = timeout.map(|t| t.as_millis().clamp(0, c_int::MAX as u128) as c_int).unwrap_or(-1);
+    let t = timeout_ms(timeout);
     let nfds = pollfds.len() as libc::nfds_t;
 
     // SAFETY: very basic linux system call.
@@ -32,8 +39,43 @@ pub fn poll(pollfds: &mut [libc::pollfd], timeout: Option<Duration>) -> io::Resu
     result(res as isize)
 }
 
+#[cfg(target_os = "linux")]
+pub fn epoll_create() -> io::Result<std::os::fd::OwnedFd> {
+    // SAFETY: very basic linux system call.
+    let res = unsafe {
+        libc::epoll_create1(libc::EPOLL_CLOEXEC)
+    };
+    // SAFETY: constructing an OwnedFd from the fd we just opened.
+    result(res as isize).map(|fd| unsafe { std::os::fd::OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+// epoll_ctl(2), with `fd` as the user data.
+#[cfg(target_os = "linux")]
+pub fn epoll_ctl(epfd: RawFd, op: c_int, fd: RawFd, events: u32) -> io::Result<()> {
+    let mut event = libc::epoll_event { events, u64: fd as u64 };
+    // SAFETY: very basic linux system call, pointer to a local struct.
+    let res = unsafe {
+        libc::epoll_ctl(epfd, op, fd, &mut event)
+    };
+    result(res as isize).map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+pub fn epoll_wait(epfd: RawFd, events: &mut [libc::epoll_event], timeout: Option<Duration>) -> io::Result<usize> {
+    let t = timeout_ms(timeout);
+    let maxevents = events.len().min(c_int::MAX as usize) as c_int;
+    // SAFETY: very basic linux system call, pointer and length of a slice.
+    let res = unsafe {
+        libc::epoll_wait(epfd, events.as_mut_ptr(), maxevents, t)
+    };
+    result(res as isize)
+}
+
+// Only used for cross-thread wakeups on systems without eventfd.
+//
 // Note that we change this pipe to non-blocking on the read side,
 // but leave it as _blocking_ on the write side!
+#[cfg(not(target_os = "linux"))]
 pub fn pipe() -> io::Result<(File, File)> {
     let mut fds: [libc::c_int; 2] = [0; 2];
     // SAFETY: very basic linux system call.
@@ -48,10 +90,166 @@ pub fn pipe() -> io::Result<(File, File)> {
     result(res as isize).map(|_| files)
 }
 
+// The fds for cross-thread wakeups, (read end, write end). On Linux this is
+// an eventfd(2): both ends are the same counter, and a read returns and
+// clears it, so wakeups coalesce. Elsewhere it is a pipe.
+#[cfg(target_os = "linux")]
+pub fn wakeup_fds() -> io::Result<(File, File)> {
+    // SAFETY: very basic linux system call.
+    let res = unsafe {
+        libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK)
+    };
+    let fd = result(res as isize)?;
+    // SAFETY: constructing a File from the fd we just opened.
+    let rx = unsafe { File::from_raw_fd(fd as RawFd) };
+    let tx = rx.try_clone()?;
+    Ok((rx, tx))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn wakeup_fds() -> io::Result<(File, File)> {
+    pipe()
+}
+
+// Make the read end of `wakeup_fds` readable.
+#[cfg(target_os = "linux")]
+pub fn wakeup(tx: RawFd) {
+    let _ = write(tx, &1u64.to_ne_bytes());
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn wakeup(tx: RawFd) {
+    let _ = write(tx, &[0u8]);
+}
+
 pub fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
     // SAFETY: very basic linux system call.
     let res = unsafe {
         libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len() as libc::size_t)
     };
-    result(res).map(|v| v as usize)
+    result(res)
+}
+
+pub fn setsockopt_int(fd: RawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let len = std::mem::size_of::<c_int>() as libc::socklen_t;
+    // SAFETY: very basic linux system call, pointer to a local c_int.
+    let res = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const c_int as *const libc::c_void, len)
+    };
+    result(res as isize).map(|_| ())
+}
+
+pub fn getsockopt_int(fd: RawFd, level: c_int, name: c_int) -> io::Result<c_int> {
+    let mut value: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+    // SAFETY: very basic linux system call, pointers to a local c_int and its length.
+    let res = unsafe {
+        libc::getsockopt(fd, level, name, &mut value as *mut c_int as *mut libc::c_void, &mut len)
+    };
+    result(res as isize).map(|_| value)
+}
+
+pub fn setsockopt_bytes(fd: RawFd, level: c_int, name: c_int, value: &[u8]) -> io::Result<()> {
+    let len = value.len() as libc::socklen_t;
+    // SAFETY: very basic linux system call, pointer and length of a slice.
+    let res = unsafe {
+        libc::setsockopt(fd, level, name, value.as_ptr() as *const libc::c_void, len)
+    };
+    result(res as isize).map(|_| ())
+}
+
+// Flags for receiving datagrams. On Linux, MSG_TRUNC makes recv return the
+// real length of a datagram that was truncated because the buffer was too small.
+#[cfg(target_os = "linux")]
+const RECV_FLAGS: c_int = libc::MSG_TRUNC;
+#[cfg(not(target_os = "linux"))]
+const RECV_FLAGS: c_int = 0;
+
+// recvfrom(2) on a datagram socket, returning the length of the datagram
+// and the peer address.
+pub fn recv_from(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, socket2::SockAddr)> {
+    // SAFETY: basic system call, with the pointer and length of `buf` and
+    // the address storage, which socket2 provides.
+    unsafe {
+        socket2::SockAddr::try_init(|storage, storage_len| {
+            let buf_ptr = buf.as_mut_ptr() as *mut libc::c_void;
+            let res = libc::recvfrom(fd, buf_ptr, buf.len(), RECV_FLAGS, storage as *mut libc::sockaddr, storage_len);
+            result(res)
+        })
+    }
+}
+
+// recv(2) with MSG_PEEK: the data stays in the socket's receive queue.
+pub fn peek(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    // SAFETY: basic system call, with the pointer and length of `buf`.
+    let res = unsafe {
+        libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), libc::MSG_PEEK)
+    };
+    result(res)
+}
+
+// The socket option for the TCP keepalive idle time.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub const TCP_KEEPIDLE: c_int = libc::TCP_KEEPALIVE;
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+pub const TCP_KEEPIDLE: c_int = libc::TCP_KEEPIDLE;
+
+// The socket option and control message type for receive timestamps.
+#[cfg(target_os = "linux")]
+pub const SO_TIMESTAMP: c_int = libc::SO_TIMESTAMPNS;
+#[cfg(not(target_os = "linux"))]
+pub const SO_TIMESTAMP: c_int = libc::SO_TIMESTAMP;
+
+// recvmsg(2) on a datagram socket, returning the length of the datagram,
+// the peer address, and the receive timestamp if it was present.
+pub fn recvmsg_timestamp(
+    fd: RawFd,
+    buf: &mut [u8],
+) -> io::Result<(usize, socket2::SockAddr, Option<std::time::SystemTime>)> {
+    // u64 so that it is properly aligned for cmsghdr.
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut timestamp = None;
+
+    // SAFETY: the msghdr points to `iov`, `control` and the address storage,
+    // which all outlive the call. Control messages are only read within
+    // the length the kernel returned, using the CMSG_* macros.
+    let (len, addr) = unsafe {
+        socket2::SockAddr::try_init(|storage, storage_len| {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_name = storage as *mut libc::c_void;
+            msg.msg_namelen = *storage_len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = std::mem::size_of_val(&control) as _;
+            let len = result(libc::recvmsg(fd, &mut msg, RECV_FLAGS))?;
+            *storage_len = msg.msg_namelen;
+
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == SO_TIMESTAMP {
+                    timestamp = Some(cmsg_timestamp(libc::CMSG_DATA(cmsg)));
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            Ok(len)
+        })?
+    };
+    Ok((len, addr, timestamp))
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn cmsg_timestamp(data: *const u8) -> std::time::SystemTime {
+    let ts = std::ptr::read_unaligned(data as *const libc::timespec);
+    std::time::UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn cmsg_timestamp(data: *const u8) -> std::time::SystemTime {
+    let tv = std::ptr::read_unaligned(data as *const libc::timeval);
+    std::time::UNIX_EPOCH + Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
 }
@@ -1,52 +1,100 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::future::Future;
-use std::os::fd::RawFd;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Wake, Waker};
+use std::thread::ThreadId;
+use futures_core::Stream;
 
+use crate::runtime::BlockingHandle;
 use crate::syscall;
 
+pub use task_local::{AccessError, LocalKey};
+
+#[path = "task_local.rs"]
+mod task_local;
+
 // Task.
 pub(crate) struct Task {
     // Unique id
     pub id:         u64,
+    // Optional name.
+    pub name:       Option<Box<str>>,
     // To wake the executor.
     pub waker:      Waker,
+    // The Arc behind `waker`, kept so that it can be reused.
+    task_waker:     Arc<TaskWaker>,
     // Future to run.
     future:         Pin<Box<dyn Future<Output=()>>>,
 }
 
 impl Task {
     // Create a new Task.
-    pub fn new<F, T>(id: u64, tx: RawFd, fut: F) -> (Self, JoinHandle<T>)
+    pub fn new<F, T>(id: u64, name: Option<&str>, task_waker: Arc<TaskWaker>, fut: F) -> (Self, LocalJoinHandle<T>)
     where
         F: Future<Output = T> + 'static,
         T: 'static,
     {
-        let join_handle = JoinHandle::new(id);
+        let join_handle = LocalJoinHandle::new(id);
 
         // Wrap the future with a Future<Output=()> so that Task doesn't have to be generic.
+        // If the task was aborted, stop polling and return, which drops `fut`.
+        // A panic is caught, so that it only ends this task.
         let join_handle2 = join_handle.clone();
         let thunk = async move {
-            let res = fut.await;
-            join_handle2.set_result(res);
+            let mut fut = std::pin::pin!(fut);
+            let res = std::future::poll_fn(|cx| {
+                if join_handle2.is_aborted() {
+                    return Poll::Ready(None);
+                }
+                match std::panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(cx))) {
+                    Ok(Poll::Ready(res)) => Poll::Ready(Some(Ok(res))),
+                    Ok(Poll::Pending) => Poll::Pending,
+                    Err(panic) => Poll::Ready(Some(Err(panic))),
+                }
+            }).await;
+            match res {
+                Some(Ok(res)) => join_handle2.set_result(res),
+                Some(Err(panic)) => join_handle2.set_error(JoinError::Panic(panic)),
+                None => {},
+            }
         };
 
         // Store id, future and waker in the Task struct nice and cosy together.
-        // Note that in the current implementation, `tx` is in blocking mode!
+        let waker: Waker = task_waker.clone().into();
+        join_handle.inner.borrow_mut().task_waker = Some(waker.clone());
         let task = Task {
             id,
+            name: name.map(Box::from),
             future: Box::pin(thunk),
-            waker: Arc::new(TaskWaker{ id, tx }).into(),
+            waker,
+            task_waker,
         };
 
         (task, join_handle)
     }
 
     // Used for the main task in `block_on()`.
-    pub fn main_task(id: u64, tx: RawFd) -> Self {
-        let waker = Arc::new(TaskWaker { id, tx }).into();
-        Task { id, future: Box::pin(async {}), waker }
+    pub fn main_task(id: u64, task_waker: Arc<TaskWaker>) -> Self {
+        let waker = task_waker.clone().into();
+        Task { id, name: None, future: Box::pin(async {}), waker, task_waker }
+    }
+
+    // Drop the task, and return its TaskWaker if nothing else references it,
+    // so that it can be reused for another task.
+    pub fn into_task_waker(self) -> Option<Arc<TaskWaker>> {
+        let Task { future, waker, mut task_waker, .. } = self;
+        drop(future);
+        drop(waker);
+        Arc::get_mut(&mut task_waker)?;
+        Some(task_waker)
     }
 
     // Poll the Task.
@@ -57,10 +105,75 @@ impl Task {
 }
 
 // The task waker makes sure the task gets queued and run by the executor.
-struct TaskWaker {
+pub(crate) struct TaskWaker {
     id:         u64,
     // The below for cross-thread waking.
-    tx:         RawFd,
+    queue:      Arc<WakeQueue>,
+}
+
+impl TaskWaker {
+    // Get a TaskWaker for task `id`, reusing one from `pool` if possible.
+    // The pool only holds TaskWakers that are not referenced elsewhere.
+    pub fn get(id: u64, queue: &Arc<WakeQueue>, pool: &mut Vec<Arc<TaskWaker>>) -> Arc<TaskWaker> {
+        if let Some(mut task_waker) = pool.pop() {
+            if let Some(tw) = Arc::get_mut(&mut task_waker) {
+                tw.id = id;
+                return task_waker;
+            }
+        }
+        Arc::new(TaskWaker { id, queue: queue.clone() })
+    }
+}
+
+// Task ids woken from other threads. The wakeup fd is only written to when
+// the queue goes from empty to non-empty, so a burst of wakeups costs
+// one write(2) instead of one per wakeup.
+pub(crate) struct WakeQueue {
+    ids:    Mutex<Vec<u64>>,
+    // Note that if this is a pipe, `tx` is in blocking mode!
+    // None if cross-thread wakeups are disabled.
+    tx:     Option<File>,
+    // The runtime's thread.
+    thread: ThreadId,
+    // Number of writes to the wakeup fd.
+    #[cfg(test)]
+    pub(crate) writes: std::sync::atomic::AtomicUsize,
+}
+
+impl WakeQueue {
+    pub fn new(tx: Option<File>) -> WakeQueue {
+        WakeQueue {
+            ids: Mutex::new(Vec::new()),
+            tx,
+            thread: std::thread::current().id(),
+            #[cfg(test)]
+            writes: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, id: u64) {
+        let Some(tx) = self.tx.as_ref() else {
+            // On the runtime's own thread this is a wakeup after the
+            // runtime is gone (say, while it drops its tasks). Ignore it.
+            if std::thread::current().id() != self.thread {
+                panic!("task woken from another thread, but cross-thread wakeups are disabled \
+                        (runtime::Builder::allow_cross_thread_wake)");
+            }
+            return;
+        };
+        let mut ids = self.ids.lock().unwrap();
+        ids.push(id);
+        if ids.len() == 1 {
+            syscall::wakeup(tx.as_raw_fd());
+            #[cfg(test)]
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Take all queued ids.
+    pub fn take(&self) -> Vec<u64> {
+        std::mem::take(&mut *self.ids.lock().unwrap())
+    }
 }
 
 impl Wake for TaskWaker {
@@ -70,40 +183,166 @@ impl Wake for TaskWaker {
                 // If we're on the same thread as the executor, queue directly.
                 executor.queue(self.id);
             } else {
-                // We're on another thread, so send task id over pipe.
-                // Note that self.tx is a _blocking_ file descriptor.
-                let _ = syscall::write(self.tx, &self.id.to_ne_bytes()[..]);
+                // We're on another thread, so queue the id and wake the executor.
+                self.queue.push(self.id);
             }
         })
     }
 }
 
-#[derive(Debug)]
-pub struct JoinError;
+/// A task state transition, passed to the hook set with
+/// `runtime::Builder::on_task_event`.
+#[derive(Clone, Copy, Debug)]
+pub struct TaskEvent<'a> {
+    /// Task id.
+    pub id: u64,
+    /// Task name, if it was spawned with `spawn_named`.
+    pub name: Option<&'a str>,
+    /// What happened.
+    pub kind: TaskEventKind,
+}
+
+/// Kind of `TaskEvent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskEventKind {
+    /// The task was spawned.
+    Spawned,
+    /// The task is about to be polled.
+    Polling,
+    /// The task was polled and is waiting to be woken up.
+    Pending,
+    /// The task was polled and ran out of budget, it will be polled
+    /// again after the reactor has run.
+    Yielded,
+    /// The task was polled and has completed.
+    Completed,
+}
+
+/// Error returned when awaiting a `JoinHandle` of a task that did not complete.
+pub enum JoinError {
+    /// The task was aborted. If it was aborted with `abort_with`,
+    /// this contains the reason.
+    Cancelled(Option<Box<dyn Any + Send>>),
+    /// The task panicked. This contains the panic payload.
+    Panic(Box<dyn Any + Send>),
+}
+
+impl JoinError {
+    /// Was the task aborted.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled(_))
+    }
+
+    /// Did the task panic.
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panic(_))
+    }
+
+    /// The panic message, if the task panicked with a string message
+    /// (which is what `panic!` does).
+    pub fn panic_message(&self) -> Option<&str> {
+        match self {
+            JoinError::Panic(panic) => panic.downcast_ref::<&str>().copied()
+                .or_else(|| panic.downcast_ref::<String>().map(|s| s.as_str())),
+            _ => None,
+        }
+    }
+
+    /// Take the panic payload, e.g. to resume the panic with
+    /// `std::panic::resume_unwind`. Returns `Err(self)` if the task did
+    /// not panic.
+    pub fn try_into_panic(self) -> Result<Box<dyn Any + Send>, JoinError> {
+        match self {
+            JoinError::Panic(panic) => Ok(panic),
+            err => Err(err),
+        }
+    }
+
+    /// The reason passed to `abort_with`, if it is of type `R`.
+    pub fn reason<R: Any>(&self) -> Option<&R> {
+        match self {
+            JoinError::Cancelled(Some(reason)) => reason.downcast_ref::<R>(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::Cancelled(None) => write!(f, "Cancelled"),
+            JoinError::Cancelled(Some(_)) => write!(f, "Cancelled(..)"),
+            JoinError::Panic(_) => match self.panic_message() {
+                Some(msg) => write!(f, "Panic({:?})", msg),
+                None => write!(f, "Panic(..)"),
+            },
+        }
+    }
+}
 impl std::fmt::Display for JoinError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "JoinError")
+        match self {
+            JoinError::Cancelled(_) => write!(f, "task was cancelled"),
+            JoinError::Panic(_) => match self.panic_message() {
+                Some(msg) => write!(f, "task panicked: {}", msg),
+                None => write!(f, "task panicked"),
+            },
+        }
     }
 }
 impl std::error::Error for JoinError {}
 
-// spawn() and spawn_blocking return a JoinHandle, which can be awaited on,
-// and which will return the return value of the spawned task.
+/// Error returned by `Handle::spawn` and `Handle::spawn_blocking`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The runtime has been dropped, or is being dropped.
+    RuntimeShuttingDown,
+    /// `spawn_blocking` needs cross-thread wakeups, and they are disabled
+    /// with `runtime::Builder::allow_cross_thread_wake`.
+    CrossThreadWakeDisabled,
+}
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnError::RuntimeShuttingDown => write!(f, "runtime is shutting down"),
+            SpawnError::CrossThreadWakeDisabled => write!(f, "cross-thread wakeups are disabled"),
+        }
+    }
+}
+impl std::error::Error for SpawnError {}
+
+// spawn_blocking returns a JoinHandle, which can be awaited on, and which
+// will return the return value of the closure. The result is set from
+// another thread, hence the Arc<Mutex>. spawn() returns a LocalJoinHandle.
 pub struct JoinHandle<T> {
     pub(crate) id: u64,
     pub(crate) inner: Arc<Mutex<JoinInner<T>>>,
 }
 
+// State of the task, as seen by the JoinHandle.
+pub(crate) enum JoinState<T> {
+    Running,
+    Finished(T),
+    Failed(JoinError),
+    // The result was returned to the awaiter.
+    Consumed,
+}
+
 pub(crate) struct JoinInner<T> {
-    pub result: Option<T>,
+    pub state: JoinState<T>,
     pub waker: Option<Waker>,
+    // Set by abort().
+    pub aborted: bool,
+    // Waker of the task itself, so that abort() can make it run.
+    pub task_waker: Option<Waker>,
+    // Cancellation flag for spawn_blocking tasks.
+    pub abort_flag: Option<Arc<AtomicBool>>,
 }
 
 impl<T> JoinHandle<T> {
     // Create new, empty JoinHandle.
     pub(crate) fn new(id: u64) -> JoinHandle<T> {
-        let inner = JoinInner { result: None, waker: None };
-        JoinHandle { id, inner: Arc::new(Mutex::new(inner)) }
+        JoinHandle { id, inner: Arc::new(Mutex::new(JoinInner::new())) }
     }
 
     // non-public clone().
@@ -113,40 +352,585 @@ impl<T> JoinHandle<T> {
 
     // store the result and wake the task that is waiting on this handle.
     pub(crate) fn set_result(&self, res: T) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.result = Some(res);
-        if let Some(waker) = inner.waker.take() {
+        self.inner.lock().unwrap().set_result(res);
+    }
+
+    // like set_result, for a task that failed.
+    pub(crate) fn set_error(&self, err: JoinError) {
+        self.inner.lock().unwrap().set_error(err);
+    }
+
+    /// Has the task finished or been aborted?
+    ///
+    /// If so, awaiting the handle returns right away. This does not take
+    /// the result. Note that the closure of an aborted `spawn_blocking` task
+    /// might still be running.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.inner.lock().unwrap().state, JoinState::Running)
+    }
+
+    /// Abort the task.
+    ///
+    /// A task spawned with `spawn_blocking` cannot be interrupted;
+    /// if it hasn't started yet it never will, and if it was spawned with
+    /// `spawn_blocking_cancellable` its cancellation flag is set.
+    ///
+    /// Awaiting the `JoinHandle` of an aborted task returns
+    /// `Err(JoinError::Cancelled(None))`.
+    pub fn abort(&self) {
+        self.abort_inner(None);
+    }
+
+    /// Abort the task, like `abort`, with a reason.
+    ///
+    /// Awaiting the `JoinHandle` returns `Err(JoinError::Cancelled(Some(reason)))`.
+    /// The reason can be retrieved with `JoinError::reason`.
+    pub fn abort_with<R: Any + Send>(&self, reason: R) {
+        self.abort_inner(Some(Box::new(reason)));
+    }
+
+    fn abort_inner(&self, reason: Option<Box<dyn Any + Send>>) {
+        self.inner.lock().unwrap().abort(reason);
+    }
+
+    /// Return an `AbortHandle` for this task.
+    pub fn abort_handle(&self) -> AbortHandle
+    where
+        T: 'static,
+    {
+        AbortHandle { id: self.id, inner: Rc::new(self.inner.clone()) }
+    }
+}
+
+/// Handle to a task spawned with `spawn`. It can be awaited to get the
+/// return value of the task.
+///
+/// This is the single-threaded version of `JoinHandle`, without the
+/// locking, since a task and its handle always live on the same thread.
+pub struct LocalJoinHandle<T> {
+    pub(crate) id: u64,
+    inner: Rc<RefCell<JoinInner<T>>>,
+}
+
+impl<T> LocalJoinHandle<T> {
+    // Create new, empty LocalJoinHandle.
+    fn new(id: u64) -> LocalJoinHandle<T> {
+        LocalJoinHandle { id, inner: Rc::new(RefCell::new(JoinInner::new())) }
+    }
+
+    // non-public clone().
+    fn clone(&self) -> LocalJoinHandle<T> {
+        LocalJoinHandle { id: self.id, inner: self.inner.clone() }
+    }
+
+    // store the result and wake the task that is waiting on this handle.
+    fn set_result(&self, res: T) {
+        self.inner.borrow_mut().set_result(res);
+    }
+
+    // like set_result, for a task that failed.
+    fn set_error(&self, err: JoinError) {
+        self.inner.borrow_mut().set_error(err);
+    }
+
+    // has abort() been called.
+    fn is_aborted(&self) -> bool {
+        self.inner.borrow().aborted
+    }
+
+    /// Has the task finished or been aborted?
+    ///
+    /// If so, awaiting the handle returns right away. This does not take
+    /// the result.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.inner.borrow().state, JoinState::Running)
+    }
+
+    /// Abort the task.
+    ///
+    /// The task is dropped the next time the executor gets to it.
+    ///
+    /// Awaiting the `LocalJoinHandle` of an aborted task returns
+    /// `Err(JoinError::Cancelled(None))`.
+    pub fn abort(&self) {
+        self.inner.borrow_mut().abort(None);
+    }
+
+    /// Abort the task, like `abort`, with a reason.
+    ///
+    /// Awaiting the `LocalJoinHandle` returns `Err(JoinError::Cancelled(Some(reason)))`.
+    /// The reason can be retrieved with `JoinError::reason`.
+    pub fn abort_with<R: Any + Send>(&self, reason: R) {
+        self.inner.borrow_mut().abort(Some(Box::new(reason)));
+    }
+
+    /// Return an `AbortHandle` for this task.
+    pub fn abort_handle(&self) -> AbortHandle
+    where
+        T: 'static,
+    {
+        AbortHandle { id: self.id, inner: self.inner.clone() }
+    }
+}
+
+impl<T> JoinInner<T> {
+    fn new() -> JoinInner<T> {
+        JoinInner {
+            state: JoinState::Running,
+            waker: None,
+            aborted: false,
+            task_waker: None,
+            abort_flag: None,
+        }
+    }
+
+    fn set_result(&mut self, res: T) {
+        self.finish(JoinState::Finished(res));
+    }
+
+    fn set_error(&mut self, err: JoinError) {
+        self.finish(JoinState::Failed(err));
+    }
+
+    fn finish(&mut self, state: JoinState<T>) {
+        if !matches!(self.state, JoinState::Running) {
+            // Aborted.
+            return;
+        }
+        self.state = state;
+        // The task is done, it cannot be woken for an abort anymore.
+        self.task_waker = None;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn poll_result(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, JoinError>> {
+        match std::mem::replace(&mut self.state, JoinState::Consumed) {
+            JoinState::Finished(res) => Poll::Ready(Ok(res)),
+            JoinState::Failed(err) => Poll::Ready(Err(err)),
+            state => {
+                self.state = state;
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+
+    fn abort(&mut self, reason: Option<Box<dyn Any + Send>>) {
+        if !matches!(self.state, JoinState::Running) {
+            return;
+        }
+        self.aborted = true;
+        self.state = JoinState::Failed(JoinError::Cancelled(reason));
+        if let Some(flag) = self.abort_flag.as_ref() {
+            flag.store(true, Ordering::Release);
+        }
+        if let Some(waker) = self.task_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.waker.take() {
             waker.wake();
         }
     }
 }
 
+// Type-erased JoinInner, for AbortHandle.
+trait Abort {
+    fn abort(&self, reason: Option<Box<dyn Any + Send>>);
+}
+
+impl<T> Abort for Arc<Mutex<JoinInner<T>>> {
+    fn abort(&self, reason: Option<Box<dyn Any + Send>>) {
+        self.lock().unwrap().abort(reason);
+    }
+}
+
+impl<T> Abort for RefCell<JoinInner<T>> {
+    fn abort(&self, reason: Option<Box<dyn Any + Send>>) {
+        self.borrow_mut().abort(reason);
+    }
+}
+
+/// A handle that can abort a task, but not await it.
+///
+/// Unlike the join handles it is not generic over the task's output,
+/// so a collection of them can be kept, for example in a registry of
+/// connections.
+#[derive(Clone)]
+pub struct AbortHandle {
+    id: u64,
+    inner: Rc<dyn Abort>,
+}
+
+impl AbortHandle {
+    /// Abort the task. See `JoinHandle::abort`.
+    pub fn abort(&self) {
+        self.inner.abort(None);
+    }
+
+    /// Abort the task with a reason. See `JoinHandle::abort_with`.
+    pub fn abort_with<R: Any + Send>(&self, reason: R) {
+        self.inner.abort(Some(Box::new(reason)));
+    }
+}
+
+impl std::fmt::Debug for AbortHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AbortHandle").field("id", &self.id).finish()
+    }
+}
+
+impl<T> std::fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("JoinHandle");
+        d.field("id", &self.id);
+        // Don't block if the lock is held, e.g. by a worker thread.
+        match self.inner.try_lock() {
+            Ok(inner) => d.field("finished", &!matches!(inner.state, JoinState::Running)),
+            Err(_) => d.field("finished", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
 // A JoinHandle can be awaited.
 impl <T> Future for JoinHandle<T> {
     type Output = Result<T, JoinError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut inner = self.inner.lock().unwrap();
-        match inner.result.take() {
-            None => {
-                inner.waker = Some(cx.waker().clone());
-                Poll::Pending
-            },
-            Some(res) => Poll::Ready(Ok(res)),
-        }
+        self.inner.lock().unwrap().poll_result(cx)
+    }
+}
+
+impl<T> std::fmt::Debug for LocalJoinHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("LocalJoinHandle");
+        d.field("id", &self.id);
+        match self.inner.try_borrow() {
+            Ok(inner) => d.field("finished", &!matches!(inner.state, JoinState::Running)),
+            Err(_) => d.field("finished", &format_args!("<borrowed>")),
+        };
+        d.finish()
+    }
+}
+
+impl <T> Future for LocalJoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.borrow_mut().poll_result(cx)
     }
 }
 
 pub fn spawn_blocking<F: FnOnce() -> R + Send + 'static, R: Send + 'static>(f: F) -> JoinHandle<R> {
     crate::executor::EXECUTOR.with_borrow(move |e| {
         let executor = e.upgrade().unwrap();
-        executor.pool.spawn(f)
+        executor.blocking_pool().spawn(f)
+    })
+}
+
+/// Like `spawn_blocking`, but the closure is passed a cancellation flag.
+///
+/// The flag is set when `JoinHandle::abort` is called. A blocking task cannot
+/// be interrupted, but the closure can check the flag regularly and return
+/// early when it is set, freeing up the worker thread.
+pub fn spawn_blocking_cancellable<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce(Arc<AtomicBool>) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    crate::executor::EXECUTOR.with_borrow(move |e| {
+        let executor = e.upgrade().unwrap();
+        executor.blocking_pool().spawn_cancellable(f)
     })
 }
 
-pub fn spawn<F: Future<Output=T> + 'static, T: 'static>(fut: F) -> JoinHandle<T> {
+/// Like `spawn_blocking`, but the closure is passed a `BlockingHandle`.
+///
+/// With `BlockingHandle::block_on` the closure can run a future on the
+/// runtime and wait for the result. See there for the deadlock risks.
+pub fn spawn_blocking_with_handle<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce(BlockingHandle) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let handle = BlockingHandle::new();
+    spawn_blocking(move || f(handle))
+}
+
+pub fn spawn<F: Future<Output=T> + 'static, T: 'static>(fut: F) -> LocalJoinHandle<T> {
     crate::executor::EXECUTOR.with_borrow(|e| {
         let executor = e.upgrade().unwrap();
         executor.spawn(fut)
     })
 }
+
+/// Like `spawn`, but gives the task a name, which is passed to the
+/// task event hook.
+pub fn spawn_named<F: Future<Output=T> + 'static, T: 'static>(name: &str, fut: F) -> LocalJoinHandle<T> {
+    crate::executor::EXECUTOR.with_borrow(|e| {
+        let executor = e.upgrade().unwrap();
+        executor.spawn_named(Some(name), fut)
+    })
+}
+
+/// Like `spawn`, but also returns an `AbortHandle` for the task.
+pub fn spawn_with_handle<F, T>(fut: F) -> (LocalJoinHandle<T>, AbortHandle)
+where
+    F: Future<Output = T> + 'static,
+    T: 'static,
+{
+    let handle = spawn(fut);
+    let abort_handle = handle.abort_handle();
+    (handle, abort_handle)
+}
+
+/// Spawn a task for each future from `stream`, with at most `limit` of them
+/// in flight at a time.
+///
+/// The returned stream yields the results of the tasks. By default they
+/// are returned in the order of the input stream; call `unordered` on it to
+/// get them in the order in which the tasks complete. A new task is spawned
+/// as soon as there is room, that is, when a result has been returned.
+///
+/// A `limit` of 0 is treated as 1. When the returned stream is dropped,
+/// the tasks that are still running are aborted.
+pub fn spawn_buffered<S, T>(stream: S, limit: usize) -> SpawnBuffered<S, T>
+where
+    S: Stream,
+    S::Item: Future<Output = T> + 'static,
+    T: 'static,
+{
+    SpawnBuffered {
+        stream: Some(Box::pin(stream)),
+        in_flight: VecDeque::new(),
+        limit: std::cmp::max(limit, 1),
+        ordered: true,
+    }
+}
+
+/// Stream returned by `spawn_buffered`.
+pub struct SpawnBuffered<S, T> {
+    // None when the input stream has ended.
+    stream:     Option<Pin<Box<S>>>,
+    in_flight:  VecDeque<LocalJoinHandle<T>>,
+    limit:      usize,
+    ordered:    bool,
+}
+
+impl<S, T> SpawnBuffered<S, T> {
+    /// Return results in the order in which the tasks complete,
+    /// instead of in the order of the input stream.
+    pub fn unordered(mut self) -> Self {
+        self.ordered = false;
+        self
+    }
+}
+
+impl<S, T> Stream for SpawnBuffered<S, T>
+where
+    S: Stream,
+    S::Item: Future<Output = T> + 'static,
+    T: 'static,
+{
+    type Item = Result<T, JoinError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Fill up the free slots.
+        while this.in_flight.len() < this.limit {
+            let Some(stream) = this.stream.as_mut() else { break };
+            match stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(fut)) => this.in_flight.push_back(spawn(fut)),
+                Poll::Ready(None) => this.stream = None,
+                Poll::Pending => break,
+            }
+        }
+
+        // In order, only the oldest task counts. Otherwise, any task that is done.
+        let n = if this.ordered { 1 } else { this.in_flight.len() };
+        for idx in 0 .. std::cmp::min(n, this.in_flight.len()) {
+            if let Poll::Ready(res) = Pin::new(&mut this.in_flight[idx]).poll(cx) {
+                this.in_flight.remove(idx);
+                return Poll::Ready(Some(res));
+            }
+        }
+
+        if this.stream.is_none() && this.in_flight.is_empty() {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+impl<S, T> std::fmt::Debug for SpawnBuffered<S, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpawnBuffered")
+            .field("in_flight", &self.in_flight.len())
+            .field("limit", &self.limit)
+            .field("ordered", &self.ordered)
+            .field("stream_done", &self.stream.is_none())
+            .finish()
+    }
+}
+
+impl<S, T> Drop for SpawnBuffered<S, T> {
+    fn drop(&mut self) {
+        for handle in &self.in_flight {
+            handle.abort();
+        }
+    }
+}
+
+/// A group of tasks, awaited in the order in which they complete.
+///
+/// Tasks are spawned on the current runtime with `spawn`. When the
+/// `JoinSet` is dropped, the tasks that are still running are aborted.
+pub struct JoinSet<T> {
+    tasks: Vec<LocalJoinHandle<T>>,
+}
+
+impl<T: 'static> JoinSet<T> {
+    /// Create a new, empty `JoinSet`.
+    pub fn new() -> JoinSet<T> {
+        JoinSet { tasks: Vec::new() }
+    }
+
+    /// Spawn a task and add it to the set.
+    pub fn spawn<F: Future<Output = T> + 'static>(&mut self, fut: F) -> AbortHandle {
+        let handle = spawn(fut);
+        let abort_handle = handle.abort_handle();
+        self.tasks.push(handle);
+        abort_handle
+    }
+
+    /// Wait for the next task to complete and return its result.
+    ///
+    /// Returns `None` if the set is empty. A task that was aborted
+    /// returns `Err(JoinError::Cancelled(_))`.
+    pub async fn join_next(&mut self) -> Option<Result<T, JoinError>> {
+        std::future::poll_fn(|cx| self.poll_join_next(cx)).await
+    }
+
+    /// Poll for the next task to complete, see `join_next`.
+    pub fn poll_join_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<T, JoinError>>> {
+        if self.tasks.is_empty() {
+            return Poll::Ready(None);
+        }
+        for idx in 0 .. self.tasks.len() {
+            if let Poll::Ready(res) = Pin::new(&mut self.tasks[idx]).poll(cx) {
+                drop(self.tasks.swap_remove(idx));
+                return Poll::Ready(Some(res));
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Abort all tasks in the set.
+    ///
+    /// The tasks stay in the set; `join_next` returns them as cancelled
+    /// (or with their result, if they had already finished).
+    pub fn abort_all(&mut self) {
+        for handle in &self.tasks {
+            handle.abort();
+        }
+    }
+
+    /// Abort all tasks and wait until they are gone from the set.
+    pub async fn shutdown(&mut self) {
+        self.abort_all();
+        while self.join_next().await.is_some() {}
+    }
+}
+
+impl<T> JoinSet<T> {
+    /// Number of tasks in the set, including finished tasks whose
+    /// result has not been returned by `join_next` yet.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Is the set empty?
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+impl<T: 'static> Default for JoinSet<T> {
+    fn default() -> Self {
+        JoinSet::new()
+    }
+}
+
+impl<T> std::fmt::Debug for JoinSet<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JoinSet").field("len", &self.tasks.len()).finish()
+    }
+}
+
+impl<T> Drop for JoinSet<T> {
+    fn drop(&mut self) {
+        for handle in &self.tasks {
+            handle.abort();
+        }
+    }
+}
+
+/// Run `f` when the returned guard is dropped.
+///
+/// Create the guard inside a task to run cleanup code when the task's
+/// future is dropped, both when it completed and when it was aborted.
+/// After an abort, the future is dropped the next time the executor
+/// gets to the task, not during the `abort()` call itself.
+///
+/// Bind the guard to a variable: `let _guard = defer(|| ...)`. A plain
+/// `let _ = ...` drops it, and runs `f`, right away.
+#[must_use = "the closure runs when the guard is dropped"]
+pub fn defer<F: FnOnce()>(f: F) -> DeferGuard<F> {
+    DeferGuard { f: Some(f) }
+}
+
+/// Yield to the runtime.
+///
+/// The current task is woken and returns `Pending` once, so other
+/// runnable tasks, the reactor and the timers get a turn before it
+/// continues. Useful in long-running loops that never await on I/O.
+pub async fn yield_now() {
+    let mut yielded = false;
+    std::future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        crate::executor::yield_task(cx);
+        Poll::Pending
+    }).await
+}
+
+/// Guard returned by `defer`.
+pub struct DeferGuard<F: FnOnce()> {
+    f: Option<F>,
+}
+
+impl<F: FnOnce()> DeferGuard<F> {
+    /// Drop the guard without running the closure.
+    pub fn cancel(mut self) {
+        self.f.take();
+    }
+}
+
+impl<F: FnOnce()> std::fmt::Debug for DeferGuard<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeferGuard").field("armed", &self.f.is_some()).finish()
+    }
+}
+
+impl<F: FnOnce()> Drop for DeferGuard<F> {
+    fn drop(&mut self) {
+        if let Some(f) = self.f.take() {
+            f();
+        }
+    }
+}
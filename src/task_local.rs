@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+use std::future::Future;
+
+/// Declare task-local variables.
+///
+/// ```text
+/// nara::task_local! {
+///     static REQ_ID: u64;
+/// }
+/// ```
+///
+/// A value is set for the duration of a future with `REQ_ID.scope(value, fut)`,
+/// and read from within that future with `REQ_ID.with(|v| ...)` or `REQ_ID.get()`.
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
+        $crate::__task_local_inner!($(#[$attr])* $vis $name, $t);
+        $crate::task_local!($($rest)*);
+    };
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty) => {
+        $crate::__task_local_inner!($(#[$attr])* $vis $name, $t);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::task::LocalKey<$t> = {
+            std::thread_local! {
+                static __KEY: std::cell::RefCell<Option<$t>> = const { std::cell::RefCell::new(None) };
+            }
+            $crate::task::LocalKey { inner: __KEY }
+        };
+    };
+}
+
+/// A task-local variable, declared with `task_local!`.
+///
+/// The runtime is single-threaded, so this is a thread-local that is set
+/// while the scoped future is being polled, and restored afterwards. That way,
+/// nested scopes and other tasks never see each other's values.
+pub struct LocalKey<T: 'static> {
+    #[doc(hidden)]
+    pub inner: std::thread::LocalKey<RefCell<Option<T>>>,
+}
+
+/// Error returned by `LocalKey::try_with` when the value is not set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessError;
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "task-local value not set")
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+impl<T: 'static> LocalKey<T> {
+    /// Run `fut` with the task-local set to `value`.
+    pub async fn scope<F: Future>(&'static self, value: T, fut: F) -> F::Output {
+        let mut slot = Some(value);
+        let mut fut = std::pin::pin!(fut);
+        std::future::poll_fn(|cx| self.enter(&mut slot, || fut.as_mut().poll(cx))).await
+    }
+
+    /// Run the closure `f` with the task-local set to `value`.
+    pub fn sync_scope<F: FnOnce() -> R, R>(&'static self, value: T, f: F) -> R {
+        let mut slot = Some(value);
+        self.enter(&mut slot, f)
+    }
+
+    /// Call `f` with a reference to the value.
+    ///
+    /// Panics if the value is not set, that is, if not called from
+    /// within `scope`.
+    pub fn with<F: FnOnce(&T) -> R, R>(&'static self, f: F) -> R {
+        match self.try_with(f) {
+            Ok(res) => res,
+            Err(_) => panic!("task-local value not set, not within LocalKey::scope"),
+        }
+    }
+
+    /// Call `f` with a reference to the value, if it is set.
+    pub fn try_with<F: FnOnce(&T) -> R, R>(&'static self, f: F) -> Result<R, AccessError> {
+        self.inner.with(|cell| {
+            let value = cell.borrow();
+            value.as_ref().map(f).ok_or(AccessError)
+        })
+    }
+
+    /// Return a copy of the value. Panics if the value is not set.
+    pub fn get(&'static self) -> T
+    where
+        T: Clone,
+    {
+        self.with(|v| v.clone())
+    }
+
+    // Swap the value in `slot` into the thread-local, run `f`, and
+    // swap it back again, also if `f` panics.
+    fn enter<F: FnOnce() -> R, R>(&'static self, slot: &mut Option<T>, f: F) -> R {
+        struct Guard<'a, T: 'static> {
+            key: &'static LocalKey<T>,
+            slot: &'a mut Option<T>,
+        }
+
+        impl<T: 'static> Drop for Guard<'_, T> {
+            fn drop(&mut self) {
+                self.key.inner.with(|cell| std::mem::swap(self.slot, &mut *cell.borrow_mut()));
+            }
+        }
+
+        self.inner.with(|cell| std::mem::swap(slot, &mut *cell.borrow_mut()));
+        let _guard = Guard { key: self, slot };
+        f()
+    }
+}
+
+impl<T: 'static> std::fmt::Debug for LocalKey<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalKey").finish_non_exhaustive()
+    }
+}
@@ -1,19 +1,25 @@
 use std::cell::RefCell;
 use std::thread;
 use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::panic::AssertUnwindSafe;
 use std::sync::{mpsc, Arc, Mutex};
-use crate::task::JoinHandle;
+use crate::task::{JoinError, JoinHandle};
 
 const MAX_THREADS: usize = 16;
 const THREAD_LIFETIME_MS: u64 = 250;
 
-type BoxedFn = Box<dyn FnOnce() -> () + Send + 'static>;
+type BoxedFn = Box<dyn FnOnce() + Send + 'static>;
 
 // A threadpool for spawn_blocking().
 pub struct ThreadPool {
     tx: mpsc::Sender<BoxedFn>,
     rx: Arc<Mutex<mpsc::Receiver<BoxedFn>>>,
     threads: RefCell<Vec<thread::JoinHandle<()>>>,
+    // Number of jobs that are queued or running.
+    pending: Arc<AtomicUsize>,
+    // Idle threads minus queued jobs.
+    available: Arc<AtomicIsize>,
 }
 
 impl ThreadPool {
@@ -24,7 +30,38 @@ impl ThreadPool {
         let (tx, rx) = mpsc::channel();
         let rx = Arc::new(Mutex::new(rx));
         let threads = RefCell::new(Vec::new());
-        ThreadPool { threads, rx, tx }
+        let pending = Arc::new(AtomicUsize::new(0));
+        let available = Arc::new(AtomicIsize::new(0));
+        ThreadPool { threads, rx, tx, pending, available }
+    }
+
+    // Are there jobs that are queued or running?
+    pub fn is_busy(&self) -> bool {
+        self.pending.load(Ordering::Acquire) > 0
+    }
+
+    // Start up to `n` extra worker threads (limited by MAX_THREADS).
+    //
+    // They follow the normal keep-alive rules, except that the first idle
+    // timeout is staggered: thread `i` waits `i + 1` lifetimes. That way they
+    // do not all exit at the same time if no work arrives.
+    pub fn prewarm(&self, n: usize) {
+        let mut threads = self.threads.borrow_mut();
+        threads.retain(|t| !t.is_finished());
+        let n = std::cmp::min(n, MAX_THREADS.saturating_sub(threads.len()));
+        for i in 0 .. n {
+            threads.push(self.launch(THREAD_LIFETIME_MS * (i as u64 + 1)));
+        }
+    }
+
+    // Start a worker thread.
+    fn launch(&self, first_lifetime_ms: u64) -> thread::JoinHandle<()> {
+        let rx = self.rx.clone();
+        let available = self.available.clone();
+        // Count it as idle right away, so that a spawn() that follows
+        // immediately does not launch yet another thread.
+        available.fetch_add(1, Ordering::AcqRel);
+        thread::spawn(move || worker(rx, available, first_lifetime_ms))
     }
 
     // Spawn the closure, returning a JoinHandle (which implements Future).
@@ -32,20 +69,40 @@ impl ThreadPool {
     where
         F: FnOnce() -> T + Send + 'static,
         T: Send + 'static,
+    {
+        self.spawn_cancellable(move |_| f())
+    }
+
+    // Spawn the closure, passing it the flag that is set by JoinHandle::abort().
+    pub fn spawn_cancellable<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce(Arc<AtomicBool>) -> T + Send + 'static,
+        T: Send + 'static,
     {
         let mut threads = self.threads.borrow_mut();
 
-        // Launch more threads, up to MAX_THREADS.
-        if threads.len() < MAX_THREADS {
-            let rx = self.rx.clone();
-            threads.push(thread::spawn(move || worker(rx)));
+        // Launch more threads if none are idle, up to MAX_THREADS.
+        if self.available.fetch_sub(1, Ordering::AcqRel) <= 0 && threads.len() < MAX_THREADS {
+            threads.push(self.launch(THREAD_LIFETIME_MS));
         }
 
         // Now move the closure to the ThreadPool executor.
+        // If the task was aborted before it got to run, skip it.
         let handle = JoinHandle::new(0);
         let handle2 = handle.clone();
+        let flag = Arc::new(AtomicBool::new(false));
+        handle.inner.lock().unwrap().abort_flag = Some(flag.clone());
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        let pending = PendingGuard(self.pending.clone());
         let thunk = move || {
-            handle2.set_result(f());
+            // Decremented after the result was set.
+            let _pending = pending;
+            if !flag.load(Ordering::Acquire) {
+                match std::panic::catch_unwind(AssertUnwindSafe(|| f(flag))) {
+                    Ok(res) => handle2.set_result(res),
+                    Err(panic) => handle2.set_error(JoinError::Panic(panic)),
+                }
+            }
         };
 
         // maybe turn SendError into JoinError?
@@ -62,19 +119,86 @@ impl ThreadPool {
     }
 }
 
+// Decrements the pending job count when dropped.
+struct PendingGuard(Arc<AtomicUsize>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
 //
 // Simple worker. Lock the Receiver and get one task, then run it and report result.
 //
 // Too bad that the implementation in `std` is actually `mpsc`, but is
 // only exposed as `mpsc`. If it was `mpsc` we wouldn't need the mutex.
 //
-fn worker(rx: Arc<Mutex<mpsc::Receiver<BoxedFn>>>) {
+// `first_lifetime_ms` is the idle timeout before the first job. The worker
+// counts itself in `available` while idle; see `JobGuard` for when it runs
+// a job.
+//
+fn worker(rx: Arc<Mutex<mpsc::Receiver<BoxedFn>>>, available: Arc<AtomicIsize>, first_lifetime_ms: u64) {
+    let mut lifetime_ms = first_lifetime_ms;
     while let Ok(rxer) = rx.lock() {
-        let work = match rxer.recv_timeout(Duration::from_millis(THREAD_LIFETIME_MS)) {
+        let work = match rxer.recv_timeout(Duration::from_millis(lifetime_ms)) {
             Ok(work) => work,
-            Err(_) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // Only exit if no queued job is counting on us.
+                let dec = |a: isize| if a > 0 { Some(a - 1) } else { None };
+                if available.fetch_update(Ordering::AcqRel, Ordering::Acquire, dec).is_ok() {
+                    break;
+                }
+                continue;
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
         };
         drop(rxer);
+        let guard = JobGuard(&available);
         work();
+        drop(guard);
+        lifetime_ms = THREAD_LIFETIME_MS;
+    }
+}
+
+// Counts the worker as idle again when its job is done.
+//
+// `spawn_cancellable` catches panics, so jobs do not unwind. If one did
+// anyway, this thread would exit, so it is not counted as idle then.
+struct JobGuard<'a>(&'a AtomicIsize);
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            self.0.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use super::ThreadPool;
+
+    // A job that panics leaves its worker idle again, and that worker runs
+    // the next job.
+    #[test]
+    fn panicking_job_keeps_worker_count() {
+        let pool = ThreadPool::new();
+        pool.prewarm(1);
+        let rt = crate::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            assert!(pool.spawn(|| panic!("job panicked")).await.is_err());
+            assert_eq!(pool.spawn(|| 42).await.unwrap(), 42);
+        });
+        for _ in 0 .. 500 {
+            if pool.available.load(Ordering::Acquire) == 1 && pool.threads.borrow().len() == 1 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("available is {}, expected 1", pool.available.load(Ordering::Acquire));
     }
 }
@@ -1,7 +1,7 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::BTreeMap;
 use std::future::Future;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
 use std::pin::Pin;
 use std::rc::{Rc, Weak};
 use std::task::Waker;
@@ -12,13 +12,13 @@ pub(crate) struct Timer {
 }
 
 pub(crate) struct InnerTimer {
-    timers: BTreeMap::<Sleep, Option<Waker>>,
+    timers: BTreeMap::<TimerKey, Option<Waker>>,
     next_id: u64,
 }
 
 thread_local! {
     // Valid after Timer::activate(), invalid after Timer::deactivate()
-    static TIMER: RefCell<Weak<RefCell<InnerTimer>>> = RefCell::new(Weak::new());
+    static TIMER: RefCell<Weak<RefCell<InnerTimer>>> = const { RefCell::new(Weak::new()) };
 }
 
 impl Timer {
@@ -50,6 +50,11 @@ impl Timer {
         Some(first.deadline.checked_duration_since(now).unwrap_or(Duration::ZERO))
     }
 
+    // Are there no timers at all?
+    pub fn is_empty(&self) -> bool {
+        self.inner.borrow().timers.is_empty()
+    }
+
     // Wake waiters on epired timers.
     pub fn tick(&self) {
         let mut this = self.inner.borrow_mut();
@@ -59,17 +64,31 @@ impl Timer {
                 break;
             }
             let (_, mut waker) = entry.remove_entry();
-            waker.take().map(|w| w.wake());
+            if let Some(w) = waker.take() {
+                w.wake();
+            }
         }
     }
 }
 
+// Key into the timers map.
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct TimerKey {
+    deadline:   Instant,
+    id:         u64,
+}
+
 pub struct Sleep {
     deadline:   Instant,
     id:         u64,
 }
 
+impl std::fmt::Debug for Sleep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sleep").field("deadline", &self.deadline).finish()
+    }
+}
+
 impl Sleep {
     pub fn deadline(&self) -> Instant {
         self.deadline
@@ -79,8 +98,51 @@ impl Sleep {
         Instant::now() >= self.deadline
     }
 
-    fn clone(&self) -> Self {
-        Sleep { deadline: self.deadline, id: self.id }
+    /// Reset the deadline. The `Sleep` can be awaited again, also if it
+    /// had already expired.
+    pub fn reset(&mut self, deadline: Instant) {
+        TIMER.with_borrow(|t| {
+            let timer = t.upgrade().unwrap();
+            let mut this = timer.borrow_mut();
+            // Nothing to do if the timer is still pending with this deadline.
+            if deadline == self.deadline && this.timers.contains_key(&self.key()) {
+                return;
+            }
+            let waker = this.timers.remove(&self.key()).flatten();
+            self.deadline = deadline;
+            this.timers.insert(self.key(), waker);
+        })
+    }
+
+    /// Poll the timer, without consuming it.
+    ///
+    /// Returns `Ready` once the deadline has passed, and keeps doing so until
+    /// the `Sleep` is `reset`. Since `Sleep` is `Unpin`, `(&mut sleep).await`
+    /// works as well. That way one `Sleep` can be kept across the iterations
+    /// of a `select!` loop.
+    pub fn poll_elapsed(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let timer = TIMER.with_borrow(|t| t.upgrade().unwrap());
+        let mut this = timer.borrow_mut();
+        // Note, if there is an entry in `timers`, it means that this was
+        // a spurious wakeup, not caused by Timer::tick().
+        match this.timers.get_mut(&self.key()) {
+            None => Poll::Ready(()),
+            Some(Some(w)) => {
+                // Only update the waker if it changed.
+                if !w.will_wake(cx.waker()) {
+                    w.clone_from(cx.waker());
+                }
+                Poll::Pending
+            },
+            Some(e) => {
+                *e = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+
+    fn key(&self) -> TimerKey {
+        TimerKey { deadline: self.deadline, id: self.id }
     }
 }
 
@@ -90,9 +152,9 @@ pub fn sleep_until(deadline: Instant) -> Sleep {
         let mut this = timer.borrow_mut();
         let id = this.next_id;
         this.next_id += 1;
-        let key = Sleep { deadline, id };
-        this.timers.insert(key.clone(), None);
-        key
+        let sleep = Sleep { deadline, id };
+        this.timers.insert(sleep.key(), None);
+        sleep
     })
 }
 
@@ -100,21 +162,332 @@ pub fn sleep(duration: Duration) -> Sleep {
     sleep_until(Instant::now() + duration)
 }
 
+/// Error returned by `timeout` and `timeout_at` if the deadline passed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+impl From<Elapsed> for std::io::Error {
+    fn from(e: Elapsed) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, e)
+    }
+}
+
+/// Run `fut`, but give up after `duration`.
+///
+/// Returns `Err(Elapsed)` if `fut` did not complete in time, in which
+/// case `fut` is dropped, cancelling it.
+pub fn timeout<F: Future>(duration: Duration, fut: F) -> impl Future<Output = Result<F::Output, Elapsed>> {
+    timeout_at(Instant::now() + duration, fut)
+}
+
+/// Run `fut`, but give up at `deadline`. See `timeout`.
+pub async fn timeout_at<F: Future>(deadline: Instant, fut: F) -> Result<F::Output, Elapsed> {
+    let mut sleep = sleep_until(deadline);
+    let mut fut = std::pin::pin!(fut);
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = fut.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        ready!(sleep.poll_elapsed(cx));
+        Poll::Ready(Err(Elapsed(())))
+    }).await
+}
+
+/// An idle timeout, for example for a connection.
+///
+/// Call `reset` on activity, and await `expired` to find out when there
+/// was no activity for the timeout period. `reset` is cheap: it only notes
+/// the time. The underlying timer is only updated when it goes off, so it
+/// is touched about once per timeout period, not once per reset.
+///
+/// Both methods take `&self`, so `expired()` can be awaited in a `select!`
+/// loop while the other branches call `reset()`.
+pub struct IdleTimeout {
+    timeout:        Cell<Duration>,
+    last_active:    Cell<Instant>,
+    sleep:          RefCell<Sleep>,
+}
+
+impl IdleTimeout {
+    /// Create a new idle timeout, starting now.
+    pub fn new(timeout: Duration) -> IdleTimeout {
+        let now = Instant::now();
+        IdleTimeout {
+            timeout: Cell::new(timeout),
+            last_active: Cell::new(now),
+            sleep: RefCell::new(sleep_until(now + timeout)),
+        }
+    }
+
+    /// Note activity: the timeout period starts again.
+    pub fn reset(&self) {
+        self.last_active.set(Instant::now());
+    }
+
+    /// Change the timeout period. Takes effect right away, counting from
+    /// the last activity.
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.timeout.set(timeout);
+        let deadline = self.deadline();
+        let mut sleep = self.sleep.borrow_mut();
+        if deadline < sleep.deadline() {
+            sleep.reset(deadline);
+        }
+    }
+
+    /// The timeout period.
+    pub fn timeout(&self) -> Duration {
+        self.timeout.get()
+    }
+
+    /// When the timeout expires, if there is no activity before then.
+    pub fn deadline(&self) -> Instant {
+        self.last_active.get() + self.timeout.get()
+    }
+
+    /// Has the timeout expired?
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline()
+    }
+
+    /// Wait until there was no activity for the timeout period.
+    ///
+    /// Keeps returning right away until `reset` is called.
+    pub async fn expired(&self) {
+        std::future::poll_fn(|cx| {
+            let mut sleep = self.sleep.borrow_mut();
+            loop {
+                ready!(sleep.poll_elapsed(cx));
+                // There might have been activity since the timer was set.
+                let deadline = self.deadline();
+                if deadline <= Instant::now() {
+                    return Poll::Ready(());
+                }
+                sleep.reset(deadline);
+            }
+        }).await
+    }
+}
+
+impl std::fmt::Debug for IdleTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdleTimeout")
+            .field("timeout", &self.timeout.get())
+            .field("deadline", &self.deadline())
+            .finish()
+    }
+}
+
+/// What an `Interval` does when a tick is missed, because it was not
+/// polled in time. See `Interval::set_missed_tick_behavior`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissedTickBehavior {
+    /// Fire the missed ticks right away, one after the other, to catch up.
+    /// The schedule stays the same. This is the default.
+    #[default]
+    Burst,
+    /// Start counting from the late tick: the next one is a full period
+    /// after it was polled.
+    Delay,
+    /// Drop the missed ticks and continue at the next tick on the
+    /// original schedule.
+    Skip,
+}
+
+/// A periodic timer, see `interval`.
+#[derive(Debug)]
+pub struct Interval {
+    sleep:      Sleep,
+    period:     Duration,
+    missed_tick_behavior: MissedTickBehavior,
+}
+
+/// Create an `Interval` that ticks every `period`. The first tick is
+/// right away.
+///
+/// Each deadline is computed from the previous one, not from when the
+/// tick was polled, so the ticks do not drift. Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    interval_at(Instant::now(), period)
+}
+
+/// Create an `Interval` that first ticks at `start`, and then every `period`.
+///
+/// Panics if `period` is zero.
+pub fn interval_at(start: Instant, period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval: period must be non-zero");
+    Interval {
+        sleep: sleep_until(start),
+        period,
+        missed_tick_behavior: MissedTickBehavior::default(),
+    }
+}
+
+impl Interval {
+    /// Wait for the next tick. Returns the time the tick was scheduled for.
+    pub async fn tick(&mut self) -> Instant {
+        std::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    /// Poll for the next tick. Like `Sleep::poll_elapsed`, this is useful
+    /// in a `select!` loop.
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        ready!(self.sleep.poll_elapsed(cx));
+        let deadline = self.sleep.deadline();
+        let now = Instant::now();
+        let mut next = deadline + self.period;
+        if next <= now {
+            match self.missed_tick_behavior {
+                MissedTickBehavior::Burst => {},
+                MissedTickBehavior::Delay => next = now + self.period,
+                MissedTickBehavior::Skip => {
+                    let missed = (now - deadline).as_nanos() / self.period.as_nanos();
+                    let skip = (missed + 1).min(u32::MAX as u128) as u32;
+                    next = deadline + self.period.saturating_mul(skip);
+                },
+            }
+        }
+        self.sleep.reset(next);
+        Poll::Ready(deadline)
+    }
+
+    /// Start again: the next tick is a full period from now.
+    pub fn reset(&mut self) {
+        self.sleep.reset(Instant::now() + self.period);
+    }
+
+    /// The period.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Set what happens when ticks are missed (default: `MissedTickBehavior::Burst`).
+    pub fn set_missed_tick_behavior(&mut self, behavior: MissedTickBehavior) {
+        self.missed_tick_behavior = behavior;
+    }
+
+    /// Get what happens when ticks are missed.
+    pub fn missed_tick_behavior(&self) -> MissedTickBehavior {
+        self.missed_tick_behavior
+    }
+}
+
+/// Run `fut` and measure how long it took, in wall clock time.
+///
+/// The clock starts when the returned future is first polled, and
+/// stops when `fut` completes.
+pub async fn timed<F: Future>(fut: F) -> (F::Output, Duration) {
+    let start = Instant::now();
+    let res = fut.await;
+    (res, start.elapsed())
+}
+
+/// Like `timed`, but pass the elapsed time to `f` and return just the
+/// output of `fut`. Useful for recording latencies in a histogram.
+pub async fn timed_with<F: Future, C: FnOnce(Duration)>(fut: F, f: C) -> F::Output {
+    let (res, elapsed) = timed(fut).await;
+    f(elapsed);
+    res
+}
+
+/// Compute the delay before retry number `attempt` (starting at 0).
+///
+/// This is exponential backoff with "full jitter": a random duration
+/// between zero and `min(max, base * 2^attempt)`.
+pub fn backoff(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let cap = 2u32.checked_pow(attempt)
+        .and_then(|n| base.checked_mul(n))
+        .map(|d| d.min(max))
+        .unwrap_or(max);
+    cap.mul_f64(random_f64())
+}
+
+/// An iterator that yields backoff delays, see `backoff`.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    attempt: u32,
+    base: Duration,
+    max: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl Backoff {
+    /// Create a new `Backoff`. It never runs out, unless `max_attempts` is set.
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff { attempt: 0, base, max, max_attempts: None }
+    }
+
+    /// Stop after `n` attempts.
+    pub fn max_attempts(mut self, n: u32) -> Backoff {
+        self.max_attempts = Some(n);
+        self
+    }
+
+    /// Start again at the first attempt, e.g. after a success.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|m| self.attempt >= m) {
+            return None;
+        }
+        let delay = backoff(self.attempt, self.base, self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        Some(delay)
+    }
+}
+
+// Random number in [0, 1). xorshift64*, seeded from RandomState.
+fn random_f64() -> f64 {
+    use std::cell::Cell;
+    use std::hash::BuildHasher;
+    thread_local! {
+        static RNG: Cell<u64> = Cell::new(
+            std::collections::hash_map::RandomState::new().hash_one(Instant::now()) | 1
+        );
+    }
+    let r = RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        rng.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    });
+    (r >> 11) as f64 / (1u64 << 53) as f64
+}
+
 impl Future for Sleep {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let timer = TIMER.with_borrow(|t| t.upgrade().unwrap());
-        let mut this = timer.borrow_mut();
-        // Note, if there is an entry in `timers`, it means that this was
-        // a spurious wakeup, not caused by Timer::tick().
-        match this.timers.get_mut(self.get_mut()) {
-            None => Poll::Ready(()),
-            Some(e) => {
-                // Only update the entry if it was not set yet.
-                e.get_or_insert_with(|| cx.waker().clone());
-                Poll::Pending
-            },
-        }
+        self.get_mut().poll_elapsed(cx)
+    }
+}
+
+// Remove the timer entry, if it's still there. This only works while the
+// runtime context is active; otherwise the timer is already gone.
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let _ = TIMER.try_with(|t| {
+            if let Some(timer) = t.borrow().upgrade() {
+                if let Ok(mut this) = timer.try_borrow_mut() {
+                    this.timers.remove(&self.key());
+                }
+            }
+        });
     }
 }
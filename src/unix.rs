@@ -0,0 +1,203 @@
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::SocketAddr;
+use std::path::Path;
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use crate::io::{Interest, Readiness};
+use crate::reactor::Registration;
+use crate::syscall;
+
+/// A Unix domain socket server, listening for connections.
+pub struct UnixListener {
+    lstn:   std::os::unix::net::UnixListener,
+    regfd:  Registration,
+}
+
+impl UnixListener {
+    /// Create a new UnixListener bound to `path`.
+    ///
+    /// If `path` exists and is a stale socket, that is, nothing is listening
+    /// on it anymore, it is removed first. If another process is still
+    /// listening on it, this fails with `io::ErrorKind::AddrInUse`. Note that
+    /// finding out involves a connect, so that listener will see a
+    /// connection that is closed right away.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+        let path = path.as_ref();
+        let lstn = match std::os::unix::net::UnixListener::bind(path) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                if !is_stale_socket(path) {
+                    let msg = format!("{}: address in use", path.display());
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+                }
+                std::fs::remove_file(path)?;
+                std::os::unix::net::UnixListener::bind(path)?
+            },
+            res => res?,
+        };
+        UnixListener::from_std(lstn)
+    }
+
+    /// Construct a nara::UnixListener from a std::os::unix::net::UnixListener.
+    ///
+    /// Fails if not called from within a runtime context.
+    pub fn from_std(lstn: std::os::unix::net::UnixListener) -> io::Result<UnixListener> {
+        lstn.set_nonblocking(true)?;
+        let fd = lstn.as_raw_fd();
+        Ok(UnixListener { lstn, regfd: Registration::new(fd)? })
+    }
+
+    /// Accept a new incoming connection.
+    ///
+    /// This is cancel-safe, like `TcpListener::accept`.
+    pub async fn accept(&self) -> io::Result<(UnixStream, SocketAddr)> {
+        loop {
+            match self.lstn.accept() {
+                Ok((strm, addr)) => return Ok((UnixStream::from_std(strm)?, addr)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.read_ready().await?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Get the local address of this listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.lstn.local_addr()
+    }
+}
+
+impl std::fmt::Debug for UnixListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("UnixListener");
+        if let Ok(addr) = self.lstn.local_addr() {
+            d.field("addr", &addr);
+        }
+        d.field("fd", &self.lstn.as_raw_fd()).finish()
+    }
+}
+
+// A socket is stale if it exists, but nothing accepts connections on it.
+fn is_stale_socket(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    let is_socket = std::fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false);
+    if !is_socket {
+        return false;
+    }
+    // Probe with a nonblocking connect: a blocking one would hang if the
+    // listener's backlog is full.
+    let probe = || -> io::Result<()> {
+        let sock = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        sock.set_nonblocking(true)?;
+        sock.connect(&SockAddr::unix(path)?)
+    };
+    match probe() {
+        // Nobody is listening.
+        Err(e) if e.raw_os_error() == Some(libc::ECONNREFUSED) => true,
+        // Listening, but the backlog is full.
+        Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => false,
+        Err(_) | Ok(()) => false,
+    }
+}
+
+/// A Unix domain stream socket.
+pub struct UnixStream {
+    strm:   std::os::unix::net::UnixStream,
+    regfd:  Registration,
+}
+
+impl UnixStream {
+    /// Connect to the Unix socket at `path`.
+    pub async fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+        let addr = SockAddr::unix(path)?;
+        let sock = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        sock.set_nonblocking(true)?;
+        let regfd = Registration::new(sock.as_raw_fd())?;
+        match sock.connect(&addr) {
+            Ok(()) => {},
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {
+                regfd.write_ready().await?;
+                if let Some(e) = sock.take_error()? {
+                    return Err(e);
+                }
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(UnixStream { strm: std::os::unix::net::UnixStream::from(std::os::fd::OwnedFd::from(sock)), regfd })
+    }
+
+    /// Construct a nara::UnixStream from a std::os::unix::net::UnixStream.
+    ///
+    /// Fails if not called from within a runtime context.
+    pub fn from_std(strm: std::os::unix::net::UnixStream) -> io::Result<UnixStream> {
+        strm.set_nonblocking(true)?;
+        let fd = strm.as_raw_fd();
+        Ok(UnixStream { strm, regfd: Registration::new(fd)? })
+    }
+
+    /// Get the local address of this stream.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.strm.local_addr()
+    }
+
+    /// Get the address of the peer.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.strm.peer_addr()
+    }
+
+    /// Try to read data from the stream, without waiting.
+    ///
+    /// If no data is available, this returns `io::ErrorKind::WouldBlock`.
+    /// No waker is registered with the reactor.
+    pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::Read;
+        (&self.strm).read(buf)
+    }
+
+    /// Try to write data to the stream, without waiting.
+    ///
+    /// If the socket buffer is full, this returns `io::ErrorKind::WouldBlock`.
+    /// No waker is registered with the reactor.
+    pub fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+        use std::io::Write;
+        (&self.strm).write(buf)
+    }
+
+    /// Wait until the stream is readable and/or writable. See `TcpStream::ready`.
+    pub async fn ready(&self, interest: Interest) -> io::Result<Readiness> {
+        self.regfd.ready(interest).await
+    }
+
+    /// Receive data from the stream without removing it from the socket's
+    /// receive queue. See `TcpStream::peek`.
+    pub async fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match syscall::peek(self.strm.as_raw_fd(), buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.regfd.read_ready().await?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Shutdown the write part of the socket.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.strm.shutdown(std::net::Shutdown::Write)
+    }
+}
+
+impl std::fmt::Debug for UnixStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("UnixStream");
+        if let Ok(addr) = self.strm.local_addr() {
+            d.field("addr", &addr);
+        }
+        if let Ok(peer) = self.strm.peer_addr() {
+            d.field("peer", &peer);
+        }
+        d.field("fd", &self.strm.as_raw_fd()).finish()
+    }
+}
+
+crate::io::impl_async_read!(UnixStream, strm, regfd);
+crate::io::impl_async_write!(UnixStream, strm, regfd, shutdown);
@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::task::Waker;
+
+// A task waiting in a `WaitQueue`.
+pub(crate) struct Waiter<D> {
+    pub id: u64,
+    pub waker: Waker,
+    // Per-waiter state, such as the number of permits it wants.
+    pub data: D,
+}
+
+// A FIFO queue of waiting tasks, shared by the channels and the other
+// sync primitives.
+//
+// Every waiter has an id from `next_id`. A future keeps its id, so that
+// it finds its place in the queue again when it is polled, and can give
+// it up when it is dropped (see `WaiterGuard`).
+pub(crate) struct WaitQueue<D = ()> {
+    waiters: VecDeque<Waiter<D>>,
+    last_id: u64,
+}
+
+impl<D> WaitQueue<D> {
+    pub fn new() -> WaitQueue<D> {
+        WaitQueue { waiters: VecDeque::new(), last_id: 0 }
+    }
+
+    // A new, unique waiter id.
+    pub fn next_id(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    // Position of waiter `id` in the queue.
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.waiters.iter().position(|w| w.id == id)
+    }
+
+    // Queue up waiter `id` at the back, or update its waker if it is
+    // already queued; `data` is only used for a new waiter. Returns its
+    // position in the queue.
+    pub fn register(&mut self, id: u64, waker: &Waker, data: D) -> usize {
+        match self.position(id) {
+            Some(pos) => {
+                self.waiters[pos].waker.clone_from(waker);
+                pos
+            },
+            None => {
+                self.waiters.push_back(Waiter { id, waker: waker.clone(), data });
+                self.waiters.len() - 1
+            },
+        }
+    }
+
+    // Remove waiter `id`. Returns it if it was queued.
+    pub fn remove(&mut self, id: u64) -> Option<Waiter<D>> {
+        let pos = self.position(id)?;
+        self.waiters.remove(pos)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Waiter<D>> {
+        self.waiters.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Waiter<D>> {
+        self.waiters.iter_mut()
+    }
+
+    // Wake the first `n` waiters. They stay queued.
+    pub fn wake_first(&self, n: usize) {
+        self.waiters.iter().take(n).for_each(|w| w.waker.wake_by_ref());
+    }
+
+    // Wake all waiters, and empty the queue.
+    pub fn wake_all(&mut self) {
+        self.waiters.drain(..).for_each(|w| w.waker.wake());
+    }
+}
+
+// Lets a waiter leave the queue when its future is dropped.
+//
+// On drop, `on_drop` is called with the shared state and the waiter id.
+// It removes the waiter, and if it was queued, passes its turn on to the
+// waiters behind it.
+pub(crate) struct WaiterGuard<'a, S> {
+    id: u64,
+    state: &'a RefCell<S>,
+    on_drop: fn(&mut S, u64),
+}
+
+impl<'a, S> WaiterGuard<'a, S> {
+    pub fn new(id: u64, state: &'a RefCell<S>, on_drop: fn(&mut S, u64)) -> WaiterGuard<'a, S> {
+        WaiterGuard { id, state, on_drop }
+    }
+}
+
+impl<S> Drop for WaiterGuard<'_, S> {
+    fn drop(&mut self) {
+        (self.on_drop)(&mut self.state.borrow_mut(), self.id);
+    }
+}
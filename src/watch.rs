@@ -0,0 +1,201 @@
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::waiters::WaitQueue;
+
+// Re-exports.
+pub use std::sync::mpsc::{RecvError, SendError};
+
+// Shared channel state.
+struct Shared<T> {
+    value: T,
+    // Bumped on every send.
+    version: u64,
+    rx_wakers: WaitQueue,
+    receivers: usize,
+    sender_gone: bool,
+}
+
+impl<T> Shared<T> {
+    fn wake_receivers(&mut self) {
+        self.rx_wakers.wake_all();
+    }
+}
+
+/// Create a new watch channel, with `init` as the current value.
+///
+/// The sender replaces the value; there is no queue. Every receiver
+/// sees the latest value, and can wait for it to change.
+pub fn channel<T>(init: T) -> (Sender<T>, Receiver<T>) {
+    let mut rx_wakers = WaitQueue::new();
+    let id = rx_wakers.next_id();
+    let shared = Rc::new(RefCell::new(Shared {
+        value: init,
+        version: 0,
+        rx_wakers,
+        receivers: 1,
+        sender_gone: false,
+    }));
+    (Sender { shared: shared.clone() }, Receiver { id, version: 0, shared })
+}
+
+/// Sender side of the watch channel.
+pub struct Sender<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Replace the value and wake all receivers.
+    ///
+    /// Fails, and returns the value, if there are no receivers.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        if self.shared.borrow().receivers == 0 {
+            return Err(SendError(value));
+        }
+        self.send_replace(value);
+        Ok(())
+    }
+
+    /// Replace the value and wake all receivers, also if there are none.
+    /// Returns the old value.
+    pub fn send_replace(&self, value: T) -> T {
+        let mut shared = self.shared.borrow_mut();
+        let old = std::mem::replace(&mut shared.value, value);
+        shared.version += 1;
+        shared.wake_receivers();
+        old
+    }
+
+    /// Borrow the current value.
+    ///
+    /// Do not hold on to the `Ref` across an `.await`: sending while the
+    /// value is borrowed panics.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref::map(self.shared.borrow(), |s| &s.value)
+    }
+
+    /// Create a new receiver. It sees the current value as seen.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut shared = self.shared.borrow_mut();
+        shared.receivers += 1;
+        let id = shared.rx_wakers.next_id();
+        Receiver { id, version: shared.version, shared: self.shared.clone() }
+    }
+
+    /// Number of receivers.
+    pub fn receiver_count(&self) -> usize {
+        self.shared.borrow().receivers
+    }
+
+    /// Are all receivers gone?
+    pub fn is_closed(&self) -> bool {
+        self.receiver_count() == 0
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.sender_gone = true;
+        shared.wake_receivers();
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Shared::fmt_debug(&self.shared, "Sender", f)
+    }
+}
+
+/// Receiver side of the watch channel. Can have multiple instances.
+pub struct Receiver<T> {
+    id: u64,
+    // Version of the value that this receiver has seen.
+    version: u64,
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Borrow the current value, and mark it as seen.
+    ///
+    /// Do not hold on to the `Ref` across an `.await`: sending while the
+    /// value is borrowed panics.
+    pub fn borrow(&mut self) -> Ref<'_, T> {
+        let shared = self.shared.borrow();
+        self.version = shared.version;
+        Ref::map(shared, |s| &s.value)
+    }
+
+    /// Has the value changed since it was last seen?
+    pub fn has_changed(&self) -> bool {
+        self.shared.borrow().version != self.version
+    }
+
+    /// Wait until the value has changed since it was last seen, and mark
+    /// it as seen.
+    ///
+    /// Fails if the sender is gone and there is no unseen value.
+    pub async fn changed(&mut self) -> Result<(), RecvError> {
+        std::future::poll_fn(|cx: &mut Context<'_>| self.poll_changed(cx)).await
+    }
+
+    /// Poll for a change, see `changed`.
+    pub fn poll_changed(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), RecvError>> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.version != self.version {
+            self.version = shared.version;
+            return Poll::Ready(Ok(()));
+        }
+        if shared.sender_gone {
+            return Poll::Ready(Err(RecvError));
+        }
+        // Arrange for us to be woken when the value changes.
+        shared.rx_wakers.register(self.id, cx.waker(), ());
+        Poll::Pending
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        let mut shared = self.shared.borrow_mut();
+        shared.receivers += 1;
+        let id = shared.rx_wakers.next_id();
+        Receiver { id, version: self.version, shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.receivers -= 1;
+        shared.rx_wakers.remove(self.id);
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Shared::fmt_debug(&self.shared, "Receiver", f)
+    }
+}
+
+impl<T: std::fmt::Debug> Shared<T> {
+    // Debug helper. Does not panic if the value is mutably borrowed.
+    fn fmt_debug(
+        this: &RefCell<Shared<T>>,
+        name: &str,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        let mut d = f.debug_struct(name);
+        match this.try_borrow() {
+            Ok(shared) => {
+                d.field("value", &shared.value);
+                d.field("version", &shared.version);
+            },
+            Err(_) => {
+                d.field("shared", &format_args!("<borrowed>"));
+            },
+        }
+        d.finish()
+    }
+}
@@ -0,0 +1,67 @@
+// Counts heap allocations, so it gets its own test binary.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::AsyncReadExt;
+use nara::net::{TcpListener, TcpStream};
+
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ROUND_TRIPS: usize = 200;
+
+async fn round_trip(client: &mut TcpStream) {
+    let mut buf = [0u8; 16];
+    client.write_all(b"0123456789abcdef").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+}
+
+// Waiting for a socket with a single waiter per fd does not allocate, so
+// an echo round trip over an established connection allocates nothing.
+#[test]
+fn echo_round_trips_do_not_allocate() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        nara::task::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            loop {
+                match conn.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => conn.write_all(&buf[..n]).await.unwrap(),
+                }
+            }
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        // Warm up: task wakers, reactor tables, socket buffers.
+        for _ in 0 .. 20 {
+            round_trip(&mut client).await;
+        }
+
+        let before = ALLOCS.load(Ordering::Relaxed);
+        for _ in 0 .. ROUND_TRIPS {
+            round_trip(&mut client).await;
+        }
+        let allocs = ALLOCS.load(Ordering::Relaxed) - before;
+        assert!(allocs < ROUND_TRIPS / 10, "{} allocations for {} round trips", allocs, ROUND_TRIPS);
+    });
+}
@@ -0,0 +1,59 @@
+// Kept in its own test binary: it counts the open fds of the process,
+// so no other tests may run at the same time.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use nara::net::TcpStream;
+
+fn open_fds() -> usize {
+    std::fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+// A listener that is never accepted on, with a tiny backlog. Once the
+// backlog is full, new connects to it stay pending.
+fn full_listener() -> socket2::Socket {
+    let sock = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    sock.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+    sock.listen(0).unwrap();
+    sock
+}
+
+#[test]
+fn dropped_connect_closes_socket() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let listener = full_listener();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+
+        // Fill up the backlog, until a connect no longer completes.
+        let mut held = Vec::new();
+        for _ in 0 .. 64 {
+            match nara::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await {
+                Ok(conn) => held.push(conn.unwrap()),
+                Err(_) => break,
+            }
+        }
+        let baseline = open_fds();
+
+        // Single address.
+        for _ in 0 .. 5 {
+            let res = nara::time::timeout(Duration::from_millis(20), TcpStream::connect(addr)).await;
+            assert!(res.is_err(), "connect did not stay pending");
+        }
+        assert_eq!(open_fds(), baseline);
+
+        // Multiple addresses, dropped during the first attempt.
+        let addrs = [addr, addr, addr];
+        for _ in 0 .. 5 {
+            let res = nara::time::timeout(Duration::from_millis(20), TcpStream::connect(&addrs[..])).await;
+            assert!(res.is_err(), "connect did not stay pending");
+        }
+        assert_eq!(open_fds(), baseline);
+
+        // Per-address timeouts: every attempt's socket is closed.
+        let res = TcpStream::connect_timeout(&addrs[..], Duration::from_millis(20)).await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(open_fds(), baseline);
+        drop(held);
+    });
+}
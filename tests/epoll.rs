@@ -0,0 +1,14 @@
+// The network tests again, with the epoll reactor backend.
+#![cfg(target_os = "linux")]
+
+use nara::runtime::{Builder, ReactorBackend};
+
+fn runtime() -> nara::runtime::Runtime {
+    Builder::new().reactor_backend(ReactorBackend::Epoll).build().unwrap()
+}
+
+#[path = "net.rs"]
+mod net;
+
+#[path = "unix.rs"]
+mod unix;
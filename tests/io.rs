@@ -0,0 +1,60 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_io::AsyncRead;
+
+// A reader that always has data.
+struct Endless;
+
+impl AsyncRead for Endless {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let n = std::cmp::min(buf.len(), 100);
+        buf[..n].fill(b'x');
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[test]
+fn read_to_end_deadline_always_ready() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut buf = b"head".to_vec();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let n = nara::io::read_to_end_deadline(&mut Endless, &mut buf, deadline).await.unwrap();
+        assert!(Instant::now() >= deadline);
+        assert!(n > 0);
+        assert_eq!(buf.len(), n + 4);
+        assert!(buf[4..].iter().all(|&b| b == b'x'));
+    });
+}
+
+#[test]
+fn read_to_end_deadline_eof() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut reader = &b"hello world"[..];
+        let mut buf = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let n = nara::io::read_to_end_deadline(&mut reader, &mut buf, deadline).await.unwrap();
+        assert_eq!(n, 11);
+        assert_eq!(buf, b"hello world");
+    });
+}
+
+// `Flushable` and `AsyncWriteExt` can be used together: `flush` is not
+// ambiguous.
+#[test]
+fn flushable_with_async_write_ext() {
+    use nara::io::{AsyncWriteExt, BufWriter, Flushable};
+
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut w = BufWriter::new(Vec::new());
+        w.write_all(b"hello").await.unwrap();
+        w.flush().await.unwrap();
+        w.write_all(b", world").await.unwrap();
+        w.flush_buffered().await.unwrap();
+        nara::io::flush_all(&mut [&mut w]).await.unwrap();
+    });
+}
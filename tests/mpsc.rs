@@ -0,0 +1,65 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Senders that block on a full channel are served in FIFO order, and
+// a sender that arrives later cannot overtake them.
+#[test]
+fn unsync_blocked_senders_fifo() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let (tx, mut rx) = nara::unsync::mpsc::channel(1);
+        tx.try_send(0).unwrap();
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut set = nara::task::JoinSet::new();
+        for i in 1 ..= 5 {
+            let tx = tx.clone();
+            let order = order.clone();
+            set.spawn(async move {
+                tx.send(i).await.unwrap();
+                order.borrow_mut().push(i);
+            });
+            // Let it run and block on the full channel.
+            nara::task::yield_now().await;
+        }
+
+        // The channel is full and senders are waiting: no room for us.
+        assert!(tx.try_send(99).is_err());
+
+        let mut received = Vec::new();
+        for _ in 0 ..= 5 {
+            received.push(rx.recv().await.unwrap());
+        }
+        while set.join_next().await.is_some() {}
+        assert_eq!(received, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(*order.borrow(), [1, 2, 3, 4, 5]);
+    });
+}
+
+// Once the runtime is gone, so are its tasks and the receivers they
+// own: a sender on another thread gets an error instead of a panic.
+#[test]
+fn send_from_thread_after_runtime_shutdown() {
+    let (tx, mut rx) = nara::sync::mpsc::channel::<u32>(4);
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (stopped_tx, stopped_rx) = std::sync::mpsc::channel::<()>();
+    let sender = std::thread::spawn(move || {
+        tx.try_send(1).unwrap();
+        started_tx.send(()).unwrap();
+        stopped_rx.recv().unwrap();
+        tx.try_send(2)
+    });
+
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        nara::task::spawn(async move {
+            while rx.recv().await.is_some() {}
+        });
+        nara::task::spawn_blocking(move || started_rx.recv().unwrap()).await.unwrap();
+    });
+    drop(rt);
+
+    stopped_tx.send(()).unwrap();
+    let res = sender.join().expect("sender thread panicked");
+    assert!(matches!(res, Err(std::sync::mpsc::TrySendError::Disconnected(2))));
+}
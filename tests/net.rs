@@ -0,0 +1,456 @@
+use std::cell::Cell;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::AsyncReadExt;
+
+use nara::io::Interest;
+use nara::net::{CachedResolver, HappyEyeballsConfig, Resolver, TcpListener, TcpSocket, TcpStream};
+use nara::sync::{CancellationToken, Notify};
+
+// tests/epoll.rs runs these tests as a module, with its own runtime().
+#[allow(dead_code)]
+fn runtime() -> nara::runtime::Runtime {
+    nara::runtime::Runtime::new().unwrap()
+}
+
+// Dropping a pending accept loses nothing: the next accept on the same
+// listener gets the connection.
+#[test]
+fn accept_after_dropped_accept() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Let an accept wait, then drop it.
+        let res = nara::time::timeout(Duration::from_millis(20), listener.accept()).await;
+        assert!(res.is_err());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut conn, _) = nara::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .expect("accept after a dropped accept timed out")
+            .unwrap();
+        assert_same_connection(&mut client, &mut conn).await;
+    });
+}
+
+// A dropped accept does not take the wakeup away from another task that
+// is waiting on the same listener.
+#[test]
+fn dropped_accept_does_not_steal_wakeup() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let listener = std::rc::Rc::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let addr = listener.local_addr().unwrap();
+
+        let l = listener.clone();
+        let waiter = nara::task::spawn(async move { l.accept().await.map(|(conn, _)| conn) });
+        nara::task::yield_now().await;
+
+        let res = nara::time::timeout(Duration::from_millis(20), listener.accept()).await;
+        assert!(res.is_err());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut conn = nara::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .expect("waiting accept was not woken")
+            .unwrap()
+            .unwrap();
+        assert_same_connection(&mut client, &mut conn).await;
+    });
+}
+
+// A connected pair of sockets with small buffers.
+async fn small_buffer_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    client.set_send_buffer_size(4096).unwrap();
+    server.set_recv_buffer_size(4096).unwrap();
+    (client, server)
+}
+
+// Fill the send buffer until a write would block. Returns the number of
+// bytes written, all of them `b'f'`.
+fn fill_send_buffer(strm: &TcpStream) -> usize {
+    let chunk = [b'f'; 1024];
+    let mut total = 0;
+    loop {
+        match strm.try_write(&chunk) {
+            Ok(n) => total += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return total,
+            Err(e) => panic!("try_write: {}", e),
+        }
+    }
+}
+
+// ready() waits for what was asked for, and reports what it found.
+#[test]
+fn ready_reports_readiness() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let (client, server) = small_buffer_pair().await;
+
+        let ready = client.ready(Interest::WRITE).await.unwrap();
+        assert!(ready.is_writable());
+        assert!(!ready.is_readable());
+
+        let res = nara::time::timeout(Duration::from_millis(20), server.ready(Interest::READ)).await;
+        assert!(res.is_err(), "readable without data");
+
+        client.try_write(b"ping").unwrap();
+        let ready = nara::time::timeout(Duration::from_secs(5), server.ready(Interest::READ))
+            .await
+            .expect("not readable after write")
+            .unwrap();
+        assert!(ready.is_readable());
+        let mut buf = [0u8; 16];
+        assert_eq!(server.try_read(&mut buf).unwrap(), 4);
+
+        // Full send buffer: not writable, until the other side reads.
+        let filled = fill_send_buffer(&client);
+        let res = nara::time::timeout(Duration::from_millis(20), client.ready(Interest::WRITE)).await;
+        assert!(res.is_err(), "writable with a full send buffer");
+        let drain = async {
+            let mut buf = vec![0u8; filled];
+            let mut server = server;
+            server.read_exact(&mut buf).await.unwrap();
+        };
+        let (ready, ()) = futures::join!(client.ready(Interest::READ | Interest::WRITE), drain);
+        assert!(ready.unwrap().is_writable());
+    });
+}
+
+// iovecs of odd sizes, each with its own fill byte.
+fn iovec_data() -> Vec<Vec<u8>> {
+    [1usize, 3000, 7, 16384, 5000, 1, 20_000, 999]
+        .iter()
+        .enumerate()
+        .map(|(i, &len)| vec![b'a' + i as u8; len])
+        .collect()
+}
+
+// With a nearly full socket buffer, a vectored write accepts part of
+// the iovecs and reports exactly what it wrote.
+#[test]
+fn write_vectored_partial() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let (mut client, mut server) = small_buffer_pair().await;
+        let filled = fill_send_buffer(&client);
+
+        // Make a bit of room.
+        let mut buf = vec![0u8; 2048];
+        server.read_exact(&mut buf).await.unwrap();
+
+        let data = iovec_data();
+        let total: usize = data.iter().map(|d| d.len()).sum();
+        let bufs: Vec<_> = data.iter().map(|d| IoSlice::new(d)).collect();
+        let n = nara::time::timeout(Duration::from_secs(5), client.write_vectored(&bufs))
+            .await
+            .expect("write_vectored did not complete")
+            .unwrap();
+        assert!(n > 0 && n < total, "n = {}", n);
+
+        // The receiver sees the filler, then exactly `n` bytes of the iovecs.
+        drop(client);
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        let expected: Vec<u8> = data.concat();
+        assert_eq!(received.len(), filled - 2048 + n);
+        assert!(received[.. filled - 2048].iter().all(|&b| b == b'f'));
+        assert_eq!(&received[filled - 2048 ..], &expected[.. n]);
+    });
+}
+
+// write_all_vectored resumes at the right iovec and offset after every
+// partial write.
+#[test]
+fn write_all_vectored_nearly_full_buffer() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let (mut client, mut server) = small_buffer_pair().await;
+        let filled = fill_send_buffer(&client);
+
+        let reader = nara::task::spawn(async move {
+            let mut received = Vec::new();
+            let mut buf = vec![0u8; 1000];
+            loop {
+                match server.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+            received
+        });
+
+        let data = iovec_data();
+        let mut bufs: Vec<_> = data.iter().map(|d| IoSlice::new(d)).collect();
+        nara::time::timeout(Duration::from_secs(10), client.write_all_vectored(&mut bufs))
+            .await
+            .expect("write_all_vectored did not complete")
+            .unwrap();
+        drop(client);
+
+        let received = reader.await.unwrap();
+        let expected: Vec<u8> = data.concat();
+        assert!(received[.. filled].iter().all(|&b| b == b'f'));
+        assert_eq!(received.len(), filled + expected.len());
+        assert!(received[filled ..] == expected[..], "iovec data was not written in order");
+    });
+}
+
+async fn assert_same_connection(client: &mut TcpStream, conn: &mut TcpStream) {
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+// A read that was dropped while pending does not pass its deadline on to
+// the next read.
+#[test]
+fn read_timeout_rearmed_after_dropped_read() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+
+        // Start a read, and drop it halfway through its timeout.
+        let mut buf = [0u8; 4];
+        let res = nara::time::timeout(Duration::from_millis(200), server.read(&mut buf)).await;
+        assert!(res.is_err());
+
+        // Data arrives after the old deadline, but before the new one.
+        let writer = nara::task::spawn(async move {
+            nara::time::sleep(Duration::from_millis(200)).await;
+            client.write_all(b"ping").await.unwrap();
+            client
+        });
+        let n = server.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        let _client = writer.await.unwrap();
+
+        // Without data, the read still times out.
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    });
+}
+
+// A limit of 0 would never run a handler; serve refuses it instead of
+// hanging.
+#[test]
+fn serve_zero_limit() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let res = nara::time::timeout(Duration::from_secs(5), listener.serve(0, |_, _| async {}))
+            .await
+            .expect("serve with a zero limit hung");
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    });
+}
+
+// If the IPv6 port is taken, bind_dual_stack fails instead of quietly
+// listening on IPv4 only.
+// On shutdown, serve_with_shutdown stops accepting but waits for the
+// handler of a connection that is still open.
+#[test]
+fn serve_shutdown_waits_for_handlers() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let started = Rc::new(Notify::new());
+        let finished = Rc::new(Cell::new(false));
+
+        let handler = {
+            let (started, finished) = (started.clone(), finished.clone());
+            move |mut strm: TcpStream, _| {
+                let (started, finished) = (started.clone(), finished.clone());
+                async move {
+                    started.notify_one();
+                    let mut buf = [0u8; 4];
+                    strm.read_exact(&mut buf).await.unwrap();
+                    finished.set(true);
+                }
+            }
+        };
+        let server = nara::task::spawn({
+            let shutdown = shutdown.clone();
+            async move { listener.serve_with_shutdown(4, &shutdown, None, handler).await }
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        nara::time::timeout(Duration::from_secs(5), started.notified())
+            .await
+            .expect("handler did not start");
+        shutdown.cancel();
+        nara::time::sleep(Duration::from_millis(20)).await;
+        assert!(!server.is_finished(), "returned with a handler still running");
+
+        client.write_all(b"done").await.unwrap();
+        let summary = nara::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("serve_with_shutdown did not return")
+            .unwrap()
+            .unwrap();
+        assert!(finished.get());
+        assert_eq!((summary.completed, summary.aborted), (1, 0));
+    });
+}
+
+// A handler that fails does not stop serve: its slot is freed, and the
+// next connection is handled.
+#[test]
+fn serve_survives_failing_handler() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let count = Rc::new(Cell::new(0));
+        let c = count.clone();
+        let server = nara::task::spawn(listener.serve(1, move |mut strm, _| {
+            c.set(c.get() + 1);
+            let first = c.get() == 1;
+            async move {
+                if first {
+                    panic!("handler failed");
+                }
+                strm.write_all(b"pong").await.unwrap();
+            }
+        }));
+
+        let mut buf = Vec::new();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        nara::time::timeout(Duration::from_secs(5), client.read_to_end(&mut buf))
+            .await
+            .expect("failed handler did not close the connection")
+            .unwrap();
+        assert!(buf.is_empty());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 4];
+        nara::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .expect("second connection not handled")
+            .unwrap();
+        assert_eq!(&buf, b"pong");
+        assert_eq!(count.get(), 2);
+        assert!(!server.is_finished());
+    });
+}
+
+#[test]
+fn dual_stack_port_in_use() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let sock = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None).unwrap();
+        sock.set_only_v6(true).unwrap();
+        let any6 = std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0));
+        if sock.bind(&any6.into()).is_err() {
+            // No IPv6 here.
+            return;
+        }
+        sock.listen(16).unwrap();
+        let port = sock.local_addr().unwrap().as_socket().unwrap().port();
+
+        let err = TcpListener::bind_dual_stack(port).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+    });
+}
+
+// connect_with_data delivers all of the data, and reports errors instead
+// of returning a stream that never connected.
+#[test]
+fn connect_with_data() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_strm, _) = TcpStream::connect_with_data(addr, b"hello").await.unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(listener);
+
+        let err = TcpStream::connect_with_data(addr, b"hello").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    });
+}
+
+// A full cache evicts the oldest entry, so distinct names do not grow it.
+#[test]
+fn cached_resolver_capacity() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let resolver = CachedResolver::with_capacity(Duration::from_secs(60), 2);
+        for port in 1..=5 {
+            resolver.resolve("localhost", port).await.unwrap();
+        }
+        assert!(format!("{:?}", resolver).contains("entries: 2"), "{:?}", resolver);
+
+        let resolver = CachedResolver::with_capacity(Duration::from_secs(60), 0);
+        resolver.resolve("localhost", 1).await.unwrap();
+        assert!(format!("{:?}", resolver).contains("entries: 0"), "{:?}", resolver);
+    });
+}
+
+// Resolves every name to the same addresses.
+struct FixedResolver(Vec<SocketAddr>);
+
+impl Resolver for FixedResolver {
+    async fn resolve(&self, _host: &str, _port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        Ok(self.0.clone())
+    }
+}
+
+// A listener that never accepts, with its backlog filled up so that
+// connecting to it hangs. Returns the listener and the queued connections.
+async fn blackhole() -> (TcpListener, Vec<TcpStream>) {
+    let sock = TcpSocket::new_v4().unwrap();
+    sock.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = sock.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut queued = Vec::new();
+    for _ in 0..16 {
+        match nara::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await {
+            Ok(strm) => queued.push(strm.unwrap()),
+            Err(_) => return (listener, queued),
+        }
+    }
+    panic!("backlog of {} did not fill up", addr);
+}
+
+// When the first address does not answer, connect_with starts an attempt
+// on the second one after the attempt delay, and that one wins.
+#[test]
+fn connect_with_staggered_fallback() {
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let (dead, _queued) = blackhole().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = vec![dead.local_addr().unwrap(), listener.local_addr().unwrap()];
+        nara::net::set_resolver(FixedResolver(addrs));
+
+        let delay = Duration::from_millis(50);
+        let config = HappyEyeballsConfig::new().with_attempt_delay(delay);
+        let start = Instant::now();
+        let mut client = nara::time::timeout(Duration::from_secs(5), TcpStream::connect_with("fallback.test:80", config))
+            .await
+            .expect("fallback attempt was not started")
+            .unwrap();
+        assert!(start.elapsed() >= delay, "second attempt started early");
+
+        let (mut conn, _) = listener.accept().await.unwrap();
+        assert_same_connection(&mut client, &mut conn).await;
+    });
+}
@@ -0,0 +1,112 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+// `turn` inside an `enter()` context leaves the context active, so that
+// tasks can still be spawned after it.
+#[test]
+fn spawn_after_turn_in_enter_context() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let done = Rc::new(Cell::new(0));
+
+    let d = done.clone();
+    nara::task::spawn(async move { d.set(d.get() + 1) });
+    rt.turn(Some(Duration::ZERO));
+    assert_eq!(done.get(), 1);
+
+    let d = done.clone();
+    nara::task::spawn(async move { d.set(d.get() + 1) });
+    rt.turn(Some(Duration::ZERO));
+    assert_eq!(done.get(), 2);
+}
+
+// Run `first` and then a task named "second" that waits once, and return
+// the events of "second". `first` is expected to set the yielded flag and
+// still complete in the same poll.
+fn events_after<F>(first: F) -> Vec<nara::task::TaskEventKind>
+where
+    F: std::future::Future<Output = ()> + 'static,
+{
+    let events = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let ev = events.clone();
+    let rt = nara::runtime::Builder::new()
+        .schedule_order(nara::runtime::ScheduleOrder::Fifo)
+        .on_task_event(move |e| {
+            if e.name == Some("second") {
+                ev.borrow_mut().push(e.kind);
+            }
+        })
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let first = nara::task::spawn(first);
+        let second = nara::task::spawn_named("second", async {
+            nara::time::sleep(Duration::from_millis(1)).await;
+        });
+        first.await.unwrap();
+        second.await.unwrap();
+    });
+    let events = events.borrow().clone();
+    events
+}
+
+// A task that runs out of budget but still completes does not make the
+// next task look like it yielded.
+#[test]
+fn budget_exhausted_does_not_leak_into_next_task() {
+    use futures::future::{select, FutureExt};
+    use nara::task::TaskEventKind::*;
+
+    let events = events_after(async {
+        let (tx, mut rx) = nara::unsync::mpsc::unbounded::<u32>();
+        for i in 0 .. 1000 {
+            tx.send(i).unwrap();
+        }
+        let drain = async move { while rx.recv().await.is_some() {} }.boxed_local();
+        select(drain, std::future::ready(()).boxed_local()).await;
+    });
+    assert_eq!(events, [Spawned, Polling, Pending, Polling, Completed]);
+}
+
+// Same for a task that called yield_now, but completed anyway.
+#[test]
+fn yield_now_does_not_leak_into_next_task() {
+    use futures::future::{select, FutureExt};
+    use nara::task::TaskEventKind::*;
+
+    let events = events_after(async {
+        let yielding = nara::task::yield_now().boxed_local();
+        select(yielding, std::future::ready(()).boxed_local()).await;
+    });
+    assert_eq!(events, [Spawned, Polling, Pending, Polling, Completed]);
+}
+
+// Handle::spawn_blocking fails instead of panicking, both without
+// cross-thread wakeups and after the runtime is gone.
+#[test]
+fn handle_spawn_blocking_errors() {
+    use nara::task::SpawnError;
+
+    let rt = nara::runtime::Builder::new().allow_cross_thread_wake(false).build().unwrap();
+    let handle = rt.handle();
+    let res = rt.block_on(async { handle.spawn_blocking(|| ()).map(|_| ()) });
+    assert_eq!(res, Err(SpawnError::CrossThreadWakeDisabled));
+
+    let rt = nara::runtime::Runtime::new().unwrap();
+    let handle = rt.handle();
+    assert_eq!(rt.block_on(async { handle.spawn_blocking(|| 1).unwrap().await.unwrap() }), 1);
+    drop(rt);
+    assert_eq!(handle.spawn_blocking(|| 1).map(|_| ()), Err(SpawnError::RuntimeShuttingDown));
+}
+
+#[test]
+fn builder_debug_shows_all_settings() {
+    let builder = nara::runtime::Builder::new()
+        .schedule_order(nara::runtime::ScheduleOrder::Fifo)
+        .reactor_backend(nara::runtime::ReactorBackend::Poll);
+    let s = format!("{:?}", builder);
+    for field in ["on_task_event", "cross_thread_wake", "schedule_order: Fifo", "reactor_backend: Poll"] {
+        assert!(s.contains(field), "{} missing from {}", field, s);
+    }
+}
@@ -0,0 +1,47 @@
+use futures::FutureExt;
+use nara::sync::{Notify, Resettable};
+
+// Once the Resettable is gone, no sender can send, also while a sender
+// of an earlier round is still around.
+#[test]
+fn resettable_send_after_drop() {
+    let (mut rx, old_tx) = Resettable::<u32>::new();
+    let tx = rx.reset();
+    drop(rx);
+    assert!(tx.send(1).is_err());
+    assert!(old_tx.send(2).is_err());
+}
+
+#[test]
+fn resettable_rounds() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let (mut rx, tx) = Resettable::new();
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv().await, Ok(1));
+
+        let old_tx = rx.reset();
+        let tx = rx.reset();
+        assert!(old_tx.send(2).is_err());
+        tx.send(3).unwrap();
+        assert_eq!(rx.recv().await, Ok(3));
+    });
+}
+
+// A waiter that was picked by notify_one but dropped before it saw the
+// notification passes it on to the next waiter.
+#[test]
+fn notify_one_passed_on_by_dropped_waiter() {
+    let notify = Notify::new();
+    let mut first = Box::pin(notify.notified());
+    let mut second = Box::pin(notify.notified());
+    assert!((&mut first).now_or_never().is_none());
+    assert!((&mut second).now_or_never().is_none());
+
+    notify.notify_one();
+    drop(first);
+    assert!((&mut second).now_or_never().is_some());
+
+    // The notification was consumed, not stored as a permit.
+    assert!(notify.notified().now_or_never().is_none());
+}
@@ -0,0 +1,147 @@
+use std::time::{Duration, Instant};
+
+use nara::time::{backoff, interval_at, Backoff, Interval, MissedTickBehavior};
+
+const BASE: Duration = Duration::from_millis(10);
+const MAX: Duration = Duration::from_secs(1);
+const SAMPLES: usize = 10_000;
+
+// The upper bound of the delay for `attempt`: min(max, base * 2^attempt).
+fn cap(attempt: u32) -> Duration {
+    (BASE * 2u32.pow(attempt)).min(MAX)
+}
+
+// Every delay is below the cap for its attempt, and with enough samples
+// the largest one comes close to it, so the sequence doubles as it should.
+#[test]
+fn backoff_delay_sequence() {
+    for attempt in 0..10 {
+        let delays: Vec<_> = (0..SAMPLES).map(|_| backoff(attempt, BASE, MAX)).collect();
+        let largest = *delays.iter().max().unwrap();
+        assert!(largest <= cap(attempt), "attempt {}: {:?} > {:?}", attempt, largest, cap(attempt));
+        assert!(largest >= cap(attempt).mul_f64(0.95), "attempt {}: {:?}", attempt, largest);
+    }
+}
+
+// Once base * 2^attempt passes max, delays stay below max, also when the
+// multiplication would overflow.
+#[test]
+fn backoff_max_cap() {
+    for attempt in [7, 8, 20, 31, 32, 64, u32::MAX] {
+        for _ in 0..1000 {
+            assert!(backoff(attempt, BASE, MAX) <= MAX);
+        }
+    }
+    let huge = Duration::from_secs(u64::MAX / 2);
+    for attempt in [1, 2, 40, u32::MAX] {
+        assert!(backoff(attempt, huge, MAX) <= MAX);
+    }
+}
+
+// Full jitter: delays are spread evenly between zero and the cap.
+#[test]
+fn backoff_jitter_bounds() {
+    let cap = cap(4);
+    let delays: Vec<f64> = (0..SAMPLES)
+        .map(|_| backoff(4, BASE, MAX).as_secs_f64() / cap.as_secs_f64())
+        .collect();
+    assert!(delays.iter().all(|&d| (0.0..=1.0).contains(&d)));
+
+    let mean = delays.iter().sum::<f64>() / SAMPLES as f64;
+    assert!((mean - 0.5).abs() < 0.05, "mean {}", mean);
+    let below_tenth = delays.iter().filter(|&&d| d < 0.1).count();
+    let above_ninetieth = delays.iter().filter(|&&d| d > 0.9).count();
+    assert!(below_tenth > SAMPLES / 20, "{} below 0.1", below_tenth);
+    assert!(above_ninetieth > SAMPLES / 20, "{} above 0.9", above_ninetieth);
+}
+
+#[test]
+fn backoff_iterator_reset() {
+    let mut b = Backoff::new(BASE, MAX).max_attempts(3);
+    for attempt in 0..3 {
+        assert!(b.next().unwrap() <= cap(attempt));
+    }
+    assert_eq!(b.next(), None);
+
+    // After a reset we start at the first attempt again, with the same
+    // number of attempts.
+    b.reset();
+    let delays: Vec<_> = b.by_ref().collect();
+    assert_eq!(delays.len(), 3);
+    assert!(delays[0] <= BASE);
+
+    // Without a limit it never runs out, and stays capped.
+    let mut b = Backoff::new(BASE, MAX);
+    assert!(b.by_ref().take(100).all(|d| d <= MAX));
+    b.reset();
+    assert!((0..SAMPLES).all(|_| {
+        let mut b = b.clone();
+        b.next().unwrap() <= BASE
+    }));
+}
+
+const PERIOD: Duration = Duration::from_millis(50);
+
+// An interval that has ticked once at `start`, and then was not polled
+// for three and a half periods. Returns it with the time the stall ended.
+async fn stalled_interval(start: Instant, behavior: MissedTickBehavior) -> (Interval, Instant) {
+    let mut iv = interval_at(start, PERIOD);
+    iv.set_missed_tick_behavior(behavior);
+    assert_eq!(iv.tick().await, start);
+    std::thread::sleep(PERIOD * 7 / 2);
+    (iv, Instant::now())
+}
+
+// Burst: the missed ticks fire right away, on the original schedule.
+#[test]
+fn interval_burst_after_stall() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let start = Instant::now();
+        let (mut iv, stalled) = stalled_interval(start, MissedTickBehavior::Burst).await;
+        for n in 1..=3 {
+            let tick = iv.tick().await;
+            assert_eq!(tick, start + PERIOD * n);
+            assert!(tick < stalled);
+        }
+        assert_eq!(iv.tick().await, start + PERIOD * 4);
+    });
+}
+
+// Skip: the late tick fires, the ones missed after it are dropped, and the
+// next one is on the original schedule.
+#[test]
+fn interval_skip_after_stall() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let start = Instant::now();
+        let (mut iv, stalled) = stalled_interval(start, MissedTickBehavior::Skip).await;
+        assert_eq!(iv.tick().await, start + PERIOD);
+        let late = Instant::now();
+
+        let tick = iv.tick().await;
+        assert!(tick > stalled, "missed tick {:?} not skipped", tick - start);
+        assert!(tick <= late + PERIOD);
+        assert_eq!((tick - start).as_nanos() % PERIOD.as_nanos(), 0, "off schedule");
+        assert!(Instant::now() >= tick);
+        assert_eq!(iv.tick().await, tick + PERIOD);
+    });
+}
+
+// Delay: the late tick fires, and the schedule starts again from there.
+#[test]
+fn interval_delay_after_stall() {
+    let rt = nara::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let start = Instant::now();
+        let (mut iv, stalled) = stalled_interval(start, MissedTickBehavior::Delay).await;
+        assert_eq!(iv.tick().await, start + PERIOD);
+        let late = Instant::now();
+
+        let tick = iv.tick().await;
+        assert!(tick >= stalled + PERIOD, "{:?} is less than a period after the stall", tick - stalled);
+        assert!(tick <= late + PERIOD);
+        assert!(Instant::now() >= tick);
+        assert_eq!(iv.tick().await, tick + PERIOD);
+    });
+}
@@ -0,0 +1,69 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use nara::net::UnixListener;
+
+// tests/epoll.rs runs these tests as a module, with its own runtime().
+#[allow(dead_code)]
+fn runtime() -> nara::runtime::Runtime {
+    nara::runtime::Runtime::new().unwrap()
+}
+
+fn socket_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("nara-test-{}-{}.sock", std::process::id(), name))
+}
+
+// A socket file that nobody listens on anymore is replaced.
+#[test]
+fn bind_replaces_stale_socket() {
+    let path = socket_path("stale");
+    let _ = std::fs::remove_file(&path);
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let rt = crate::runtime();
+    rt.block_on(async {
+        let listener = UnixListener::bind(&path).unwrap();
+        drop(listener);
+    });
+    std::fs::remove_file(&path).unwrap();
+}
+
+// A live listener whose backlog is full is reported as in use, without
+// blocking the runtime thread.
+#[test]
+fn bind_live_listener_with_full_backlog() {
+    let path = socket_path("full");
+    let _ = std::fs::remove_file(&path);
+    let sock = socket2::Socket::new(socket2::Domain::UNIX, socket2::Type::STREAM, None).unwrap();
+    sock.bind(&socket2::SockAddr::unix(&path).unwrap()).unwrap();
+    sock.listen(0).unwrap();
+
+    // Fill up the backlog.
+    let mut held = Vec::new();
+    loop {
+        let c = socket2::Socket::new(socket2::Domain::UNIX, socket2::Type::STREAM, None).unwrap();
+        c.set_nonblocking(true).unwrap();
+        match c.connect(&socket2::SockAddr::unix(&path).unwrap()) {
+            Ok(()) => held.push(c),
+            Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => break,
+            Err(e) => panic!("connect: {}", e),
+        }
+        assert!(held.len() < 1024, "backlog never filled up");
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let bind_path = path.clone();
+    std::thread::spawn(move || {
+        let rt = crate::runtime();
+        let res = rt.block_on(async { UnixListener::bind(&bind_path).map(drop) });
+        let _ = tx.send(res);
+    });
+    let res = rx.recv_timeout(Duration::from_secs(5)).expect("bind blocked");
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+    assert!(path.exists());
+
+    drop(held);
+    drop(sock);
+    std::fs::remove_file(&path).unwrap();
+}